@@ -0,0 +1,35 @@
+//! Plugin bridge process, runs one plugin for the engine (see `engine::loader::bridge`)
+extern crate engine;
+
+use engine::loader::bridge;
+use std::{ffi::OsString, str::FromStr};
+
+fn main() {
+    let mut args = std::env::args_os().skip(1);
+    let result = match (args.next(), args.next(), args.next()) {
+        (Some(flag), Some(plugin), None) if flag == "--probe" => bridge::serve_probe(plugin),
+        (Some(plugin), Some(sample_rate), Some(block_size)) => {
+            match (parse(sample_rate), parse(block_size)) {
+                (Some(sample_rate), Some(block_size)) => {
+                    bridge::serve(plugin, sample_rate, block_size)
+                }
+                _ => usage(),
+            }
+        }
+        _ => usage(),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn parse<T: FromStr>(arg: OsString) -> Option<T> {
+    arg.to_str().and_then(|arg| arg.parse().ok())
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: naama-bridge <plugin> <sample rate> <block size>");
+    eprintln!("       naama-bridge --probe <plugin>");
+    std::process::exit(2);
+}
@@ -0,0 +1,305 @@
+//! Minimal FLAC encoder, samples are stored in verbatim subframes (lossless, uncompressed)
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Size of the STREAMINFO metadata block
+const STREAMINFO_SIZE: usize = 34;
+
+/// Accumulate bits most significant first
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u64, bits: u32) {
+        for bit in (0..bits).rev() {
+            self.acc = (self.acc << 1) | ((value >> bit) & 1);
+            self.bits += 1;
+            if self.bits == 8 {
+                self.bytes.push(self.acc as u8);
+                self.acc = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    /// Pad with zeros up to the next byte boundary
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.acc = 0;
+        self.bits = 0;
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, byte| {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Frame header code of a sample rate, rates without code are read from STREAMINFO
+fn sample_rate_code(sample_rate: u32) -> u64 {
+    match sample_rate {
+        88_200 => 0b0001,
+        176_400 => 0b0010,
+        192_000 => 0b0011,
+        8_000 => 0b0100,
+        16_000 => 0b0101,
+        22_050 => 0b0110,
+        24_000 => 0b0111,
+        32_000 => 0b1000,
+        44_100 => 0b1001,
+        48_000 => 0b1010,
+        96_000 => 0b1011,
+        _ => 0b0000,
+    }
+}
+
+/// Frame header code of a bit depth, 0 for the unsupported ones
+fn sample_size_code(bits_per_sample: u32) -> u64 {
+    match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0,
+    }
+}
+
+/// Encode a frame number the way FLAC does (an extended UTF-8 encoding)
+fn write_utf8(bits: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        bits.write(value, 8);
+        return;
+    }
+    let len = match value {
+        0..=0x7ff => 2,
+        0x800..=0xffff => 3,
+        0x1_0000..=0x1f_ffff => 4,
+        0x20_0000..=0x3ff_ffff => 5,
+        0x400_0000..=0x7fff_ffff => 6,
+        _ => 7,
+    };
+    let prefix = (0xff00u64 >> len) & 0xff;
+    bits.write(prefix | (value >> (6 * (len - 1))), 8);
+    for shift in (0..len - 1).rev() {
+        bits.write(0x80 | ((value >> (6 * shift)) & 0x3f), 8);
+    }
+}
+
+/// Write a FLAC stream, the STREAMINFO block is completed by `finalize`
+pub struct FlacWriter<W: Write + Seek> {
+    writer: W,
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    block_size: usize,
+    /// Interleaved samples waiting for a full frame
+    pending: Vec<i32>,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_size: usize,
+    max_frame_size: usize,
+    bits: BitWriter,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    /// Write the stream header
+    ///
+    /// # Parameters
+    ///
+    /// * `channels` Number of channels, 1 to 8
+    /// * `sample_rate` Sample rate in Hz
+    /// * `bits_per_sample` Bit depth of the samples: 8, 12, 16, 20 or 24
+    /// * `block_size` Number of samples per channel in each frame, 16 to 65535
+    pub fn new(
+        mut writer: W,
+        channels: usize,
+        sample_rate: u32,
+        bits_per_sample: u32,
+        block_size: usize,
+    ) -> io::Result<Self> {
+        if channels == 0
+            || channels > 8
+            || sample_size_code(bits_per_sample) == 0
+            || block_size < 16
+            || block_size > 0xffff
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unsupported FLAC stream format",
+            ));
+        }
+        writer.write_all(b"fLaC")?;
+        let mut flac = Self {
+            writer,
+            channels,
+            sample_rate,
+            bits_per_sample,
+            block_size,
+            pending: Vec::with_capacity(block_size * channels),
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+            bits: BitWriter::new(),
+        };
+        let header = flac.streaminfo();
+        flac.writer.write_all(&header)?;
+        Ok(flac)
+    }
+
+    fn streaminfo(&self) -> Vec<u8> {
+        let mut bits = BitWriter::new();
+        // Last metadata block, type STREAMINFO
+        bits.write(0x80, 8);
+        bits.write(STREAMINFO_SIZE as u64, 24);
+        bits.write(self.block_size as u64, 16);
+        bits.write(self.block_size as u64, 16);
+        bits.write(self.min_frame_size as u64, 24);
+        bits.write(self.max_frame_size as u64, 24);
+        bits.write(u64::from(self.sample_rate), 20);
+        bits.write(self.channels as u64 - 1, 3);
+        bits.write(u64::from(self.bits_per_sample) - 1, 5);
+        bits.write(self.total_samples, 36);
+        // The MD5 signature is left unset
+        bits.write(0, 64);
+        bits.write(0, 64);
+        bits.bytes
+    }
+
+    /// Write interleaved samples, they must fit in `bits_per_sample` bits
+    pub fn write(&mut self, samples: &[i32]) -> io::Result<()> {
+        let frame_len = self.block_size * self.channels;
+        for sample in samples {
+            self.pending.push(*sample);
+            if self.pending.len() == frame_len {
+                self.write_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let frames = self.pending.len() / self.channels;
+        self.bits.clear();
+        let bits = &mut self.bits;
+        // Sync code, fixed block size
+        bits.write(0b11_1111_1111_1110, 14);
+        bits.write(0, 2);
+        // Block size stored after the frame number
+        bits.write(0b0111, 4);
+        bits.write(sample_rate_code(self.sample_rate), 4);
+        bits.write(self.channels as u64 - 1, 4);
+        bits.write(sample_size_code(self.bits_per_sample) << 1, 4);
+        write_utf8(bits, self.frame_number);
+        bits.write(frames as u64 - 1, 16);
+        let crc = crc8(&bits.bytes);
+        bits.write(u64::from(crc), 8);
+        let mask = (1u64 << self.bits_per_sample) - 1;
+        for channel in 0..self.channels {
+            // Verbatim subframe without wasted bits
+            bits.write(0b0000_0010, 8);
+            for frame in 0..frames {
+                let sample = self.pending[frame * self.channels + channel];
+                bits.write(sample as u64 & mask, self.bits_per_sample);
+            }
+        }
+        bits.align();
+        let crc = crc16(&bits.bytes);
+        bits.write(u64::from(crc), 16);
+        self.writer.write_all(&bits.bytes)?;
+        let size = bits.bytes.len();
+        if self.frame_number == 0 || size < self.min_frame_size {
+            self.min_frame_size = size;
+        }
+        self.max_frame_size = self.max_frame_size.max(size);
+        self.frame_number += 1;
+        self.total_samples += frames as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write the remaining samples and complete the stream header
+    pub fn finalize(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            self.write_frame()?;
+        }
+        let header = self.streaminfo();
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&header)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn decode_with_claxon() {
+        let samples: Vec<i32> = (0..100i32)
+            .flat_map(|frame| vec![frame * 300, -frame])
+            .collect();
+        let mut flac = FlacWriter::new(Cursor::new(Vec::new()), 2, 44100, 16, 32).unwrap();
+        flac.write(&samples).unwrap();
+        let data = flac.finalize().unwrap().into_inner();
+        let mut reader = claxon::FlacReader::new(&data[..]).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.samples, Some(100));
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.sample_rate, 44100);
+        let decoded = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn utf8_frame_number() {
+        let mut bits = BitWriter::new();
+        write_utf8(&mut bits, 0x7f);
+        write_utf8(&mut bits, 0x80);
+        write_utf8(&mut bits, 0x1_0000);
+        assert_eq!(bits.bytes, vec![0x7f, 0xc2, 0x80, 0xf0, 0x90, 0x80, 0x80]);
+    }
+}
@@ -1,16 +1,121 @@
-use crate::loader::asset::AudioAsset;
+use crate::loader::asset::{AudioAsset, ResampleQuality};
+use crate::loader::smf::MidiFile;
 use crate::prelude::*;
-use cpal::{self, traits::DeviceTrait};
+use crate::supervisor::transport::BlockSegment;
+use cpal::{
+    self,
+    traits::{DeviceTrait, EventLoopTrait},
+    StreamData, UnknownTypeOutputBuffer,
+};
+use flac::FlacWriter;
+use sample::conv;
 use std::{
     cell::RefCell,
-    sync::{Arc, RwLock, RwLockReadGuard},
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
+#[derive(Debug, Fail)]
+pub enum DeviceError {
+    #[fail(display = "Unable to query the device formats: {}", _0)]
+    SupportedFormats(cpal::SupportedFormatsError),
+    #[fail(display = "The device does not support any output format")]
+    NoSupportedFormat,
+    #[fail(display = "Unable to build the output stream: {}", _0)]
+    BuildStream(cpal::BuildStreamError),
+    #[fail(display = "Unable to play the output stream: {}", _0)]
+    PlayStream(cpal::PlayStreamError),
+    #[fail(display = "The device is already running")]
+    AlreadyRunning,
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "WAV encoding error: {}", _0)]
+    WavEncoding(hound::Error),
+    #[fail(display = "Unsupported file format {:?}", _0)]
+    UnsupportedFileFormat(FileFormat),
+    #[fail(display = "The file is already finalized")]
+    Finalized,
+}
+
+impl From<io::Error> for DeviceError {
+    fn from(err: io::Error) -> Self {
+        DeviceError::Io(err)
+    }
+}
+
+impl From<hound::Error> for DeviceError {
+    fn from(err: hound::Error) -> Self {
+        DeviceError::WavEncoding(err)
+    }
+}
+
+impl From<cpal::SupportedFormatsError> for DeviceError {
+    fn from(err: cpal::SupportedFormatsError) -> Self {
+        DeviceError::SupportedFormats(err)
+    }
+}
+
+impl From<cpal::BuildStreamError> for DeviceError {
+    fn from(err: cpal::BuildStreamError) -> Self {
+        DeviceError::BuildStream(err)
+    }
+}
+
+impl From<cpal::PlayStreamError> for DeviceError {
+    fn from(err: cpal::PlayStreamError) -> Self {
+        DeviceError::PlayStream(err)
+    }
+}
+
+pub mod flac;
+
+/// Callback used by an output device to pull interleaved samples out of the graph
+pub type RenderCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// A device the supervisor plays its graph into
+pub trait OutputDevice: SampleDevice {
+    /// Get the device sample rate
+    fn get_sample_rate(&self) -> u32;
+
+    /// Get the device samples block size
+    fn get_block_size(&self) -> u32;
+
+    /// Get the latency of the graph played into the device, in samples
+    fn get_latency(&self) -> usize;
+
+    /// Set the latency of the graph played into the device, the delay of its slowest branch
+    fn set_latency(&mut self, latency: usize);
+
+    /// Start pulling samples from `render`, until `stop` is called
+    fn start(&mut self, render: RenderCallback) -> Result<(), DeviceError>;
+
+    /// Stop the device, the render callback is dropped
+    fn stop(&mut self);
+
+    /// Is the device currently pulling samples
+    fn is_running(&self) -> bool;
+}
+
 /// Output samples to a system device (sound card [...])
 pub struct SysOutputDevice {
     id: DeviceId,
     device: cpal::Device,
-    format: cpal::SupportedFormat,
+    format: cpal::Format,
+    block_size: u32,
+    /// Latency of the graph, in samples
+    latency: usize,
+    event_loop: Arc<cpal::EventLoop>,
+    render: Arc<Mutex<Option<RenderCallback>>>,
+    stream: Option<cpal::StreamId>,
+    event_thread: Option<JoinHandle<()>>,
 }
 
 impl SysOutputDevice {
@@ -19,25 +124,99 @@ impl SysOutputDevice {
     /// # Parameters
     ///
     /// * `device` The CPAL device to write into
-    pub fn new(device: cpal::Device) -> Self {
-        let id = DeviceId(crate::supervisor::linker::new_id());
-        let mut formats_range = device
-            .supported_output_formats()
-            .expect("Wrong output device");
-        let format = formats_range
-            .next()
-            .expect("Output device supported format");
-        Self { device, format, id }
+    /// * `event_loop` The CPAL event loop that will drive the device stream
+    pub fn new(device: cpal::Device, event_loop: cpal::EventLoop) -> Result<Self, DeviceError> {
+        Self::with_config(device, event_loop, None, None)
     }
 
-    /// Get the device sample rate
-    pub fn get_sample_rate(&self) -> u32 {
-        self.format.max_sample_rate.0
+    /// Create a new output device with a custom configuration
+    ///
+    /// # Parameters
+    ///
+    /// * `device` The CPAL device to write into
+    /// * `event_loop` The CPAL event loop that will drive the device stream
+    /// * `sample_rate` Sample rate to use, the highest rate of the device by default
+    /// * `block_size` Samples block size of the graph, 10ms of samples by default
+    pub fn with_config(
+        device: cpal::Device,
+        event_loop: cpal::EventLoop,
+        sample_rate: Option<u32>,
+        block_size: Option<u32>,
+    ) -> Result<Self, DeviceError> {
+        let id = DeviceId(crate::supervisor::linker::new_id());
+        let mut formats_range = device.supported_output_formats()?;
+        let format = match sample_rate {
+            Some(rate) => formats_range
+                .find(|format| format.min_sample_rate.0 <= rate && rate <= format.max_sample_rate.0)
+                .map(|format| cpal::Format {
+                    channels: format.channels,
+                    sample_rate: cpal::SampleRate(rate),
+                    data_type: format.data_type,
+                }),
+            None => formats_range
+                .next()
+                .map(|format| format.with_max_sample_rate()),
+        }
+        .ok_or(DeviceError::NoSupportedFormat)?;
+        let block_size =
+            block_size.unwrap_or(format.sample_rate.0 / 100 * u32::from(format.channels));
+        Ok(Self {
+            device,
+            format,
+            block_size,
+            latency: 0,
+            id,
+            event_loop: Arc::new(event_loop),
+            render: Arc::new(Mutex::new(None)),
+            stream: None,
+            event_thread: None,
+        })
     }
 
-    /// Get the device samples block size
-    pub fn get_block_size(&self) -> u32 {
-        self.get_sample_rate() / 100 * (self.format.channels as u32)
+    /// Run the CPAL event loop, it never returns so it get its own thread
+    fn spawn_event_loop(&mut self) {
+        let event_loop = self.event_loop.clone();
+        let render = self.render.clone();
+        let mut scratch: Vec<f32> = Vec::new();
+        self.event_thread = Some(thread::spawn(move || {
+            event_loop.run(move |stream, data| {
+                let buffer = match data {
+                    Ok(StreamData::Output { buffer }) => buffer,
+                    Ok(_) => return,
+                    Err(err) => {
+                        error!("Output stream {:?}: {}", stream, err);
+                        return;
+                    }
+                };
+                let mut render = render.lock().unwrap();
+                match (render.as_mut(), buffer) {
+                    (Some(render), UnknownTypeOutputBuffer::F32(mut buffer)) => render(&mut buffer),
+                    (Some(render), UnknownTypeOutputBuffer::I16(mut buffer)) => {
+                        scratch.resize(buffer.len(), 0.0);
+                        render(&mut scratch);
+                        for (out, sample) in buffer.iter_mut().zip(scratch.iter()) {
+                            *out = conv::f32::to_i16(*sample);
+                        }
+                    }
+                    (Some(render), UnknownTypeOutputBuffer::U16(mut buffer)) => {
+                        scratch.resize(buffer.len(), 0.0);
+                        render(&mut scratch);
+                        for (out, sample) in buffer.iter_mut().zip(scratch.iter()) {
+                            *out = conv::f32::to_u16(*sample);
+                        }
+                    }
+                    (None, UnknownTypeOutputBuffer::F32(mut buffer)) => {
+                        buffer.iter_mut().for_each(|out| *out = 0.0)
+                    }
+                    (None, UnknownTypeOutputBuffer::I16(mut buffer)) => {
+                        buffer.iter_mut().for_each(|out| *out = 0)
+                    }
+                    (None, UnknownTypeOutputBuffer::U16(mut buffer)) => buffer
+                        .iter_mut()
+                        .for_each(|out| *out = std::u16::MAX / 2 + 1),
+                }
+            })
+        }));
     }
 }
 
@@ -49,11 +228,232 @@ impl SampleDevice for SysOutputDevice {
         self.get_block_size() as usize
     }
     fn nbr_channel(&self) -> usize {
-        2
+        self.format.channels as usize
     }
 }
 
-impl SampleOutput for SysOutputDevice {}
+impl OutputDevice for SysOutputDevice {
+    fn get_sample_rate(&self) -> u32 {
+        self.format.sample_rate.0
+    }
+
+    fn get_block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn get_latency(&self) -> usize {
+        self.latency
+    }
+
+    fn set_latency(&mut self, latency: usize) {
+        self.latency = latency;
+    }
+
+    fn start(&mut self, render: RenderCallback) -> Result<(), DeviceError> {
+        if self.stream.is_some() {
+            return Err(DeviceError::AlreadyRunning);
+        }
+        let stream = self
+            .event_loop
+            .build_output_stream(&self.device, &self.format)?;
+        *self.render.lock().unwrap() = Some(render);
+        if let Err(err) = self.event_loop.play_stream(stream.clone()) {
+            self.event_loop.destroy_stream(stream);
+            self.render.lock().unwrap().take();
+            return Err(err.into());
+        }
+        self.stream = Some(stream);
+        if self.event_thread.is_none() {
+            self.spawn_event_loop();
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            self.event_loop.destroy_stream(stream);
+        }
+        self.render.lock().unwrap().take();
+    }
+
+    fn is_running(&self) -> bool {
+        self.stream.is_some()
+    }
+}
+
+/// Headless output device, the graph is pulled from a background thread without any sound card.
+/// Rendered blocks can be read back through the loopback channel.
+pub struct NullOutputDevice {
+    id: DeviceId,
+    sample_rate: u32,
+    block_size: u32,
+    /// Latency of the graph, in samples
+    latency: usize,
+    channels: usize,
+    realtime: bool,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    loopback: Option<SyncSender<Vec<f32>>>,
+}
+
+impl NullOutputDevice {
+    /// Create a new headless device
+    ///
+    /// # Parameters
+    ///
+    /// * `sample_rate` The sample rate reported to the graph
+    /// * `block_size` The samples block size (per channel)
+    pub fn new(sample_rate: u32, block_size: u32) -> Self {
+        let id = DeviceId(crate::supervisor::linker::new_id());
+        Self {
+            id,
+            sample_rate,
+            block_size,
+            latency: 0,
+            channels: 2,
+            realtime: true,
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+            loopback: None,
+        }
+    }
+
+    /// When `realtime` is false blocks are pulled as fast as possible
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    /// Get a channel receiving every rendered block (interleaved)
+    ///
+    /// # Parameters
+    ///
+    /// * `capacity` Number of blocks kept when the receiver is late, extra blocks are dropped
+    pub fn loopback(&mut self, capacity: usize) -> Receiver<Vec<f32>> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        self.loopback = Some(sender);
+        receiver
+    }
+}
+
+impl SampleDevice for NullOutputDevice {
+    fn id(&self) -> DeviceId {
+        self.id
+    }
+    fn block_size(&self) -> usize {
+        self.block_size as usize
+    }
+    fn nbr_channel(&self) -> usize {
+        self.channels
+    }
+}
+
+impl OutputDevice for NullOutputDevice {
+    fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn get_block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn get_latency(&self) -> usize {
+        self.latency
+    }
+
+    fn set_latency(&mut self, latency: usize) {
+        self.latency = latency;
+    }
+
+    fn start(&mut self, mut render: RenderCallback) -> Result<(), DeviceError> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(DeviceError::AlreadyRunning);
+        }
+        let running = self.running.clone();
+        let loopback = self.loopback.clone();
+        let mut buffer = vec![0f32; self.block_size as usize * self.channels];
+        let period = if self.realtime {
+            Some(Duration::from_secs(1) * self.block_size / self.sample_rate)
+        } else {
+            None
+        };
+        self.thread = Some(thread::spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                render(&mut buffer);
+                if let Some(loopback) = loopback.as_ref() {
+                    let _ = loopback.try_send(buffer.clone());
+                }
+                if let Some(period) = period {
+                    thread::sleep(period);
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Null output device thread panicked");
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for NullOutputDevice {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Last device of the graph, samples piped into it are sent to the main output device.
+///
+/// The samples are kept by the compiled graph, see `Graph::input_samples`
+#[derive(Clone)]
+pub struct MasterSample {
+    id: DeviceId,
+    block_size: usize,
+    channels: usize,
+}
+
+impl MasterSample {
+    /// Create a new master input
+    ///
+    /// # Parameters
+    ///
+    /// * `size` size of the sample buffer
+    /// * `channels` number of channels of the main output
+    pub fn new(size: usize, channels: usize) -> Self {
+        let id = DeviceId(crate::supervisor::linker::new_id());
+        Self {
+            id,
+            block_size: size,
+            channels,
+        }
+    }
+}
+
+impl SampleDevice for MasterSample {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn nbr_channel(&self) -> usize {
+        self.channels
+    }
+
+    fn id(&self) -> DeviceId {
+        self.id
+    }
+}
+
+impl SampleInput for MasterSample {
+    fn next(&mut self, _buffer: &[f32], _channel: usize) {}
+}
 
 /// Black hole that just log incoming samples
 pub struct LoggerSample {
@@ -96,25 +496,368 @@ impl SampleInput for LoggerSample {
     }
 }
 
+/// Format of the files written by `FileSampleInput`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// WAV with 32 bits float samples
+    WavFloat,
+    /// WAV with integer samples of the given bit depth (8, 16, 24 or 32)
+    WavPcm(u16),
+    /// FLAC with the given bit depth (8, 16 or 24)
+    Flac(u16),
+}
+
+enum FileWriter {
+    Wav(hound::WavWriter<BufWriter<File>>, Option<u16>),
+    Flac(FlacWriter<BufWriter<File>>, u16),
+}
+
+impl FileWriter {
+    fn write(&mut self, sample: f32) -> Result<(), DeviceError> {
+        match self {
+            FileWriter::Wav(writer, None) => writer.write_sample(sample)?,
+            FileWriter::Wav(writer, Some(bits)) => writer.write_sample(to_int(sample, *bits))?,
+            FileWriter::Flac(writer, bits) => writer.write(&[to_int(sample, *bits)])?,
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> Result<(), DeviceError> {
+        match self {
+            FileWriter::Wav(writer, _) => writer.finalize()?,
+            FileWriter::Flac(writer, _) => {
+                writer.finalize()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert a sample to a clamped integer of `bits` bits
+fn to_int(sample: f32, bits: u16) -> i32 {
+    let max = (1i64 << (bits - 1)) as f64;
+    (f64::from(sample) * max).round().max(-max).min(max - 1.0) as i32
+}
+
+struct FileSinkState {
+    writer: Option<FileWriter>,
+    /// First write error, writing stops when it occurs
+    error: Option<DeviceError>,
+    block: Vec<Vec<f32>>,
+    frames: usize,
+}
+
+/// Write the samples piped into it to an audio file, used to render the graph offline.
+///
+/// Samples are encoded from the processing thread so this device is not meant to be used
+/// during real time playback
+#[derive(Clone)]
+pub struct FileSampleInput {
+    id: DeviceId,
+    block_size: usize,
+    channels: usize,
+    state: Arc<Mutex<FileSinkState>>,
+}
+
+impl FileSampleInput {
+    /// Create the file, it is completed by `finalize`
+    ///
+    /// # Parameters
+    ///
+    /// * `path` Path of the created file
+    /// * `format` Encoding of the file
+    /// * `sample_rate` Sample rate of the graph
+    /// * `block_size` Size of the sample blocks
+    /// * `channels` Number of channels
+    pub fn create<T: AsRef<Path>>(
+        path: T,
+        format: FileFormat,
+        sample_rate: u32,
+        block_size: usize,
+        channels: usize,
+    ) -> Result<Self, DeviceError> {
+        let wav_spec = |bits_per_sample, sample_format| hound::WavSpec {
+            channels: channels as u16,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        };
+        let writer = match format {
+            FileFormat::WavFloat => FileWriter::Wav(
+                hound::WavWriter::create(path, wav_spec(32, hound::SampleFormat::Float))?,
+                None,
+            ),
+            FileFormat::WavPcm(bits) if bits == 8 || bits == 16 || bits == 24 || bits == 32 => {
+                FileWriter::Wav(
+                    hound::WavWriter::create(path, wav_spec(bits, hound::SampleFormat::Int))?,
+                    Some(bits),
+                )
+            }
+            FileFormat::Flac(bits) if bits == 8 || bits == 16 || bits == 24 => {
+                let file = BufWriter::new(File::create(path)?);
+                FileWriter::Flac(
+                    FlacWriter::new(file, channels, sample_rate, u32::from(bits), block_size)?,
+                    bits,
+                )
+            }
+            _ => return Err(DeviceError::UnsupportedFileFormat(format)),
+        };
+        Ok(Self {
+            id: DeviceId(crate::supervisor::linker::new_id()),
+            block_size,
+            channels,
+            state: Arc::new(Mutex::new(FileSinkState {
+                writer: Some(writer),
+                error: None,
+                block: vec![vec![0f32; block_size]; channels],
+                frames: 0,
+            })),
+        })
+    }
+
+    /// Complete and close the file, returns the number of frames written
+    pub fn finalize(&self) -> Result<usize, DeviceError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.error.take() {
+            state.writer = None;
+            return Err(err);
+        }
+        state
+            .writer
+            .take()
+            .ok_or(DeviceError::Finalized)?
+            .finalize()?;
+        Ok(state.frames)
+    }
+}
+
+impl SampleDevice for FileSampleInput {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn nbr_channel(&self) -> usize {
+        self.channels
+    }
+
+    fn id(&self) -> DeviceId {
+        self.id
+    }
+}
+
+impl SampleInput for FileSampleInput {
+    fn next(&mut self, buffer: &[f32], channel: usize) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(block) = state.block.get_mut(channel) {
+            block.copy_from_slice(buffer);
+        }
+        if channel + 1 != self.channels || state.error.is_some() {
+            return;
+        }
+        if let Some(writer) = state.writer.as_mut() {
+            for frame in 0..self.block_size {
+                for channel in state.block.iter() {
+                    if let Err(err) = writer.write(channel[frame]) {
+                        state.error = Some(err);
+                        return;
+                    }
+                }
+            }
+            state.frames += self.block_size;
+        }
+    }
+}
+
+/// Marker of a seek request that has not been set
+const NO_SEEK: usize = usize::max_value();
+
+#[derive(Debug)]
+struct TransportState {
+    frames: usize,
+    playhead: AtomicUsize,
+    seek: AtomicUsize,
+    start: AtomicUsize,
+    end: AtomicUsize,
+    looping: AtomicBool,
+    ended: AtomicBool,
+}
+
+/// Transport controls of an `AssetSampleOutput`, usable from any thread while the asset is
+/// played. Positions are expressed in frames of the resampled asset, changes are applied at
+/// the start of the next block.
+#[derive(Debug, Clone)]
+pub struct AssetTransport {
+    state: Arc<TransportState>,
+}
+
+impl AssetTransport {
+    fn new(frames: usize) -> Self {
+        Self {
+            state: Arc::new(TransportState {
+                frames,
+                playhead: AtomicUsize::new(0),
+                seek: AtomicUsize::new(NO_SEEK),
+                start: AtomicUsize::new(0),
+                end: AtomicUsize::new(frames),
+                looping: AtomicBool::new(false),
+                ended: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Get the position of the next played block
+    pub fn position(&self) -> usize {
+        self.state.playhead.load(Ordering::Acquire)
+    }
+
+    /// Move the playhead to `frame`, seeking past the end marker ends the stream
+    pub fn seek(&self, frame: usize) {
+        self.state
+            .seek
+            .store(frame.min(self.state.frames), Ordering::Release);
+    }
+
+    /// Restart the playback at the start marker
+    pub fn rewind(&self) {
+        self.seek(self.state.start.load(Ordering::Acquire));
+    }
+
+    /// When looping the playhead jumps back to the start marker once the end marker is reached
+    pub fn set_looping(&self, looping: bool) {
+        self.state.looping.store(looping, Ordering::Release);
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.state.looping.load(Ordering::Acquire)
+    }
+
+    /// Restrict the played region to `start..end`, `None` stands for the end of the asset
+    pub fn set_markers(&self, start: usize, end: Option<usize>) {
+        let end = end.unwrap_or(self.state.frames).min(self.state.frames);
+        self.state.start.store(start.min(end), Ordering::Release);
+        self.state.end.store(end, Ordering::Release);
+    }
+
+    /// Get the start and end markers
+    pub fn markers(&self) -> (usize, usize) {
+        (
+            self.state.start.load(Ordering::Acquire),
+            self.state.end.load(Ordering::Acquire),
+        )
+    }
+
+    /// Is the playhead past the end marker
+    pub fn is_ended(&self) -> bool {
+        self.state.ended.load(Ordering::Acquire)
+    }
+
+    /// Get the number of frames of the asset
+    pub fn frames(&self) -> usize {
+        self.state.frames
+    }
+}
+
+/// Stream an audio asset into the graph, the asset is resampled to the output sample rate.
+///
+/// The playhead advances by one block each time the first channel is read, see
+/// `AssetTransport` to control the playback. A mono asset is played on both channels of a
+/// stereo device.
 #[derive(Debug)]
 pub struct AssetSampleOutput {
     id: DeviceId,
     asset: AudioAsset,
     block_size: usize,
-    buffer: Arc<RwLock<Vec<f32>>>,
+    transport: AssetTransport,
+    /// Position of the block being read
+    block_start: usize,
+    start: usize,
+    end: usize,
+    looping: bool,
 }
 
 impl AssetSampleOutput {
-    pub fn new(asset: AudioAsset, block_size: usize) -> Self {
+    /// Create an output playing `asset` at `sample_rate`, using windowed sinc resampling
+    pub fn new(asset: AudioAsset, block_size: usize, sample_rate: u32) -> Self {
+        Self::with_quality(asset, block_size, sample_rate, ResampleQuality::default())
+    }
+
+    /// Create an output playing `asset` at `sample_rate`
+    ///
+    /// # Parameters
+    ///
+    /// * `asset` The played asset
+    /// * `block_size` Size of the sample blocks
+    /// * `sample_rate` Sample rate of the graph, the asset is converted if needed
+    /// * `quality` Interpolation used for the conversion
+    pub fn with_quality(
+        asset: AudioAsset,
+        block_size: usize,
+        sample_rate: u32,
+        quality: ResampleQuality,
+    ) -> Self {
+        let asset = if asset.sample_rate != sample_rate {
+            info!(
+                "Resampling asset from {} Hz to {} Hz ({:?})",
+                asset.sample_rate, sample_rate, quality
+            );
+            asset.resample(sample_rate, quality)
+        } else {
+            asset
+        };
         let id = DeviceId(crate::supervisor::linker::new_id());
-        let buffer = Arc::new(RwLock::new(Vec::from(&asset.buffer[0..block_size])));
+        let transport = AssetTransport::new(asset.frames());
         Self {
             id,
             block_size,
+            end: asset.frames(),
             asset,
-            buffer,
+            transport,
+            block_start: 0,
+            start: 0,
+            looping: false,
         }
     }
+
+    /// Get the played asset
+    pub fn asset(&self) -> &AudioAsset {
+        &self.asset
+    }
+
+    /// Get a handle on the transport controls
+    pub fn transport(&self) -> AssetTransport {
+        self.transport.clone()
+    }
+
+    /// Wrap `position` into the loop region
+    fn wrap(&self, position: usize) -> usize {
+        if self.looping && self.end > self.start && position >= self.end {
+            self.start + (position - self.start) % (self.end - self.start)
+        } else {
+            position
+        }
+    }
+
+    /// Apply the transport changes and move on to the next block
+    fn next_block(&mut self) {
+        let state = &self.transport.state;
+        self.start = state.start.load(Ordering::Acquire);
+        self.end = state.end.load(Ordering::Acquire);
+        self.looping = state.looping.load(Ordering::Acquire);
+        let position = match state.seek.swap(NO_SEEK, Ordering::AcqRel) {
+            NO_SEEK => state.playhead.load(Ordering::Acquire),
+            seek => seek,
+        };
+        self.block_start = self.wrap(position);
+        let ended = self.block_start >= self.end;
+        state.ended.store(ended, Ordering::Release);
+        let next = if ended {
+            self.block_start
+        } else {
+            self.wrap(self.block_start + self.block_size).min(self.end)
+        };
+        state.playhead.store(next, Ordering::Release);
+    }
 }
 
 impl SampleDevice for AssetSampleOutput {
@@ -123,7 +866,10 @@ impl SampleDevice for AssetSampleOutput {
     }
 
     fn nbr_channel(&self) -> usize {
-        2
+        match self.asset.channels() {
+            1 => 2,
+            channels => channels,
+        }
     }
 
     fn id(&self) -> DeviceId {
@@ -132,17 +878,248 @@ impl SampleDevice for AssetSampleOutput {
 }
 
 impl SampleOutput for AssetSampleOutput {
-    fn next(&self, channel: usize) -> Option<RwLockReadGuard<Vec<f32>>> {
-        Some(self.buffer.read().unwrap())
+    fn next(&mut self, buffer: &mut [f32], channel: usize) -> bool {
+        if channel == 0 {
+            self.next_block();
+        }
+        let source = if self.asset.channels() == 1 {
+            0
+        } else {
+            channel
+        };
+        let samples = match self.asset.buffer.get(source) {
+            Some(samples) if self.block_start < self.end => samples,
+            _ => return false,
+        };
+        let mut position = self.block_start;
+        for sample in buffer.iter_mut() {
+            position = self.wrap(position);
+            *sample = if position < self.end {
+                samples[position]
+            } else {
+                0.0
+            };
+            position += 1;
+        }
+        true
+    }
+}
+
+/// Play a list of MIDI events positioned in quarter notes, e.g. to drive an instrument plugin
+///
+/// The events are scheduled from the transport of each block through its tempo map, so the
+/// sequence follows the tempo changes, seeks and loops of the transport. Nothing is played
+/// while the transport is stopped and the sounding notes are stopped whenever the playback
+/// jumps.
+pub struct MidiSequenceOutput {
+    id: DeviceId,
+    /// Events sorted by position
+    events: Vec<(f64, MidiEvent)>,
+    block_size: usize,
+    /// Position in samples of the last played block
+    played: Option<f64>,
+    /// Position in samples the next block plays from when the playback doesn't jump
+    next_position: Option<f64>,
+    /// Notes started and not stopped yet, a bit per key of each channel
+    sounding: [u128; 16],
+}
+
+impl MidiSequenceOutput {
+    /// Create an output playing `events`, each paired with its position in quarter notes,
+    /// events at the same position keep their order
+    pub fn new(mut events: Vec<(f64, MidiEvent)>, block_size: usize) -> Self {
+        events.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Self {
+            id: DeviceId(crate::supervisor::linker::new_id()),
+            events,
+            block_size,
+            played: None,
+            next_position: None,
+            sounding: [0; 16],
+        }
+    }
+
+    /// Push a note off for every sounding note, at `frame` in the block
+    fn release(&mut self, buffer: &mut MidiBuffer, frame: usize) {
+        for (channel, keys) in self.sounding.iter_mut().enumerate() {
+            while *keys != 0 {
+                let key = keys.trailing_zeros();
+                let mut event = midi_event(frame as i32, [0x80 | channel as u8, key as u8, 0]);
+                event.live = false;
+                // A full buffer keeps the remaining notes for the next block
+                if !buffer.push(event) {
+                    return;
+                }
+                *keys &= !(1 << key);
+            }
+        }
+    }
+
+    /// Keep track of the sounding notes
+    fn track(&mut self, event: &MidiEvent) {
+        let [status, key, velocity] = event.data;
+        let keys = &mut self.sounding[usize::from(status & 0x0F)];
+        let bit = 1u128 << (key & 0x7F);
+        match status & 0xF0 {
+            0x90 if velocity > 0 => *keys |= bit,
+            0x80 | 0x90 => *keys &= !bit,
+            _ => {}
+        }
+    }
+
+    /// Push the events of a block segment, see `Transport::segments`
+    fn play(&mut self, buffer: &mut MidiBuffer, segment: BlockSegment, map: &TempoMap, rate: f64) {
+        let start = map.samples_to_beats(segment.sample_pos, rate);
+        let end = map.samples_to_beats(segment.sample_pos + segment.frames as f64, rate);
+        let first = self.events.partition_point(|(beat, _)| *beat < start);
+        for idx in first..self.events.len() {
+            let (beat, event) = self.events[idx];
+            if beat >= end {
+                break;
+            }
+            let offset = (map.beats_to_samples(beat, rate) - segment.sample_pos).round();
+            let offset = (offset.max(0.0) as usize).min(segment.frames - 1);
+            let mut event = event;
+            event.delta_frames = (segment.frame + offset) as i32;
+            // Events past the capacity of the buffer are dropped
+            if !buffer.push(event) {
+                break;
+            }
+            self.track(&event);
+        }
+    }
+
+    /// Is the transport past the last event, for good
+    fn is_past_end(&self, transport: &Transport) -> bool {
+        let beat = transport.ppq_pos();
+        let looping = transport
+            .loop_range()
+            .map(|(_, end)| beat < end)
+            .unwrap_or(false);
+        let played = self.events.last().map(|(last, _)| *last < beat);
+        !looping && played.unwrap_or(true)
+    }
+}
+
+impl MidiDevice for MidiSequenceOutput {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn id(&self) -> DeviceId {
+        self.id
+    }
+}
+
+impl MidiOutput for MidiSequenceOutput {
+    fn next(
+        &mut self,
+        buffer: &mut MidiBuffer,
+        transport: Option<&Transport>,
+        tempo_map: &TempoMap,
+    ) -> bool {
+        let transport = match transport {
+            Some(transport) if transport.is_playing() => transport,
+            Some(transport) => {
+                self.release(buffer, 0);
+                self.played = None;
+                self.next_position = None;
+                return !self.is_past_end(transport);
+            }
+            None => return true,
+        };
+        let position = transport.sample_pos();
+        // The host may give the position of the previous block again, it was already played
+        if self.played == Some(position) && self.next_position != Some(position) {
+            return true;
+        }
+        if self.next_position != Some(position) {
+            self.release(buffer, 0);
+        }
+        let rate = transport.sample_rate();
+        let mut end = position;
+        for segment in transport.segments(self.block_size, tempo_map) {
+            if segment.frame > 0 {
+                // The playback wraps around the loop
+                self.release(buffer, segment.frame);
+            }
+            self.play(buffer, segment, tempo_map, rate);
+            end = segment.sample_pos + segment.frames as f64;
+        }
+        self.played = Some(position);
+        self.next_position = Some(end);
+        !self.is_past_end(transport)
     }
 }
 
-/// A device that send or get data from/to a loaded vst plugin
+/// Play the tracks of a MIDI file into the graph, e.g. piped into the MIDI input of a synth
+///
+/// The events are kept in quarter notes and follow the tempo map of the transport: set
+/// `MidiFile::tempo_map` as the host map to play the file at its own tempo.
+pub struct MidiFileOutput {
+    sequence: MidiSequenceOutput,
+}
+
+impl MidiFileOutput {
+    /// Create an output playing every track of `file`
+    pub fn new(file: &MidiFile, block_size: usize) -> Self {
+        let tracks: Vec<usize> = (0..file.tracks.len()).collect();
+        Self::with_tracks(file, &tracks, block_size)
+    }
+
+    /// Create an output playing some tracks of `file`, unknown tracks are ignored
+    ///
+    /// # Parameters
+    ///
+    /// * `file` The played file
+    /// * `tracks` Indexes of the played tracks, events at the same position keep this order
+    /// * `block_size` Size of the sample blocks
+    pub fn with_tracks(file: &MidiFile, tracks: &[usize], block_size: usize) -> Self {
+        let events = tracks
+            .iter()
+            .filter_map(|track| file.tracks.get(*track))
+            .flat_map(|track| track.events.iter())
+            .map(|event| {
+                let mut midi = midi_event(0, event.data);
+                midi.live = false;
+                (file.ticks_to_beats(event.tick), midi)
+            })
+            .collect();
+        Self {
+            sequence: MidiSequenceOutput::new(events, block_size),
+        }
+    }
+}
+
+impl MidiDevice for MidiFileOutput {
+    fn block_size(&self) -> usize {
+        MidiDevice::block_size(&self.sequence)
+    }
+
+    fn id(&self) -> DeviceId {
+        MidiDevice::id(&self.sequence)
+    }
+}
+
+impl MidiOutput for MidiFileOutput {
+    fn next(
+        &mut self,
+        buffer: &mut MidiBuffer,
+        transport: Option<&Transport>,
+        tempo_map: &TempoMap,
+    ) -> bool {
+        self.sequence.next(buffer, transport, tempo_map)
+    }
+}
+
+/// A device that send or get data from/to a loaded vst plugin, the samples are
+/// stored by the compiled graph and processed by the plugin itself
 #[derive(Clone)]
 pub struct VstBufferedDevice {
     id: DeviceId,
     vst_id: VstId,
-    buffer: Vec<Arc<RwLock<Vec<f32>>>>,
+    block_size: usize,
+    channels: usize,
 }
 
 impl VstBufferedDevice {
@@ -151,18 +1128,19 @@ impl VstBufferedDevice {
         Self {
             vst_id,
             id,
-            buffer: vec![Arc::new(RwLock::new(vec![0f32; size])); channels],
+            block_size: size,
+            channels,
         }
     }
 }
 
 impl SampleDevice for VstBufferedDevice {
     fn block_size(&self) -> usize {
-        self.buffer[0].read().unwrap().len()
+        self.block_size
     }
 
     fn nbr_channel(&self) -> usize {
-        self.buffer.len()
+        self.channels
     }
 
     fn id(&self) -> DeviceId {
@@ -175,16 +1153,171 @@ impl SampleDevice for VstBufferedDevice {
 }
 
 impl SampleOutput for VstBufferedDevice {
-    fn next(&self, channel: usize) -> Option<std::sync::RwLockReadGuard<Vec<f32>>> {
-        Some(self.buffer[channel].read().unwrap())
+    fn next(&mut self, _buffer: &mut [f32], _channel: usize) -> bool {
+        false
     }
 }
 
 impl SampleInput for VstBufferedDevice {
-    fn next(&mut self, buffer: &[f32], channel: usize) {
-        self.buffer[channel]
-            .write()
-            .unwrap()
-            .copy_from_slice(buffer);
+    fn next(&mut self, _buffer: &[f32], _channel: usize) {}
+}
+
+impl MidiDevice for VstBufferedDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn id(&self) -> DeviceId {
+        self.id
+    }
+
+    fn parent_vst(&self) -> Option<VstId> {
+        Some(self.vst_id)
+    }
+}
+
+impl MidiInput for VstBufferedDevice {}
+
+impl MidiOutput for VstBufferedDevice {
+    fn next(
+        &mut self,
+        _buffer: &mut MidiBuffer,
+        _transport: Option<&Transport>,
+        _tempo_map: &TempoMap,
+    ) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(frames: usize) -> AssetSampleOutput {
+        let left = (0..frames).map(|frame| frame as f32).collect::<Vec<_>>();
+        let right = left.iter().map(|sample| -sample).collect();
+        AssetSampleOutput::new(AudioAsset::new(vec![left, right], 48000), 4, 48000)
+    }
+
+    fn block(output: &mut AssetSampleOutput) -> Option<Vec<Vec<f32>>> {
+        let mut block = vec![vec![0f32; 4]; 2];
+        let mut playing = false;
+        for (channel, samples) in block.iter_mut().enumerate() {
+            playing |= output.next(samples, channel);
+        }
+        if playing {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn asset_stream() {
+        let mut output = ramp(6);
+        let transport = output.transport();
+        assert_eq!(
+            block(&mut output),
+            Some(vec![vec![0.0, 1.0, 2.0, 3.0], vec![0.0, -1.0, -2.0, -3.0]])
+        );
+        assert_eq!(transport.position(), 4);
+        assert_eq!(
+            block(&mut output),
+            Some(vec![vec![4.0, 5.0, 0.0, 0.0], vec![-4.0, -5.0, 0.0, 0.0]])
+        );
+        assert!(!transport.is_ended());
+        assert_eq!(block(&mut output), None);
+        assert!(transport.is_ended());
+        transport.rewind();
+        assert_eq!(block(&mut output).unwrap()[0], vec![0.0, 1.0, 2.0, 3.0]);
+        assert!(!transport.is_ended());
+    }
+
+    #[test]
+    fn asset_mono_on_stereo() {
+        let mono = AudioAsset::new(vec![vec![1.0, 2.0, 3.0]], 48000);
+        let mut output = AssetSampleOutput::new(mono, 4, 48000);
+        assert_eq!(output.nbr_channel(), 2);
+        assert_eq!(
+            block(&mut output),
+            Some(vec![vec![1.0, 2.0, 3.0, 0.0], vec![1.0, 2.0, 3.0, 0.0]])
+        );
+    }
+
+    #[test]
+    fn asset_loop_and_markers() {
+        let mut output = ramp(10);
+        let transport = output.transport();
+        transport.set_markers(2, Some(5));
+        transport.set_looping(true);
+        transport.seek(3);
+        assert_eq!(block(&mut output).unwrap()[0], vec![3.0, 4.0, 2.0, 3.0]);
+        assert_eq!(transport.position(), 4);
+        assert_eq!(block(&mut output).unwrap()[0], vec![4.0, 2.0, 3.0, 4.0]);
+        transport.set_looping(false);
+        assert_eq!(block(&mut output).unwrap()[0], vec![2.0, 3.0, 4.0, 0.0]);
+        assert_eq!(block(&mut output), None);
+        assert_eq!(transport.markers(), (2, 5));
+    }
+
+    #[test]
+    fn midi_file_playback() {
+        let file = MidiFile::decode(&crate::loader::smf::tests::two_tracks()[..]).unwrap();
+        // 100 samples per quarter note
+        let map = TempoMap::new(60.0);
+        let mut transport = Transport::new(100.0);
+        let mut output = MidiFileOutput::new(&file, 64);
+        let mut buffer = MidiBuffer::new(8);
+        let mut next = |output: &mut MidiFileOutput, transport: &Transport| {
+            buffer.clear();
+            let playing = output.next(&mut buffer, Some(transport), &map);
+            let events: Vec<_> = buffer
+                .events()
+                .iter()
+                .map(|event| (event.delta_frames, event.data[0]))
+                .collect();
+            (playing, events)
+        };
+        assert_eq!(next(&mut output, &transport), (true, vec![]));
+        transport.play();
+        let mut blocks = Vec::new();
+        loop {
+            let (playing, events) = next(&mut output, &transport);
+            if !playing {
+                break;
+            }
+            blocks.push(events);
+            transport.advance(64, &map);
+        }
+        assert_eq!(
+            blocks,
+            vec![
+                vec![(0, 0xC0), (0, 0x90)],
+                vec![(36, 0x90), (36, 0x90)],
+                vec![],
+                vec![],
+                vec![(44, 0x80)],
+            ]
+        );
+
+        // The block wrapping around the loop stops the sounding note and plays the start
+        transport.set_loop(Some((0.0, 1.5)));
+        transport.seek(64.0, &map);
+        assert_eq!(
+            next(&mut output, &transport),
+            (true, vec![(36, 0x90), (36, 0x90)])
+        );
+        // The same block is not played twice
+        assert_eq!(next(&mut output, &transport), (true, vec![]));
+        transport.advance(64, &map);
+        assert_eq!(
+            next(&mut output, &transport),
+            (true, vec![(22, 0x80), (22, 0xC0), (22, 0x90)])
+        );
+        transport.advance(64, &map);
+        assert_eq!(transport.sample_pos(), 42.0);
+        transport.stop();
+        assert_eq!(next(&mut output, &transport), (true, vec![(0, 0x80)]));
+        assert_eq!(next(&mut output, &transport), (true, vec![]));
     }
 }
@@ -7,6 +7,12 @@ extern crate log;
 #[macro_use]
 extern crate failure;
 extern crate generational_arena;
+extern crate hound;
+extern crate lewton;
+extern crate libc;
+extern crate memmap;
+extern crate serde;
+extern crate serde_json;
 
 pub mod devices;
 pub mod loader;
@@ -15,67 +21,146 @@ pub mod supervisor;
 
 #[cfg(test)]
 mod tests {
+    use crate::{
+        devices::*,
+        loader::asset::AudioAsset,
+        prelude::*,
+        supervisor::{Supervisor, SupervisorError},
+    };
+    use std::path::Path;
+    use vst::host::PluginLoadError;
+
     #[test]
     fn all() {
         std::env::set_var("RUST_LOG", "trace");
         std::env::set_var("RUST_BACKTRACE", "full");
-        env_logger::init();
-        let media = AudioAsset::from_flac_file(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .open("examples/assets/sample.flac")
-                .unwrap(),
-        )
-        .expect("Sample");
-        let mut supervisor = Supervisor::new();
+        let _ = env_logger::try_init();
+        let media = AudioAsset::from_path("examples/assets/sample.flac").expect("Sample");
+        let mut supervisor = Supervisor::new().expect("Supervisor");
         let bsize = supervisor.main_output.block_size();
+        let plug = supervisor
+            .load_vst(Path::new("examples/vst/gain_effect.dll"))
+            .expect("Load vst");
+        let plug2 = supervisor
+            .load_vst(Path::new("examples/vst/gain_effect.dll"))
+            .expect("Load vst");
+        let (plug_input, plug_output) = {
+            let plugin = supervisor.plugins[&plug].lock().unwrap();
+            (plugin.get_inputs(), plugin.get_outputs())
+        };
+        let (plug2_input, plug2_output) = {
+            let plugin = supervisor.plugins[&plug2].lock().unwrap();
+            (plugin.get_inputs(), plugin.get_outputs())
+        };
+        let linker = &mut supervisor.linker;
+        let media_output = linker.register_output(Box::new(AssetSampleOutput::new(
+            media,
+            bsize,
+            supervisor.main_output.get_sample_rate(),
+        )));
+        let log_input = linker.register_input(Box::new(LoggerSample::new(bsize)));
+        linker
+            .pipe(media_output, plug_input)
+            .expect("Pipe flac -> vst");
+        linker
+            .pipe(plug_output, plug2_input)
+            .expect("Pipe vst -> vst");
+        linker
+            .pipe(plug2_output, log_input)
+            .expect("Pipe vst -> logger");
+        let mut graph = linker.compile(&supervisor.plugins).expect("Compile graph");
+        graph.process();
+        info!("All devices processed: {:?}", graph.get_schedule());
+    }
+
+    #[test]
+    fn null_output_playback() {
+        let mut output = NullOutputDevice::new(48000, 64);
+        output.set_realtime(false);
+        let loopback = output.loopback(4);
+        let mut supervisor = Supervisor::with_output(cpal::default_host(), Box::new(output));
+        let media = AudioAsset::new(vec![vec![0.5; 128]; 2], 48000);
         let media_output = supervisor
             .linker
-            .register_output(Box::new(AssetSampleOutput::new(media, bsize)));
-        let log_input = supervisor
+            .register_output(Box::new(AssetSampleOutput::new(media, 64, 48000)));
+        supervisor
             .linker
-            .register_input(Box::new(LoggerSample::new(bsize)));
-        let plug = supervisor.load_vst(Path::new("examples/vst/gain_effect.dll"));
-        let plug2 = supervisor.load_vst(Path::new("examples/vst/gain_effect.dll"));
-        let entry = supervisor
+            .pipe(media_output, supervisor.main_input)
+            .expect("Pipe asset -> main output");
+        supervisor.start().expect("Start playback");
+        let block = loopback
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("Rendered block");
+        supervisor.stop();
+        assert_eq!(block, vec![0.5; 128]);
+    }
+
+    #[test]
+    fn load_missing_vst() {
+        let output = NullOutputDevice::new(48000, 64);
+        let mut supervisor = Supervisor::with_output(cpal::default_host(), Box::new(output));
+        match supervisor.load_vst(Path::new("examples/vst/missing.dll")) {
+            Err(SupervisorError::PluginLoad(PluginLoadError::InvalidPath)) => {}
+            other => panic!("Unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(supervisor.plugins.is_empty());
+    }
+
+    #[test]
+    fn offline_render() {
+        let output = NullOutputDevice::new(48000, 64);
+        let mut supervisor = Supervisor::with_output(cpal::default_host(), Box::new(output));
+        let media = AudioAsset::new(vec![vec![0.5; 100]; 2], 48000);
+        let media_output = supervisor
             .linker
-            .pipe(media_output, supervisor.plugins[&plug].get_inputs())
-            .expect("Pipe flac -> vst");
+            .register_output(Box::new(AssetSampleOutput::new(media, 64, 48000)));
         supervisor
             .linker
-            .pipe(
-                supervisor.plugins[&plug].get_outputs(),
-                supervisor.plugins[&plug2].get_inputs(),
-            )
-            .expect("Pipe vst -> vst");
+            .pipe(media_output, supervisor.main_input)
+            .expect("Pipe asset -> main output");
+        let path = std::env::temp_dir().join("engine-offline-render.flac");
+        let frames = supervisor
+            .render(&path, FileFormat::Flac(16), None)
+            .expect("Render");
+        assert_eq!(frames, 192);
+        let rendered = AudioAsset::from_path(&path).expect("Rendered file");
+        std::fs::remove_file(&path).ok();
+        assert_eq!(rendered.channels(), 2);
+        assert_eq!(rendered.frames(), 192);
+        assert_eq!(rendered.buffer[1][99], 0.5);
+        assert_eq!(rendered.buffer[1][100], 0.0);
+    }
+
+    #[test]
+    fn render_twice() {
+        let asset = std::env::temp_dir().join("engine-render-twice-asset.flac");
+        let ramp: Vec<f32> = (0..100).map(|frame| frame as f32 / 128.0).collect();
+        let mut file = FileSampleInput::create(&asset, FileFormat::Flac(16), 48000, 100, 1)
+            .expect("Asset file");
+        SampleInput::next(&mut file, &ramp, 0);
+        file.finalize().expect("Finalize asset");
+        let output = NullOutputDevice::new(48000, 64);
+        let mut supervisor = Supervisor::with_output(cpal::default_host(), Box::new(output));
+        let media_output = supervisor.load_asset(&asset).expect("Load asset");
         supervisor
             .linker
-            .pipe(supervisor.plugins[&plug2].get_outputs(), log_input)
-            .expect("Pipe vst -> logger");
-        let mut tmp_left = vec![0f32; bsize];
-        let mut tmp_right = vec![0f32; bsize];
-        let linker = &mut supervisor.linker;
-        let plugins = &mut supervisor.plugins;
-        let mut actual = entry;
-        loop {
-            linker
-                .bind(
-                    actual,
-                    &mut [&mut tmp_left, &mut tmp_right],
-                    |mut audio_buffer, vst| {
-                        if let Some(vst) = vst {
-                            plugins.get_mut(&vst).unwrap().next(&mut audio_buffer);
-                        }
-                    },
-                )
-                .expect("Wrong pipe");
-            if let Some(next) = linker.get_next(actual) {
-                actual = next;
-            } else {
-                info!("All sequence done (last: {:?}) !", actual);
-                break;
-            }
-        }
-        info!("{:?}", &tmp_left[0..4]);
+            .pipe(media_output, supervisor.main_input)
+            .expect("Pipe asset -> main output");
+        let renders: Vec<_> = (0..2)
+            .map(|pass| {
+                let path = std::env::temp_dir().join(format!("engine-render-twice-{}.flac", pass));
+                let frames = supervisor
+                    .render(&path, FileFormat::Flac(16), None)
+                    .expect("Render");
+                let position = supervisor.vst_host.lock().unwrap().transport.sample_pos();
+                let rendered = AudioAsset::from_path(&path).expect("Rendered file");
+                std::fs::remove_file(&path).ok();
+                (frames, position, rendered.buffer)
+            })
+            .collect();
+        std::fs::remove_file(&asset).ok();
+        assert_eq!(renders[0].0, 192);
+        assert_eq!(renders[0].1, 192.0);
+        assert_eq!(renders[0], renders[1]);
     }
 }
@@ -0,0 +1,246 @@
+//! Minimal AIFF / AIFF-C decoder, only uncompressed PCM and 32 bits float are supported
+use std::io::{self, Read};
+
+#[derive(Debug, Fail)]
+pub enum AiffError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Not an AIFF stream")]
+    NotAiff,
+    #[fail(display = "Missing {} chunk", _0)]
+    MissingChunk(&'static str),
+    #[fail(display = "Malformed {} chunk", _0)]
+    MalformedChunk(&'static str),
+    #[fail(display = "Chunk of {} bytes truncated", _0)]
+    Truncated(usize),
+    #[fail(display = "Unsupported compression type {:?}", _0)]
+    UnsupportedCompression(String),
+    #[fail(display = "Unsupported sample size {}", _0)]
+    UnsupportedSampleSize(u16),
+}
+
+impl From<io::Error> for AiffError {
+    fn from(err: io::Error) -> Self {
+        AiffError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    BigEndian,
+    LittleEndian,
+    Float,
+}
+
+/// Format of an AIFF stream, read from the COMM chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AiffSpec {
+    pub channels: u16,
+    pub frames: u32,
+    pub bits_per_sample: u16,
+    pub sample_rate: f64,
+    encoding: Encoding,
+}
+
+/// Decode a whole AIFF stream into interleaved samples in the range [-1, 1]
+pub fn decode<T: Read>(mut rd: T) -> Result<(AiffSpec, Vec<f32>), AiffError> {
+    let mut header = [0u8; 12];
+    rd.read_exact(&mut header)?;
+    if &header[0..4] != b"FORM" {
+        return Err(AiffError::NotAiff);
+    }
+    let aifc = match &header[8..12] {
+        b"AIFF" => false,
+        b"AIFC" => true,
+        _ => return Err(AiffError::NotAiff),
+    };
+    let mut spec = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        if let Err(err) = rd.read_exact(&mut chunk) {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                break;
+            }
+            return Err(err.into());
+        }
+        let size = be_u32(&chunk[4..8]) as usize;
+        // Chunks are padded to an even size. The buffer grows with what is actually read, a
+        // size larger than the stream is not allocated upfront
+        let mut data = Vec::new();
+        rd.by_ref()
+            .take((size + size % 2) as u64)
+            .read_to_end(&mut data)?;
+        if data.len() < size {
+            return Err(AiffError::Truncated(size));
+        }
+        match &chunk[0..4] {
+            b"COMM" => spec = Some(read_comm(&data[..size], aifc)?),
+            b"SSND" => {
+                let spec = spec.ok_or(AiffError::MissingChunk("COMM"))?;
+                // Offset and block size come before the samples
+                if size < 8 {
+                    return Err(AiffError::MalformedChunk("SSND"));
+                }
+                let offset = be_u32(&data[0..4]) as usize;
+                let samples = read_ssnd(&spec, &data[(8 + offset).min(size)..size]);
+                return Ok((spec, samples));
+            }
+            _ => {}
+        }
+    }
+    Err(AiffError::MissingChunk(if spec.is_some() {
+        "SSND"
+    } else {
+        "COMM"
+    }))
+}
+
+fn read_comm(data: &[u8], aifc: bool) -> Result<AiffSpec, AiffError> {
+    if data.len() < 18 {
+        return Err(AiffError::MissingChunk("COMM"));
+    }
+    let bits_per_sample = be_u16(&data[6..8]);
+    let encoding = if aifc && data.len() >= 22 {
+        match &data[18..22] {
+            b"NONE" | b"twos" => Encoding::BigEndian,
+            b"sowt" => Encoding::LittleEndian,
+            b"fl32" | b"FL32" => Encoding::Float,
+            other => {
+                return Err(AiffError::UnsupportedCompression(
+                    String::from_utf8_lossy(other).into_owned(),
+                ))
+            }
+        }
+    } else {
+        Encoding::BigEndian
+    };
+    if (encoding == Encoding::Float && bits_per_sample != 32)
+        || bits_per_sample == 0
+        || bits_per_sample > 32
+    {
+        return Err(AiffError::UnsupportedSampleSize(bits_per_sample));
+    }
+    Ok(AiffSpec {
+        channels: be_u16(&data[0..2]),
+        frames: be_u32(&data[2..6]),
+        bits_per_sample,
+        sample_rate: extended_to_f64(&data[8..18]),
+        encoding,
+    })
+}
+
+fn read_ssnd(spec: &AiffSpec, data: &[u8]) -> Vec<f32> {
+    let width = (spec.bits_per_sample as usize + 7) / 8;
+    let count = (spec.frames as usize * spec.channels as usize).min(data.len() / width);
+    // Samples are left justified, scaling by the stored width gives the right range
+    let scale = 1.0 / (1u64 << (width * 8 - 1)) as f32;
+    data.chunks_exact(width)
+        .take(count)
+        .map(|bytes| match spec.encoding {
+            Encoding::Float => f32::from_bits(be_u32(bytes)),
+            Encoding::BigEndian => signed(bytes.iter().copied()) as f32 * scale,
+            Encoding::LittleEndian => signed(bytes.iter().rev().copied()) as f32 * scale,
+        })
+        .collect()
+}
+
+/// Sign extend big endian bytes
+fn signed<I: ExactSizeIterator<Item = u8>>(bytes: I) -> i32 {
+    let shift = 32 - bytes.len() * 8;
+    let value = bytes.fold(0u32, |acc, byte| (acc << 8) | byte as u32);
+    ((value << shift) as i32) >> shift
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) << 8 | u16::from(bytes[1])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    bytes[0..4]
+        .iter()
+        .fold(0u32, |acc, byte| (acc << 8) | u32::from(*byte))
+}
+
+/// Convert an IEEE 754 80 bits extended float
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let exponent = i32::from(be_u16(&bytes[0..2]) & 0x7fff);
+    let mantissa = bytes[2..10]
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    let value = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    if bytes[0] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aiff(samples: &[i16]) -> Vec<u8> {
+        let mut ssnd = vec![0u8; 8];
+        for sample in samples {
+            ssnd.extend_from_slice(&sample.to_be_bytes());
+        }
+        form(ssnd, samples.len() as u32 / 2)
+    }
+
+    fn form(ssnd: Vec<u8>, frames: u32) -> Vec<u8> {
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&2u16.to_be_bytes());
+        comm.extend_from_slice(&frames.to_be_bytes());
+        comm.extend_from_slice(&16u16.to_be_bytes());
+        // 44100 as an 80 bits extended float
+        comm.extend_from_slice(&[0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        let mut body = b"AIFF".to_vec();
+        for (id, data) in [(b"COMM", comm), (b"SSND", ssnd)].iter() {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            body.extend_from_slice(data);
+        }
+        let mut file = b"FORM".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        file.extend(body);
+        file
+    }
+
+    #[test]
+    fn decode_pcm16() {
+        let (spec, samples) = decode(&aiff(&[0, 16384, -32768, 32767])[..]).expect("Decode");
+        assert_eq!(spec.channels, 2);
+        assert_eq!(spec.frames, 2);
+        assert_eq!(spec.bits_per_sample, 16);
+        assert_eq!(spec.sample_rate, 44100.0);
+        assert_eq!(samples, vec![0.0, 0.5, -1.0, 32767.0 / 32768.0]);
+    }
+
+    #[test]
+    fn reject_malformed_chunks() {
+        match decode(&form(vec![0u8; 2], 0)[..]) {
+            Err(AiffError::MalformedChunk("SSND")) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        // The SSND chunk claims 4 GB but the stream ends right after its header
+        let mut file = aiff(&[0, 0]);
+        let ssnd = file.len() - 12;
+        file[ssnd + 4..ssnd + 8].copy_from_slice(&u32::max_value().to_be_bytes());
+        file.truncate(ssnd + 8);
+        match decode(&file[..]) {
+            Err(AiffError::Truncated(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reject_other_containers() {
+        match decode(&b"RIFF\0\0\0\0WAVE"[..]) {
+            Err(AiffError::NotAiff) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,12 +1,40 @@
-use claxon::FlacReader;
+use crate::loader::aiff::{self, AiffError};
 use crate::prelude::*;
-use sample::conv;
-use std::io::Read;
+use claxon::FlacReader;
+use hound::{SampleFormat, WavReader};
+use lewton::inside_ogg::OggStreamReader;
+use sample::{
+    conv,
+    interpolate::Linear,
+    signal::{self, Signal},
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
 
 #[derive(Debug, Fail)]
 pub enum AssetError {
-    #[fail(display = "FLAC Decoding error: {:?}", 0)]
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Unknown audio file format")]
+    UnknownFormat,
+    #[fail(display = "FLAC Decoding error: {:?}", _0)]
     FLACDecoding(claxon::Error),
+    #[fail(display = "WAV Decoding error: {}", _0)]
+    WAVDecoding(hound::Error),
+    #[fail(display = "AIFF Decoding error: {}", _0)]
+    AIFFDecoding(AiffError),
+    #[fail(display = "Ogg Vorbis Decoding error: {}", _0)]
+    OggDecoding(lewton::VorbisError),
+}
+
+impl From<io::Error> for AssetError {
+    fn from(err: io::Error) -> Self {
+        AssetError::Io(err)
+    }
 }
 
 impl From<claxon::Error> for AssetError {
@@ -15,19 +43,404 @@ impl From<claxon::Error> for AssetError {
     }
 }
 
+impl From<hound::Error> for AssetError {
+    fn from(err: hound::Error) -> Self {
+        AssetError::WAVDecoding(err)
+    }
+}
+
+impl From<AiffError> for AssetError {
+    fn from(err: AiffError) -> Self {
+        AssetError::AIFFDecoding(err)
+    }
+}
+
+impl From<lewton::VorbisError> for AssetError {
+    fn from(err: lewton::VorbisError) -> Self {
+        AssetError::OggDecoding(err)
+    }
+}
+
+/// Container format of an audio file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Flac,
+    Wav,
+    Aiff,
+    Ogg,
+}
+
+impl AudioFormat {
+    /// Guess the format from the first bytes of a file (at least 12 bytes are needed)
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(b"fLaC") {
+            Some(AudioFormat::Flac)
+        } else if magic.starts_with(b"OggS") {
+            Some(AudioFormat::Ogg)
+        } else if magic.len() < 12 {
+            None
+        } else if &magic[0..4] == b"RIFF" && &magic[8..12] == b"WAVE" {
+            Some(AudioFormat::Wav)
+        } else if &magic[0..4] == b"FORM" && (&magic[8..12] == b"AIFF" || &magic[8..12] == b"AIFC")
+        {
+            Some(AudioFormat::Aiff)
+        } else {
+            None
+        }
+    }
+}
+
+/// Interpolation used to convert an asset to another sample rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// Cheap linear interpolation, audible aliasing on high frequencies
+    Linear,
+    /// Windowed sinc interpolation, band limited to the lower of the two sample rates
+    Sinc,
+}
+
+impl Default for ResampleQuality {
+    fn default() -> Self {
+        ResampleQuality::Sinc
+    }
+}
+
+/// Zero crossings of the sinc kernel on each side of the interpolated point
+const SINC_DEPTH: usize = 16;
+
+/// A decoded audio file
 #[derive(Debug, Clone)]
 pub struct AudioAsset {
-    pub buffer: Vec<f32>,
+    /// Samples of every channel in the range [-1, 1]
+    pub buffer: Vec<Vec<f32>>,
+    /// Sample rate in Hz
+    pub sample_rate: u32,
+    /// Bit depth of the stored samples, lossy formats report the depth they are decoded to
+    pub bits_per_sample: u32,
+    /// Metadata tags as (name, value) pairs, FLAC and Ogg Vorbis comments
+    pub tags: Vec<(String, String)>,
 }
 
 impl AudioAsset {
+    /// Create an asset from per-channel samples
+    pub fn new(buffer: Vec<Vec<f32>>, sample_rate: u32) -> Self {
+        Self {
+            buffer,
+            sample_rate,
+            bits_per_sample: 32,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Load an audio file, the format is detected from the file content
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Self, AssetError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Load an audio stream, the format is detected from the stream content
+    pub fn from_reader<T: Read + Seek>(mut rd: T) -> Result<Self, AssetError> {
+        let mut magic = Vec::with_capacity(12);
+        rd.by_ref().take(12).read_to_end(&mut magic)?;
+        rd.seek(SeekFrom::Start(0))?;
+        match AudioFormat::detect(&magic).ok_or(AssetError::UnknownFormat)? {
+            AudioFormat::Flac => Self::from_flac_file(rd),
+            AudioFormat::Wav => Self::from_wav_file(rd),
+            AudioFormat::Aiff => Self::from_aiff_file(rd),
+            AudioFormat::Ogg => Self::from_ogg_file(rd),
+        }
+    }
+
     pub fn from_flac_file<T: Read>(rd: T) -> Result<Self, AssetError> {
         let mut reader = FlacReader::new(rd)?;
-        let mut buffer = Vec::new(); //TODO pre-alocate enough size to fill all samples
+        let info = reader.streaminfo();
+        let tags = reader
+            .tags()
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        let scale = int_scale(info.bits_per_sample);
+        let mut samples =
+            Vec::with_capacity(info.samples.unwrap_or(0) as usize * info.channels as usize);
         for sample in reader.samples() {
-            let sample = conv::i32::to_f32(sample.unwrap());
-            buffer.push(sample);
+            samples.push(sample? as f32 * scale);
+        }
+        Ok(AudioAsset {
+            buffer: deinterleave(&samples, info.channels as usize),
+            sample_rate: info.sample_rate,
+            bits_per_sample: info.bits_per_sample,
+            tags,
+        })
+    }
+
+    /// Load a PCM (8 to 32 bits) or float WAV stream
+    pub fn from_wav_file<T: Read>(rd: T) -> Result<Self, AssetError> {
+        let mut reader = WavReader::new(rd)?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+            SampleFormat::Int => {
+                let scale = int_scale(u32::from(spec.bits_per_sample));
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as f32 * scale))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+        Ok(AudioAsset {
+            buffer: deinterleave(&samples, spec.channels as usize),
+            sample_rate: spec.sample_rate,
+            bits_per_sample: u32::from(spec.bits_per_sample),
+            tags: Vec::new(),
+        })
+    }
+
+    /// Load an AIFF or uncompressed AIFF-C stream
+    pub fn from_aiff_file<T: Read>(rd: T) -> Result<Self, AssetError> {
+        let (spec, samples) = aiff::decode(rd)?;
+        Ok(AudioAsset {
+            buffer: deinterleave(&samples, spec.channels as usize),
+            sample_rate: spec.sample_rate.round() as u32,
+            bits_per_sample: u32::from(spec.bits_per_sample),
+            tags: Vec::new(),
+        })
+    }
+
+    /// Load an Ogg Vorbis stream
+    pub fn from_ogg_file<T: Read + Seek>(rd: T) -> Result<Self, AssetError> {
+        let mut reader = OggStreamReader::new(rd)?;
+        let mut samples = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_itl()? {
+            samples.extend(packet.into_iter().map(conv::i16::to_f32));
+        }
+        Ok(AudioAsset {
+            buffer: deinterleave(&samples, reader.ident_hdr.audio_channels as usize),
+            sample_rate: reader.ident_hdr.audio_sample_rate,
+            bits_per_sample: 16,
+            tags: reader.comment_hdr.comment_list.clone(),
+        })
+    }
+
+    /// Convert the asset to `sample_rate`, the asset is cloned when the rates already match
+    pub fn resample(&self, sample_rate: u32, quality: ResampleQuality) -> AudioAsset {
+        if sample_rate == self.sample_rate || self.sample_rate == 0 {
+            return self.clone();
+        }
+        let buffer = self
+            .buffer
+            .iter()
+            .map(|channel| {
+                resample_channel(
+                    channel,
+                    f64::from(self.sample_rate),
+                    f64::from(sample_rate),
+                    quality,
+                )
+            })
+            .collect();
+        AudioAsset {
+            buffer,
+            sample_rate,
+            bits_per_sample: self.bits_per_sample,
+            tags: self.tags.clone(),
+        }
+    }
+
+    /// Get the number of channels
+    pub fn channels(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Get the number of samples per channel
+    pub fn frames(&self) -> usize {
+        self.buffer
+            .first()
+            .map(|channel| channel.len())
+            .unwrap_or(0)
+    }
+
+    /// Get the play time of the asset at its own sample rate
+    pub fn duration(&self) -> Duration {
+        if self.sample_rate == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(self.frames() as f64 / f64::from(self.sample_rate))
+    }
+
+    /// Get the value of the first tag named `name`, names are case insensitive
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Factor converting a signed integer sample of `bits_per_sample` bits to [-1, 1]
+fn int_scale(bits_per_sample: u32) -> f32 {
+    1.0 / (1u64 << (bits_per_sample.max(1) - 1)) as f32
+}
+
+/// Convert the samples of one channel from `source_hz` to `target_hz`
+fn resample_channel(
+    samples: &[f32],
+    source_hz: f64,
+    target_hz: f64,
+    quality: ResampleQuality,
+) -> Vec<f32> {
+    let len = (samples.len() as f64 * target_hz / source_hz).ceil() as usize;
+    match quality {
+        ResampleQuality::Linear => {
+            let mut source = signal::from_iter(samples.iter().map(|sample| [*sample]));
+            let interpolator = Linear::from_source(&mut source);
+            source
+                .from_hz_to_hz(interpolator, source_hz, target_hz)
+                .take(len)
+                .map(|frame| frame[0])
+                .collect()
+        }
+        ResampleQuality::Sinc => {
+            // Band limited to the lower Nyquist frequency of the two rates, the kernel widens
+            // when downsampling to keep `SINC_DEPTH` zero crossings on each side
+            let cutoff = (target_hz / source_hz).min(1.0);
+            let half_width = SINC_DEPTH as f64 / cutoff;
+            let last = samples.len().saturating_sub(1);
+            (0..len)
+                .map(|frame| {
+                    let position = frame as f64 * source_hz / target_hz;
+                    let first = (position - half_width).ceil().max(0.0) as usize;
+                    let end = ((position + half_width).floor() as usize).min(last);
+                    (first..=end)
+                        .map(|idx| {
+                            let distance = position - idx as f64;
+                            f64::from(samples[idx]) * sinc(distance, cutoff, half_width)
+                        })
+                        .sum::<f64>() as f32
+                })
+                .collect()
+        }
+    }
+}
+
+/// Windowed sinc kernel at `distance` source frames from the interpolated point, the frames
+/// past `half_width` have no weight
+fn sinc(distance: f64, cutoff: f64, half_width: f64) -> f64 {
+    let x = std::f64::consts::PI * distance * cutoff;
+    let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+    // Hann window
+    let window = 0.5 + 0.5 * (std::f64::consts::PI * distance / half_width).cos();
+    cutoff * sinc * window
+}
+
+/// Split interleaved samples into one buffer per channel
+fn deinterleave(samples: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    let channels = channels.max(1);
+    let mut buffer = vec![Vec::with_capacity(samples.len() / channels); channels];
+    for frame in samples.chunks(channels) {
+        for (channel, sample) in buffer.iter_mut().zip(frame.iter()) {
+            channel.push(*sample);
+        }
+    }
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(
+            AudioFormat::detect(b"fLaC\0\0\0\x22"),
+            Some(AudioFormat::Flac)
+        );
+        assert_eq!(AudioFormat::detect(b"OggS\0\x02"), Some(AudioFormat::Ogg));
+        assert_eq!(
+            AudioFormat::detect(b"RIFF\x24\0\0\0WAVEfmt "),
+            Some(AudioFormat::Wav)
+        );
+        assert_eq!(
+            AudioFormat::detect(b"FORM\0\0\0\x2eAIFC"),
+            Some(AudioFormat::Aiff)
+        );
+        assert_eq!(AudioFormat::detect(b"RIFF\x24\0\0\0AVI "), None);
+        assert_eq!(AudioFormat::detect(b"ID3"), None);
+    }
+
+    #[test]
+    fn deinterleave_channels() {
+        let buffer = deinterleave(&[0.0, 1.0, 0.5, -1.0, 0.25, -0.5], 2);
+        assert_eq!(buffer, vec![vec![0.0, 0.5, 0.25], vec![1.0, -1.0, -0.5]]);
+    }
+
+    #[test]
+    fn scale_by_bit_depth() {
+        assert_eq!(i16::min_value() as f32 * int_scale(16), -1.0);
+        assert_eq!(-8_388_608f32 * int_scale(24), -1.0);
+        assert_eq!(4_194_304f32 * int_scale(24), 0.5);
+    }
+
+    #[test]
+    fn metadata() {
+        let mut asset = AudioAsset::new(vec![vec![0.0; 24000]; 2], 48000);
+        asset.tags.push(("TITLE".to_owned(), "Stem".to_owned()));
+        assert_eq!(asset.channels(), 2);
+        assert_eq!(asset.frames(), 24000);
+        assert_eq!(asset.duration(), Duration::from_millis(500));
+        assert_eq!(asset.tag("title"), Some("Stem"));
+    }
+
+    fn sine(freq: f64, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                (2.0 * std::f64::consts::PI * freq * frame as f64 / f64::from(sample_rate)).sin()
+                    as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resample() {
+        let asset = AudioAsset::new(vec![sine(440.0, 44100, 4410)], 44100);
+        let expected = sine(440.0, 48000, 4800);
+        for quality in [ResampleQuality::Linear, ResampleQuality::Sinc].iter() {
+            let resampled = asset.resample(48000, *quality);
+            assert_eq!(resampled.sample_rate, 48000);
+            assert_eq!(resampled.frames(), 4800);
+            // Edges are skipped, the interpolators have no history there
+            let error = resampled.buffer[0][100..4700]
+                .iter()
+                .zip(expected[100..4700].iter())
+                .map(|(sample, expected)| (sample - expected).abs())
+                .fold(0f32, f32::max);
+            assert!(error < 0.01, "{:?} error {}", quality, error);
+        }
+    }
+
+    #[test]
+    fn resample_down() {
+        // The 30 kHz partial is above the Nyquist frequency of 48 kHz, it must not alias
+        let partial = sine(30000.0, 96000, 9600);
+        let samples = sine(1000.0, 96000, 9600)
+            .iter()
+            .zip(partial.iter())
+            .map(|(low, high)| low + 0.5 * high)
+            .collect();
+        let asset = AudioAsset::new(vec![samples], 96000);
+        let expected = sine(1000.0, 48000, 4800);
+        let resampled = asset.resample(48000, ResampleQuality::Sinc);
+        assert_eq!(resampled.frames(), 4800);
+        let error = resampled.buffer[0][100..4700]
+            .iter()
+            .zip(expected[100..4700].iter())
+            .map(|(sample, expected)| (sample - expected).abs())
+            .fold(0f32, f32::max);
+        assert!(error < 0.01, "error {}", error);
+    }
+
+    #[test]
+    fn unknown_format() {
+        match AudioAsset::from_reader(io::Cursor::new(b"not an audio file".to_vec())) {
+            Err(AssetError::UnknownFormat) => {}
+            other => panic!("Unexpected result {:?}", other),
         }
-        Ok(AudioAsset { buffer })
     }
 }
@@ -0,0 +1,1463 @@
+//! Out-of-process plugins: the plugin runs in a bridge process (`naama-bridge`) so a crash
+//! can't take the engine down. Samples and MIDI events are exchanged through a shared memory
+//! file and commands through the standard I/O of the process, one JSON message per line. The
+//! bridge keeps its standard output for the answers, what the plugin prints goes to the
+//! standard error.
+//!
+//! The bridge also probes plugins for the scanner (`naama-bridge --probe <plugin>`): the
+//! plugin is loaded, its informations are sent back and the process exits.
+use crate::{
+    loader::{
+        parameters::ParameterInfo,
+        vst::{process_sliced, ParameterChange, PluginEvents},
+    },
+    prelude::*,
+    supervisor::{automation::PARAMETER_CHANGES_CAPACITY, midi::MIDI_BUFFER_CAPACITY},
+};
+use memmap::MmapMut;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::UnsafeCell,
+    env,
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    hint,
+    io::{self, BufRead, BufReader, Write},
+    mem,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    slice,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use vst::{
+    api::PluginFlags,
+    buffer::{Outputs, SendEventBuffer},
+    host::{HostBuffer, PluginInstance, PluginLoader},
+    plugin::{Category, Info, Plugin},
+};
+
+/// Size reserved for the `SharedHeader` at the start of the shared memory
+const HEADER_SIZE: usize = 256;
+/// Busy waiting iterations before the waiting thread starts yielding
+const SPIN_LIMIT: u32 = 1000;
+/// Time between two checks of the watchdog of a bridge
+const WATCHDOG_PERIOD: Duration = Duration::from_millis(50);
+/// Parameter changes of the plugin forwarded after each block, the others wait for the next one
+const SHARED_AUTOMATION: usize = 256;
+/// Time given to the bridge to process a block, to answer a request or a probe, or to exit
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time given to the bridge to load the plugin and to initialize it
+const LOAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Fraction of a block the audio thread waits for the pending block, the bridge had the
+/// previous block to process it
+const LATE_MARGIN: u32 = 4;
+
+#[derive(Debug, Fail)]
+pub enum BridgeError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Invalid bridge message: {}", _0)]
+    Protocol(serde_json::Error),
+    #[fail(display = "Plugin error: {}", _0)]
+    Plugin(String),
+    #[fail(display = "The plugin process crashed")]
+    Crashed,
+    #[fail(display = "The plugin process stopped responding")]
+    Timeout,
+}
+
+impl From<io::Error> for BridgeError {
+    fn from(err: io::Error) -> Self {
+        BridgeError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for BridgeError {
+    fn from(err: serde_json::Error) -> Self {
+        BridgeError::Protocol(err)
+    }
+}
+
+/// Path of the bridge executable installed next to the current one
+pub fn default_executable() -> io::Result<PathBuf> {
+    Ok(env::current_exe()?.with_file_name(format!("naama-bridge{}", env::consts::EXE_SUFFIX)))
+}
+
+/// Plugin `Info` as sent by the bridge
+#[derive(Debug, Serialize, Deserialize)]
+struct InfoMessage {
+    name: String,
+    vendor: String,
+    presets: i32,
+    parameters: i32,
+    inputs: i32,
+    outputs: i32,
+    midi_inputs: i32,
+    midi_outputs: i32,
+    unique_id: i32,
+    version: i32,
+    category: i32,
+    initial_delay: i32,
+    preset_chunks: bool,
+    f64_precision: bool,
+    silent_when_stopped: bool,
+}
+
+impl From<Info> for InfoMessage {
+    fn from(info: Info) -> Self {
+        Self {
+            name: info.name,
+            vendor: info.vendor,
+            presets: info.presets,
+            parameters: info.parameters,
+            inputs: info.inputs,
+            outputs: info.outputs,
+            midi_inputs: info.midi_inputs,
+            midi_outputs: info.midi_outputs,
+            unique_id: info.unique_id,
+            version: info.version,
+            category: info.category.into(),
+            initial_delay: info.initial_delay,
+            preset_chunks: info.preset_chunks,
+            f64_precision: info.f64_precision,
+            silent_when_stopped: info.silent_when_stopped,
+        }
+    }
+}
+
+impl From<InfoMessage> for Info {
+    fn from(info: InfoMessage) -> Self {
+        // `Category` is converted by transmutation, only known values are accepted
+        let category = match info.category {
+            category @ 0..=11 => Category::from(category),
+            _ => Category::Unknown,
+        };
+        Self {
+            name: info.name,
+            vendor: info.vendor,
+            presets: info.presets,
+            parameters: info.parameters,
+            inputs: info.inputs,
+            outputs: info.outputs,
+            midi_inputs: info.midi_inputs,
+            midi_outputs: info.midi_outputs,
+            unique_id: info.unique_id,
+            version: info.version,
+            category,
+            initial_delay: info.initial_delay,
+            preset_chunks: info.preset_chunks,
+            f64_precision: info.f64_precision,
+            silent_when_stopped: info.silent_when_stopped,
+        }
+    }
+}
+
+/// Commands sent to the bridge
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    /// Map the shared memory and start processing
+    Init {
+        shared: PathBuf,
+        sample_rate: f32,
+        block_size: i64,
+    },
+    SetParameter {
+        index: i32,
+        value: f32,
+    },
+    GetParameter {
+        index: i32,
+    },
+    GetParameterInfo {
+        index: i32,
+    },
+    SetParameterText {
+        index: i32,
+        text: String,
+    },
+    GetProgram,
+    SetProgram {
+        program: i32,
+    },
+    GetProgramName {
+        program: i32,
+    },
+    /// Rename the current program
+    SetProgramName {
+        name: String,
+    },
+    /// Get the state chunk of the current program, or of the whole bank
+    GetData {
+        bank: bool,
+    },
+    SetData {
+        bank: bool,
+        data: Vec<u8>,
+    },
+    /// Read the initial delay again, after the plugin called `IOChanged`
+    GetInitialDelay,
+    Suspend,
+    Shutdown,
+}
+
+/// Answers of the bridge
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    /// Sent once the plugin is loaded, before any request
+    Loaded(InfoMessage),
+    /// Only answer of a probe, the bits of the plugin `PluginFlags` come with its `Info`
+    Probed {
+        info: InfoMessage,
+        flags: i32,
+    },
+    Ready {
+        tail_size: isize,
+    },
+    Done,
+    Value(f32),
+    Parameter(ParameterInfo),
+    /// Answer of `SetParameterText`, false if the plugin didn't understand the text
+    Accepted(bool),
+    Program(i32),
+    Name(String),
+    Data(Vec<u8>),
+    Delay(i32),
+    Error(String),
+}
+
+fn send<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<(), BridgeError> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Take the standard output of the bridge for the answers, the standard output of the process
+/// is redirected to the standard error so the plugin can't write into the channel
+#[cfg(unix)]
+fn control_output() -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+
+    unsafe {
+        let control = libc::dup(libc::STDOUT_FILENO);
+        if control < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File::from_raw_fd(control))
+    }
+}
+
+#[cfg(windows)]
+fn control_output() -> io::Result<File> {
+    use std::os::windows::io::FromRawHandle;
+
+    unsafe {
+        let control = libc::dup(1);
+        if control < 0 || libc::dup2(2, 1) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File::from_raw_handle(libc::get_osfhandle(control) as _))
+    }
+}
+
+/// Read the next message, `None` once the other side closed the channel
+fn receive<R: BufRead, T: for<'de> Deserialize<'de>>(
+    reader: &mut R,
+) -> Result<Option<T>, BridgeError> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+/// `SharedHeader::notifications` bit set when the plugin called `IOChanged`
+const NOTIFY_IO_CHANGED: u32 = 1;
+/// `SharedHeader::notifications` bit set when the plugin called `UpdateDisplay`
+const NOTIFY_DISPLAY_CHANGED: u32 = 2;
+
+/// Synchronisation of the audio blocks, the engine bumps `request` once the inputs are written
+/// and the bridge copies it into `done` once the outputs are
+#[repr(C)]
+struct SharedHeader {
+    request: AtomicU32,
+    done: AtomicU32,
+    frames: AtomicU32,
+    /// Number of MIDI events sent to the plugin for the requested block
+    input_events: AtomicU32,
+    /// Number of MIDI events sent by the plugin during the block
+    output_events: AtomicU32,
+    /// Number of parameter changes made by the plugin since the previous block
+    automation: AtomicU32,
+    /// Number of automation changes applied during the requested block
+    parameter_changes: AtomicU32,
+    /// `NOTIFY_*` bits of the host callbacks made by the plugin since the previous block
+    notifications: AtomicU32,
+    /// Transport of the engine for the requested block, only written before `request` is bumped
+    transport: UnsafeCell<Transport>,
+}
+
+/// `MidiEvent` as stored in the shared memory, unknown note length and offset are 0
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SharedMidiEvent {
+    delta_frames: i32,
+    note_length: i32,
+    note_offset: i32,
+    data: [u8; 3],
+    live: bool,
+    detune: i8,
+    note_off_velocity: u8,
+}
+
+impl From<&MidiEvent> for SharedMidiEvent {
+    fn from(event: &MidiEvent) -> Self {
+        Self {
+            delta_frames: event.delta_frames,
+            note_length: event.note_length.unwrap_or(0),
+            note_offset: event.note_offset.unwrap_or(0),
+            data: event.data,
+            live: event.live,
+            detune: event.detune,
+            note_off_velocity: event.note_off_velocity,
+        }
+    }
+}
+
+impl From<SharedMidiEvent> for MidiEvent {
+    fn from(event: SharedMidiEvent) -> Self {
+        Self {
+            data: event.data,
+            delta_frames: event.delta_frames,
+            live: event.live,
+            note_length: Some(event.note_length).filter(|length| *length > 0),
+            note_offset: Some(event.note_offset).filter(|offset| *offset > 0),
+            detune: event.detune,
+            note_off_velocity: event.note_off_velocity,
+        }
+    }
+}
+
+/// Size reserved for the MIDI events of each direction
+const EVENTS_SIZE: usize = MIDI_BUFFER_CAPACITY * mem::size_of::<SharedMidiEvent>();
+const INPUT_EVENTS_OFFSET: usize = HEADER_SIZE;
+const OUTPUT_EVENTS_OFFSET: usize = INPUT_EVENTS_OFFSET + EVENTS_SIZE;
+const AUTOMATION_OFFSET: usize = OUTPUT_EVENTS_OFFSET + EVENTS_SIZE;
+const PARAMETER_CHANGES_OFFSET: usize =
+    AUTOMATION_OFFSET + SHARED_AUTOMATION * mem::size_of::<ParameterChange>();
+const CHANNELS_OFFSET: usize =
+    PARAMETER_CHANGES_OFFSET + PARAMETER_CHANGES_CAPACITY * mem::size_of::<ParameterChange>();
+
+/// Shared memory file holding the header, the MIDI events sent to and by the plugin, its
+/// parameter changes, the automation sent to it, the input then the output channels
+struct SharedBuffer {
+    map: MmapMut,
+    inputs: usize,
+    outputs: usize,
+    block_size: usize,
+}
+
+impl SharedBuffer {
+    fn size(inputs: usize, outputs: usize, block_size: usize) -> usize {
+        CHANNELS_OFFSET + (inputs + outputs) * block_size * mem::size_of::<f32>()
+    }
+
+    /// Create the file, or map the one created by the engine
+    fn map(
+        path: &Path,
+        create: bool,
+        inputs: usize,
+        outputs: usize,
+        block_size: usize,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(create)
+            .open(path)?;
+        assert!(mem::size_of::<SharedHeader>() <= HEADER_SIZE);
+        let size = Self::size(inputs, outputs, block_size);
+        if create {
+            file.set_len(size as u64)?;
+        } else if file.metadata()?.len() < size as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Shared memory too small",
+            ));
+        }
+        Ok(Self {
+            map: unsafe { MmapMut::map_mut(&file)? },
+            inputs,
+            outputs,
+            block_size,
+        })
+    }
+
+    fn header(&self) -> &SharedHeader {
+        unsafe { &*(self.map.as_ptr() as *const SharedHeader) }
+    }
+
+    /// Get `len` values of type `T` starting at `offset`
+    fn region<T>(&mut self, offset: usize, len: usize) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.map.as_mut_ptr().add(offset) as *mut T, len) }
+    }
+
+    /// Get the slots of the MIDI events sent to the plugin, `SharedHeader::input_events` tells
+    /// how many are used
+    fn input_events(&mut self) -> &mut [SharedMidiEvent] {
+        self.region(INPUT_EVENTS_OFFSET, MIDI_BUFFER_CAPACITY)
+    }
+
+    /// Get the slots of the MIDI events sent by the plugin, see `SharedHeader::output_events`
+    fn output_events(&mut self) -> &mut [SharedMidiEvent] {
+        self.region(OUTPUT_EVENTS_OFFSET, MIDI_BUFFER_CAPACITY)
+    }
+
+    /// Get the slots of the parameter changes, see `SharedHeader::automation`
+    fn automation(&mut self) -> &mut [ParameterChange] {
+        self.region(AUTOMATION_OFFSET, SHARED_AUTOMATION)
+    }
+
+    /// Get the slots of the automation changes of the block, positioned in the block, see
+    /// `SharedHeader::parameter_changes`
+    fn parameter_changes(&mut self) -> &mut [ParameterChange] {
+        self.region(PARAMETER_CHANGES_OFFSET, PARAMETER_CHANGES_CAPACITY)
+    }
+
+    /// Get a channel, inputs come first
+    fn channel(&mut self, channel: usize) -> &mut [f32] {
+        let offset = CHANNELS_OFFSET + channel * self.block_size * mem::size_of::<f32>();
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.map.as_mut_ptr().add(offset) as *mut f32,
+                self.block_size,
+            )
+        }
+    }
+
+    fn input(&mut self, channel: usize) -> &mut [f32] {
+        self.channel(channel)
+    }
+
+    fn output(&mut self, channel: usize) -> &mut [f32] {
+        let inputs = self.inputs;
+        self.channel(inputs + channel)
+    }
+}
+
+/// Wait for `counter` to reach `value`, `check` is called regularly and stops the wait when it
+/// returns an error
+fn wait_for<F: FnMut() -> Result<(), BridgeError>>(
+    counter: &AtomicU32,
+    value: u32,
+    mut check: F,
+) -> Result<(), BridgeError> {
+    let mut spins = 0u32;
+    while counter.load(Ordering::Acquire) != value {
+        spins = spins.wrapping_add(1);
+        if spins < SPIN_LIMIT {
+            hint::spin_loop();
+        } else {
+            thread::yield_now();
+        }
+        if spins % SPIN_LIMIT == 0 {
+            check()?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the answers of a bridge until it closes its standard output
+fn read_answers(
+    mut stdout: BufReader<ChildStdout>,
+    sender: mpsc::Sender<Result<Option<Response>, BridgeError>>,
+) {
+    loop {
+        let answer = receive(&mut stdout);
+        let closed = matches!(answer, Ok(None) | Err(BridgeError::Io(_)));
+        if sender.send(answer).is_err() || closed {
+            return;
+        }
+    }
+}
+
+/// Standard I/O of a bridge process, the control requests are sent through it
+///
+/// It is shared with the `VstParameters` of the plugin so the parameters can be used from
+/// any thread, while the audio thread only uses the shared memory. The answers are read on
+/// their own thread: a bridge that doesn't answer within the timeout is killed and marked
+/// as hung.
+pub struct BridgeChannel {
+    stdin: ChildStdin,
+    answers: mpsc::Receiver<Result<Option<Response>, BridgeError>>,
+    child: Arc<Mutex<Child>>,
+    health: Arc<BridgeHealth>,
+    timeout: Duration,
+}
+
+impl BridgeChannel {
+    /// Take the standard I/O of the bridge and start reading its answers
+    fn new(
+        child: Arc<Mutex<Child>>,
+        health: Arc<BridgeHealth>,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let (stdin, stdout) = {
+            let mut child = child.lock().unwrap();
+            let stdin = child.stdin.take().expect("Piped stdin");
+            (stdin, child.stdout.take().expect("Piped stdout"))
+        };
+        let (sender, answers) = mpsc::channel();
+        let stdout = BufReader::new(stdout);
+        thread::Builder::new()
+            .name("bridge-answers".to_string())
+            .spawn(move || read_answers(stdout, sender))?;
+        Ok(Self {
+            stdin,
+            answers,
+            child,
+            health,
+            timeout,
+        })
+    }
+
+    /// Wait for the next answer of the bridge
+    fn answer(&mut self, timeout: Duration) -> Result<Response, BridgeError> {
+        if let Some(err) = self.health.error() {
+            return Err(err);
+        }
+        match self.answers.recv_timeout(timeout) {
+            Ok(Ok(Some(Response::Error(err)))) => Err(BridgeError::Plugin(err)),
+            Ok(Ok(Some(response))) => Ok(response),
+            Ok(Ok(None)) | Ok(Err(BridgeError::Io(_))) => Err(BridgeError::Crashed),
+            Ok(Err(err)) => Err(err),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(BridgeError::Crashed),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.health.fail(FAILURE_TIMEOUT);
+                let _ = self.child.lock().unwrap().kill();
+                Err(BridgeError::Timeout)
+            }
+        }
+    }
+
+    fn call_within(
+        &mut self,
+        request: &Request,
+        timeout: Duration,
+    ) -> Result<Response, BridgeError> {
+        if let Some(err) = self.health.error() {
+            return Err(err);
+        }
+        if send(&mut self.stdin, request).is_err() {
+            return Err(BridgeError::Crashed);
+        }
+        self.answer(timeout)
+    }
+
+    fn call(&mut self, request: &Request) -> Result<Response, BridgeError> {
+        self.call_within(request, self.timeout)
+    }
+
+    pub fn set_parameter(&mut self, index: i32, value: f32) -> Result<(), BridgeError> {
+        self.call(&Request::SetParameter { index, value })?;
+        Ok(())
+    }
+
+    pub fn get_parameter(&mut self, index: i32) -> Result<f32, BridgeError> {
+        match self.call(&Request::GetParameter { index })? {
+            Response::Value(value) => Ok(value),
+            _ => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        }
+    }
+
+    pub fn parameter_info(&mut self, index: i32) -> Result<ParameterInfo, BridgeError> {
+        match self.call(&Request::GetParameterInfo { index })? {
+            Response::Parameter(info) => Ok(info),
+            _ => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        }
+    }
+
+    /// Set a parameter from its text, returns false if the plugin didn't understand it
+    pub fn set_parameter_text(&mut self, index: i32, text: &str) -> Result<bool, BridgeError> {
+        let text = text.to_string();
+        match self.call(&Request::SetParameterText { index, text })? {
+            Response::Accepted(accepted) => Ok(accepted),
+            _ => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        }
+    }
+
+    pub fn program(&mut self) -> Result<i32, BridgeError> {
+        match self.call(&Request::GetProgram)? {
+            Response::Program(program) => Ok(program),
+            _ => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        }
+    }
+
+    pub fn set_program(&mut self, program: i32) -> Result<(), BridgeError> {
+        self.call(&Request::SetProgram { program })?;
+        Ok(())
+    }
+
+    pub fn program_name(&mut self, program: i32) -> Result<String, BridgeError> {
+        match self.call(&Request::GetProgramName { program })? {
+            Response::Name(name) => Ok(name),
+            _ => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        }
+    }
+
+    pub fn set_program_name(&mut self, name: &str) -> Result<(), BridgeError> {
+        let name = name.to_string();
+        self.call(&Request::SetProgramName { name })?;
+        Ok(())
+    }
+
+    /// Get the state chunk of the current program, or of the bank
+    pub fn data(&mut self, bank: bool) -> Result<Vec<u8>, BridgeError> {
+        match self.call(&Request::GetData { bank })? {
+            Response::Data(data) => Ok(data),
+            _ => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        }
+    }
+
+    pub fn set_data(&mut self, bank: bool, data: &[u8]) -> Result<(), BridgeError> {
+        let data = data.to_vec();
+        self.call(&Request::SetData { bank, data })?;
+        Ok(())
+    }
+}
+
+fn silence(outputs: &mut Outputs<f32>) {
+    for channel in 0..outputs.len() {
+        outputs
+            .get_mut(channel)
+            .iter_mut()
+            .for_each(|sample| *sample = 0.0);
+    }
+}
+
+/// `BridgeHealth::failure` of a bridge still running
+const FAILURE_NONE: u32 = 0;
+/// `BridgeHealth::failure` of a bridge whose process exited
+const FAILURE_CRASHED: u32 = 1;
+/// `BridgeHealth::failure` of a bridge that left a block pending for too long
+const FAILURE_TIMEOUT: u32 = 2;
+
+/// State of a bridge shared with its watchdog and its control channel
+#[derive(Default)]
+struct BridgeHealth {
+    /// `FAILURE_*` value of the first failure found
+    failure: AtomicU32,
+    stop: AtomicBool,
+}
+
+impl BridgeHealth {
+    /// Record a failure, the first one is kept
+    fn fail(&self, failure: u32) {
+        let _ = self.failure.compare_exchange(
+            FAILURE_NONE,
+            failure,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    /// Get the error of the recorded failure
+    fn error(&self) -> Option<BridgeError> {
+        match self.failure.load(Ordering::Acquire) {
+            FAILURE_CRASHED => Some(BridgeError::Crashed),
+            FAILURE_TIMEOUT => Some(BridgeError::Timeout),
+            _ => None,
+        }
+    }
+}
+
+/// Check a bridge from outside of the audio thread until it fails or `health.stop` is set
+///
+/// # Parameters
+///
+/// * `shared` Mapping of the shared memory of the bridge, the requested and done blocks are
+///   read from its header
+/// * `timeout` Time a block can stay pending before the bridge is considered hung
+fn watchdog(
+    child: Arc<Mutex<Child>>,
+    shared: SharedBuffer,
+    health: Arc<BridgeHealth>,
+    timeout: Duration,
+) {
+    let mut pending: Option<(u32, Instant)> = None;
+    while !health.stop.load(Ordering::Acquire) {
+        let exited = match child.lock().unwrap().try_wait() {
+            Ok(None) => false,
+            Ok(Some(_)) | Err(_) => true,
+        };
+        let request = shared.header().request.load(Ordering::Acquire);
+        pending = if shared.header().done.load(Ordering::Acquire) == request {
+            None
+        } else {
+            match pending {
+                Some((block, since)) if block == request => Some((block, since)),
+                _ => Some((request, Instant::now())),
+            }
+        };
+        let failure = match pending {
+            _ if exited => FAILURE_CRASHED,
+            Some((_, since)) if since.elapsed() > timeout => FAILURE_TIMEOUT,
+            _ => FAILURE_NONE,
+        };
+        if failure != FAILURE_NONE {
+            health.fail(failure);
+            return;
+        }
+        thread::sleep(WATCHDOG_PERIOD);
+    }
+}
+
+/// A plugin running in a bridge process
+///
+/// Blocks are processed without allocation and pipelined: the bridge processes a block while
+/// the engine computes the next one, its outputs are returned one block later and the extra
+/// block is reported in the initial delay. The audio thread waits for the pending block at
+/// most a fraction of a block: a late block is output as silence. Control calls (parameters,
+/// suspend) wait for the bridge answer at most for the timeout, the bridge is killed past
+/// it. A watchdog thread reports a bridge that exits, or leaves a block pending for longer
+/// than the timeout, as crashed.
+pub struct BridgedInstance {
+    child: Arc<Mutex<Child>>,
+    health: Arc<BridgeHealth>,
+    watchdog: Option<thread::JoinHandle<()>>,
+    /// Host of the engine, its transport is forwarded to the bridge
+    host: Arc<Mutex<VstHost>>,
+    channel: Arc<Mutex<BridgeChannel>>,
+    shared: SharedBuffer,
+    shared_path: PathBuf,
+    info: Info,
+    tail_size: isize,
+    sample_rate: f32,
+    /// Is the host rendering offline, see `VstHost::offline`
+    offline: bool,
+    /// Last block requested
+    request: u32,
+    /// Frames of the last block requested, its outputs are returned by the next `process`
+    frames: usize,
+    /// Did the bridge miss the last block, its outputs are dropped once it is done
+    late: bool,
+    /// MIDI events of the next block, written once the bridge is done with the pending one
+    events: Vec<SharedMidiEvent>,
+    /// Automation changes of the next block, written with the events
+    changes: Vec<ParameterChange>,
+    timeout: Duration,
+}
+
+impl BridgedInstance {
+    /// Start a bridge process and load the plugin in it
+    ///
+    /// # Parameters
+    ///
+    /// * `bridge` Path of the bridge executable, see `default_executable`
+    /// * `plugin` Path of the plugin library
+    /// * `host` Host whose transport is reported to the plugin
+    pub fn spawn<P: AsRef<OsStr>, Q: AsRef<Path>>(
+        bridge: P,
+        plugin: Q,
+        host: Arc<Mutex<VstHost>>,
+        sample_rate: f32,
+        block_size: i64,
+    ) -> Result<Self, BridgeError> {
+        let child = Command::new(bridge)
+            .arg(plugin.as_ref())
+            .arg(sample_rate.to_string())
+            .arg(block_size.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let child = Arc::new(Mutex::new(child));
+        let health = Arc::new(BridgeHealth::default());
+        let mut channel = BridgeChannel::new(child.clone(), health.clone(), DEFAULT_TIMEOUT)?;
+        let mut info = match channel.answer(LOAD_TIMEOUT) {
+            Ok(Response::Loaded(info)) => Info::from(info),
+            other => {
+                let mut child = child.lock().unwrap();
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(other
+                    .err()
+                    .unwrap_or_else(|| BridgeError::Plugin("Unexpected answer".to_string())));
+            }
+        };
+        let shared_path = env::temp_dir().join(format!(
+            "naama-bridge-{}-{}.shm",
+            std::process::id(),
+            crate::supervisor::linker::new_id()
+        ));
+        let shared = SharedBuffer::map(
+            &shared_path,
+            true,
+            info.inputs.max(0) as usize,
+            info.outputs.max(0) as usize,
+            block_size as usize,
+        )?;
+        unsafe { *shared.header().transport.get() = host.lock().unwrap().transport };
+        info.initial_delay += block_size as i32;
+        let mut instance = Self {
+            child,
+            health,
+            watchdog: None,
+            host,
+            channel: Arc::new(Mutex::new(channel)),
+            shared,
+            shared_path: shared_path.clone(),
+            info,
+            tail_size: 0,
+            sample_rate,
+            offline: false,
+            request: 0,
+            frames: 0,
+            late: false,
+            events: Vec::with_capacity(MIDI_BUFFER_CAPACITY),
+            changes: Vec::with_capacity(PARAMETER_CHANGES_CAPACITY),
+            timeout: DEFAULT_TIMEOUT,
+        };
+        let init = Request::Init {
+            shared: shared_path.clone(),
+            sample_rate,
+            block_size,
+        };
+        instance.tail_size = match instance
+            .channel
+            .lock()
+            .unwrap()
+            .call_within(&init, LOAD_TIMEOUT)?
+        {
+            Response::Ready { tail_size } => tail_size,
+            _ => return Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        };
+        let shared = SharedBuffer::map(
+            &shared_path,
+            false,
+            instance.shared.inputs,
+            instance.shared.outputs,
+            instance.shared.block_size,
+        )?;
+        let child = instance.child.clone();
+        let health = instance.health.clone();
+        let timeout = instance.timeout;
+        instance.watchdog = Some(
+            thread::Builder::new()
+                .name("bridge-watchdog".to_string())
+                .spawn(move || watchdog(child, shared, health, timeout))?,
+        );
+        Ok(instance)
+    }
+
+    fn call(&mut self, request: &Request) -> Result<Response, BridgeError> {
+        self.channel.lock().unwrap().call(request)
+    }
+
+    /// Get the control channel of the bridge, to use the plugin parameters from other threads
+    pub fn channel(&self) -> Arc<Mutex<BridgeChannel>> {
+        self.channel.clone()
+    }
+
+    pub fn get_info(&self) -> Info {
+        self.info.clone()
+    }
+
+    pub fn get_tail_size(&self) -> isize {
+        self.tail_size
+    }
+
+    /// Read the initial delay of the plugin again, it may change when the plugin calls
+    /// `IOChanged`. The block the outputs are delayed by in the bridge is added to it
+    pub fn get_initial_delay(&mut self) -> Result<i32, BridgeError> {
+        match self.call(&Request::GetInitialDelay)? {
+            Response::Delay(delay) => {
+                let delay = delay + self.shared.block_size as i32;
+                self.info.initial_delay = delay;
+                Ok(delay)
+            }
+            _ => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        }
+    }
+
+    pub fn set_parameter(&mut self, index: i32, value: f32) -> Result<(), BridgeError> {
+        self.channel.lock().unwrap().set_parameter(index, value)
+    }
+
+    pub fn suspend(&mut self) -> Result<(), BridgeError> {
+        self.call(&Request::Suspend)?;
+        Ok(())
+    }
+
+    /// Send the events of the next block, they are delivered to the plugin by `process`. They
+    /// are dropped if the bridge is late
+    pub fn process_events(&mut self, events: &[MidiEvent]) {
+        let free = MIDI_BUFFER_CAPACITY - self.events.len();
+        self.events
+            .extend(events.iter().take(free).map(SharedMidiEvent::from));
+    }
+
+    /// Send the automation changes of the next block, `process` applies them at their
+    /// position in the block. They are dropped if the bridge is late
+    pub fn process_parameter_changes(&mut self, changes: &[ParameterChange]) {
+        let free = PARAMETER_CHANGES_CAPACITY - self.changes.len();
+        self.changes.extend(changes.iter().take(free));
+    }
+
+    /// Request a block from the bridge and output the previous one, channels missing on
+    /// either side are silent. The MIDI events and parameter changes sent by the plugin are
+    /// pushed into `events`
+    ///
+    /// The bridge is given the duration of a block to process the pending block, and a
+    /// fraction of a block more. Otherwise the block is silent and no other block is requested
+    /// until the bridge is done. Offline it is waited for. An error is only returned once the
+    /// bridge is found crashed or hung.
+    pub fn process(
+        &mut self,
+        buffer: &mut AudioBuffer<f32>,
+        events: &PluginEvents,
+    ) -> Result<(), BridgeError> {
+        if let Some(err) = self.health.error() {
+            return Err(err);
+        }
+        let frames = buffer.samples().min(self.shared.block_size);
+        let (inputs, mut outputs) = buffer.split();
+        // Keep the previous transport if the host is busy
+        let transport = match self.host.try_lock() {
+            Ok(host) => {
+                self.offline = host.offline;
+                Some(host.transport)
+            }
+            Err(_) => None,
+        };
+        let period = Duration::from_secs_f64(frames as f64 / f64::from(self.sample_rate));
+        let offline = self.offline;
+        let health = &self.health;
+        let start = Instant::now();
+        let done = wait_for(&self.shared.header().done, self.request, || {
+            if health.failure.load(Ordering::Acquire) != FAILURE_NONE
+                || (!offline && start.elapsed() > period / LATE_MARGIN)
+            {
+                Err(BridgeError::Timeout)
+            } else {
+                Ok(())
+            }
+        });
+        if done.is_err() {
+            self.late = true;
+            self.events.clear();
+            self.changes.clear();
+            silence(&mut outputs);
+            return Ok(());
+        }
+        // The samples of a late block are dropped, not what the plugin sent
+        let ready = if self.late {
+            0
+        } else {
+            self.frames.min(frames)
+        };
+        self.late = false;
+        for channel in 0..outputs.len() {
+            let output = &mut outputs.get_mut(channel)[..frames];
+            let copied = if channel < self.shared.outputs {
+                output[..ready].copy_from_slice(&self.shared.output(channel)[..ready]);
+                ready
+            } else {
+                0
+            };
+            output[copied..].iter_mut().for_each(|sample| *sample = 0.0);
+        }
+        self.collect_events(events);
+        for channel in 0..self.shared.inputs {
+            let shared = &mut self.shared.input(channel)[..frames];
+            if channel < inputs.len() {
+                shared.copy_from_slice(&inputs.get(channel)[..frames]);
+            } else {
+                shared.iter_mut().for_each(|sample| *sample = 0.0);
+            }
+        }
+        self.shared.input_events()[..self.events.len()].copy_from_slice(&self.events);
+        self.shared.parameter_changes()[..self.changes.len()].copy_from_slice(&self.changes);
+        self.request = self.request.wrapping_add(1);
+        self.frames = frames;
+        let header = self.shared.header();
+        if let Some(transport) = transport {
+            unsafe { *header.transport.get() = transport };
+        }
+        header.frames.store(frames as u32, Ordering::Relaxed);
+        header
+            .input_events
+            .store(self.events.len() as u32, Ordering::Relaxed);
+        self.events.clear();
+        header
+            .parameter_changes
+            .store(self.changes.len() as u32, Ordering::Relaxed);
+        self.changes.clear();
+        header.request.store(self.request, Ordering::Release);
+        Ok(())
+    }
+
+    /// Push the MIDI events, parameter changes and notifications sent by the plugin during the
+    /// last block into `events`
+    fn collect_events(&mut self, events: &PluginEvents) {
+        let header = self.shared.header();
+        let count =
+            (header.output_events.load(Ordering::Relaxed) as usize).min(MIDI_BUFFER_CAPACITY);
+        let changes = (header.automation.load(Ordering::Relaxed) as usize).min(SHARED_AUTOMATION);
+        let notifications = header.notifications.load(Ordering::Relaxed);
+        for event in self.shared.output_events()[..count].iter() {
+            events.midi.push(MidiEvent::from(*event));
+        }
+        for change in self.shared.automation()[..changes].iter() {
+            events.automation.push(*change);
+        }
+        if notifications & NOTIFY_IO_CHANGED != 0 {
+            events.io_changed.store(true, Ordering::Release);
+        }
+        if notifications & NOTIFY_DISPLAY_CHANGED != 0 {
+            events.display_changed.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BridgedInstance {
+    fn drop(&mut self) {
+        self.health.stop.store(true, Ordering::Release);
+        if let Some(watchdog) = self.watchdog.take() {
+            let _ = watchdog.join();
+        }
+        if let Ok(mut channel) = self.channel.lock() {
+            let _ = send(&mut channel.stdin, &Request::Shutdown);
+        }
+        let mut child = self.child.lock().unwrap();
+        let start = Instant::now();
+        while let Ok(None) = child.try_wait() {
+            if start.elapsed() > self.timeout {
+                warn!("Killing the unresponsive bridge of {}", self.info.name);
+                let _ = child.kill();
+                let _ = child.wait();
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = fs::remove_file(&self.shared_path);
+    }
+}
+
+/// Load a plugin in a bridge process and read its informations, the bridge is killed if it
+/// doesn't answer within `timeout`
+///
+/// # Parameters
+///
+/// * `bridge` Path of the bridge executable, see `default_executable`
+/// * `plugin` Path of the plugin library
+/// * `timeout` Time given to the plugin to be loaded and queried
+pub fn probe<P: AsRef<OsStr>, Q: AsRef<Path>>(
+    bridge: P,
+    plugin: Q,
+    timeout: Duration,
+) -> Result<(Info, PluginFlags), BridgeError> {
+    let mut child = Command::new(bridge)
+        .arg("--probe")
+        .arg(plugin.as_ref())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = BufReader::new(child.stdout.take().expect("Piped stdout"));
+    // The answer is read on its own thread, it ends with the process
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("bridge-probe".to_string())
+        .spawn(move || {
+            let _ = sender.send(receive(&mut stdout));
+        })?;
+    let result = match receiver.recv_timeout(timeout) {
+        Ok(Ok(Some(Response::Probed { info, flags }))) => {
+            Ok((Info::from(info), PluginFlags::from_bits_truncate(flags)))
+        }
+        Ok(Ok(Some(Response::Error(err)))) => Err(BridgeError::Plugin(err)),
+        Ok(Ok(Some(_))) => Err(BridgeError::Plugin("Unexpected answer".to_string())),
+        Ok(Err(BridgeError::Protocol(err))) => Err(BridgeError::Protocol(err)),
+        Ok(_) | Err(mpsc::RecvTimeoutError::Disconnected) => Err(BridgeError::Crashed),
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let _ = child.kill();
+            Err(BridgeError::Timeout)
+        }
+    };
+    let _ = child.wait();
+    result
+}
+
+/// Process the blocks requested by the engine until `running` is cleared
+fn process_loop(
+    plugin: Arc<Mutex<PluginInstance>>,
+    host: Arc<Mutex<VstHost>>,
+    plugin_events: Arc<PluginEvents>,
+    mut shared: SharedBuffer,
+    running: Arc<AtomicBool>,
+) {
+    let mut inputs = vec![vec![0f32; shared.block_size]; shared.inputs];
+    let mut outputs = vec![vec![0f32; shared.block_size]; shared.outputs];
+    let mut buffer = HostBuffer::new(shared.inputs, shared.outputs);
+    let mut events: Vec<MidiEvent> = Vec::with_capacity(MIDI_BUFFER_CAPACITY);
+    let mut slice_events: Vec<MidiEvent> = Vec::with_capacity(MIDI_BUFFER_CAPACITY);
+    let mut changes: Vec<ParameterChange> = Vec::with_capacity(PARAMETER_CHANGES_CAPACITY);
+    let mut send_events = SendEventBuffer::new(MIDI_BUFFER_CAPACITY);
+    let mut last = shared.header().done.load(Ordering::Acquire);
+    while running.load(Ordering::Relaxed) {
+        let request = shared.header().request.load(Ordering::Acquire);
+        if request == last {
+            thread::sleep(Duration::from_micros(50));
+            continue;
+        }
+        last = request;
+        let frames = shared.header().frames.load(Ordering::Relaxed) as usize;
+        host.lock().unwrap().transport = unsafe { *shared.header().transport.get() };
+        let count = (shared.header().input_events.load(Ordering::Relaxed) as usize)
+            .min(MIDI_BUFFER_CAPACITY);
+        events.clear();
+        events.extend(
+            shared.input_events()[..count]
+                .iter()
+                .map(|event| MidiEvent::from(*event)),
+        );
+        let count = (shared.header().parameter_changes.load(Ordering::Relaxed) as usize)
+            .min(PARAMETER_CHANGES_CAPACITY);
+        changes.clear();
+        changes.extend_from_slice(&shared.parameter_changes()[..count]);
+        for (channel, input) in inputs.iter_mut().enumerate() {
+            input.truncate(0);
+            input.extend_from_slice(&shared.input(channel)[..frames]);
+        }
+        outputs
+            .iter_mut()
+            .for_each(|output| output.resize(frames, 0.0));
+        process_sliced(
+            &mut plugin.lock().unwrap(),
+            &mut buffer,
+            &inputs,
+            &mut outputs,
+            &events,
+            &changes,
+            &mut send_events,
+            &mut slice_events,
+        );
+        for (channel, output) in outputs.iter().enumerate() {
+            shared.output(channel)[..frames].copy_from_slice(output);
+        }
+        let mut count = 0;
+        while count < MIDI_BUFFER_CAPACITY {
+            match plugin_events.midi.pop() {
+                Some(event) => shared.output_events()[count] = SharedMidiEvent::from(&event),
+                None => break,
+            }
+            count += 1;
+        }
+        shared
+            .header()
+            .output_events
+            .store(count as u32, Ordering::Relaxed);
+        let mut count = 0;
+        while count < SHARED_AUTOMATION {
+            match plugin_events.automation.pop() {
+                Some(change) => shared.automation()[count] = change,
+                None => break,
+            }
+            count += 1;
+        }
+        shared
+            .header()
+            .automation
+            .store(count as u32, Ordering::Relaxed);
+        let mut notifications = 0;
+        if plugin_events.io_changed.swap(false, Ordering::AcqRel) {
+            notifications |= NOTIFY_IO_CHANGED;
+        }
+        if plugin_events.display_changed.swap(false, Ordering::AcqRel) {
+            notifications |= NOTIFY_DISPLAY_CHANGED;
+        }
+        shared
+            .header()
+            .notifications
+            .store(notifications, Ordering::Relaxed);
+        shared.header().done.store(request, Ordering::Release);
+    }
+}
+
+/// Run the bridge side: load the plugin and serve the engine requests until it shuts the
+/// bridge down or exits. Called by the `naama-bridge` executable.
+///
+/// The sample rate and block size of the engine are given on the command line, plugins may
+/// read them from the host while they are loaded, before the `Init` request.
+pub fn serve<P: AsRef<Path>>(
+    plugin: P,
+    sample_rate: f32,
+    block_size: i64,
+) -> Result<(), BridgeError> {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let mut writer = control_output()?;
+    let host = Arc::new(Mutex::new(VstHost::new(
+        f64::from(sample_rate),
+        block_size as isize,
+    )));
+    let instance =
+        PluginLoader::load(plugin.as_ref(), host.clone()).and_then(|mut loader| loader.instance());
+    let instance = match instance {
+        Ok(instance) => instance,
+        Err(err) => return send(&mut writer, &Response::Error(format!("{}", err))),
+    };
+    send(&mut writer, &Response::Loaded(instance.get_info().into()))?;
+    let info = instance.get_info();
+    let mut instance = Some(instance);
+    let mut plugin: Option<Arc<Mutex<PluginInstance>>> = None;
+    let running = Arc::new(AtomicBool::new(true));
+    let mut audio_thread = None;
+    while let Some(request) = receive(&mut reader)? {
+        let response = match (request, plugin.as_ref()) {
+            (
+                Request::Init {
+                    shared,
+                    sample_rate,
+                    block_size,
+                },
+                None,
+            ) => {
+                let shared = SharedBuffer::map(
+                    &shared,
+                    false,
+                    info.inputs.max(0) as usize,
+                    info.outputs.max(0) as usize,
+                    block_size as usize,
+                );
+                match (shared, instance.take()) {
+                    (Ok(shared), Some(mut instance)) => {
+                        let events = Arc::new(PluginEvents::new());
+                        {
+                            let mut host = host.lock().unwrap();
+                            host.block_size = block_size as isize;
+                            host.register_instance(instance.instance_id(), events.clone());
+                        }
+                        instance.init();
+                        instance.set_sample_rate(sample_rate);
+                        instance.set_block_size(block_size);
+                        instance.resume();
+                        let tail_size = instance.get_tail_size();
+                        let instance = Arc::new(Mutex::new(instance));
+                        plugin = Some(instance.clone());
+                        let running = running.clone();
+                        let host = host.clone();
+                        audio_thread = Some(thread::spawn(move || {
+                            process_loop(instance, host, events, shared, running)
+                        }));
+                        Response::Ready { tail_size }
+                    }
+                    (Err(err), taken) => {
+                        instance = taken;
+                        Response::Error(format!("Unable to map the shared memory: {}", err))
+                    }
+                    (Ok(_), None) => unreachable!("The instance is only taken once initialized"),
+                }
+            }
+            (Request::Init { .. }, Some(_)) => Response::Error("Already initialized".to_string()),
+            (Request::SetParameter { index, value }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                parameters.set_parameter(index, value);
+                Response::Done
+            }
+            (Request::GetParameter { index }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                Response::Value(parameters.get_parameter(index))
+            }
+            (Request::GetParameterInfo { index }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                Response::Parameter(ParameterInfo::read(&*parameters, index))
+            }
+            (Request::SetParameterText { index, text }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                Response::Accepted(parameters.string_to_parameter(index, text))
+            }
+            (Request::GetProgram, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                Response::Program(parameters.get_preset_num())
+            }
+            (Request::SetProgram { program }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                parameters.change_preset(program);
+                Response::Done
+            }
+            (Request::GetProgramName { program }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                Response::Name(parameters.get_preset_name(program))
+            }
+            (Request::SetProgramName { name }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                parameters.set_preset_name(name);
+                Response::Done
+            }
+            (Request::GetData { bank }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                Response::Data(if bank {
+                    parameters.get_bank_data()
+                } else {
+                    parameters.get_preset_data()
+                })
+            }
+            (Request::SetData { bank, data }, Some(instance)) => {
+                let parameters = instance.lock().unwrap().get_parameter_object();
+                if bank {
+                    parameters.load_bank_data(&data);
+                } else {
+                    parameters.load_preset_data(&data);
+                }
+                Response::Done
+            }
+            (Request::GetInitialDelay, Some(instance)) => {
+                Response::Delay(instance.lock().unwrap().get_initial_delay())
+            }
+            (Request::Suspend, Some(instance)) => {
+                instance.lock().unwrap().suspend();
+                Response::Done
+            }
+            (Request::Shutdown, _) => break,
+            (_, None) => Response::Error("Not initialized".to_string()),
+        };
+        send(&mut writer, &response)?;
+    }
+    running.store(false, Ordering::Relaxed);
+    if let Some(audio_thread) = audio_thread {
+        let _ = audio_thread.join();
+    }
+    Ok(())
+}
+
+/// Run the probe side: load the plugin, send its informations and exit. Called by the
+/// `naama-bridge` executable.
+pub fn serve_probe<P: AsRef<Path>>(plugin: P) -> Result<(), BridgeError> {
+    let mut writer = control_output()?;
+    let host = Arc::new(Mutex::new(VstHost::new(44100.0, 0)));
+    let instance =
+        PluginLoader::load(plugin.as_ref(), host).and_then(|mut loader| loader.instance());
+    let response = match instance {
+        Ok(instance) => Response::Probed {
+            info: instance.get_info().into(),
+            flags: instance.get_flags().bits(),
+        },
+        Err(err) => Response::Error(format!("{}", err)),
+    };
+    send(&mut writer, &response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_buffer_layout() {
+        let path = env::temp_dir().join("engine-bridge-layout.shm");
+        let mut engine = SharedBuffer::map(&path, true, 2, 1, 16).unwrap();
+        let mut bridge = SharedBuffer::map(&path, false, 2, 1, 16).unwrap();
+        engine.input(1)[3] = 0.5;
+        engine.input_events()[1] = SharedMidiEvent::from(&midi_event(3, [0x90, 60, 100]));
+        bridge.output(0)[15] = -1.0;
+        bridge.header().done.store(7, Ordering::Release);
+        assert_eq!(bridge.input(1)[3], 0.5);
+        let event = MidiEvent::from(bridge.input_events()[1]);
+        assert_eq!((event.delta_frames, event.data), (3, [0x90, 60, 100]));
+        assert_eq!(event.note_length, None);
+        assert_eq!(engine.output(0)[15], -1.0);
+        assert_eq!(engine.input(0), &[0.0; 16][..]);
+        assert!(wait_for(&engine.header().done, 7, || Err(BridgeError::Timeout)).is_ok());
+        assert!(SharedBuffer::map(&path, false, 2, 2, 16).is_err());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn info_roundtrip() {
+        let info = Info {
+            name: "Gain".to_string(),
+            category: Category::Effect,
+            inputs: 2,
+            ..Info::default()
+        };
+        let message = serde_json::to_string(&InfoMessage::from(info)).unwrap();
+        let info = Info::from(serde_json::from_str::<InfoMessage>(&message).unwrap());
+        assert_eq!(info.name, "Gain");
+        assert_eq!(info.inputs, 2);
+        assert!(match info.category {
+            Category::Effect => true,
+            _ => false,
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bridge_crash() {
+        // A bridge exiting without answering is a crash
+        match BridgedInstance::spawn(
+            "false",
+            "plugin.so",
+            Arc::new(Mutex::new(VstHost::new(48000.0, 64))),
+            48000.0,
+            64,
+        ) {
+            Err(BridgeError::Crashed) => {}
+            Err(err) => panic!("Unexpected error {}", err),
+            Ok(_) => panic!("Unexpected bridge"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn probe_crash_and_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        match probe("false", "plugin.so", DEFAULT_TIMEOUT) {
+            Err(BridgeError::Crashed) => {}
+            other => panic!("Unexpected result {:?}", other.err()),
+        }
+        // A bridge hanging like a plugin stuck while loading is killed
+        let hang = env::temp_dir().join("engine-bridge-hang.sh");
+        fs::write(&hang, "#!/bin/sh\nexec sleep 10\n").unwrap();
+        fs::set_permissions(&hang, fs::Permissions::from_mode(0o755)).unwrap();
+        let start = Instant::now();
+        match probe(&hang, "plugin.so", Duration::from_millis(100)) {
+            Err(BridgeError::Timeout) => {}
+            other => panic!("Unexpected result {:?}", other.err()),
+        }
+        assert!(start.elapsed() < DEFAULT_TIMEOUT);
+        fs::remove_file(&hang).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_timeout() {
+        // A bridge that never answers a request is killed and stays hung
+        let child = Command::new("sleep")
+            .arg("10")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let child = Arc::new(Mutex::new(child));
+        let health = Arc::new(BridgeHealth::default());
+        let mut channel =
+            BridgeChannel::new(child.clone(), health.clone(), Duration::from_millis(100)).unwrap();
+        let start = Instant::now();
+        match channel.set_parameter(0, 0.5) {
+            Err(BridgeError::Timeout) => {}
+            other => panic!("Unexpected result {:?}", other.err()),
+        }
+        assert!(start.elapsed() < DEFAULT_TIMEOUT);
+        assert!(child.lock().unwrap().wait().is_ok());
+        match (health.error(), channel.set_parameter(0, 0.5)) {
+            (Some(BridgeError::Timeout), Err(BridgeError::Timeout)) => {}
+            other => panic!("Unexpected state {:?}", other),
+        }
+    }
+}
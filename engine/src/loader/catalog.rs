@@ -0,0 +1,423 @@
+//! Plugin scanner and persistent plugin catalog
+use crate::{
+    loader::bridge::{self, BridgeError},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use vst::{api::PluginFlags, plugin::Info};
+
+/// Default time given to a plugin to be loaded and queried
+const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Fail)]
+pub enum CatalogError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Invalid catalog: {}", _0)]
+    Format(serde_json::Error),
+}
+
+impl From<io::Error> for CatalogError {
+    fn from(err: io::Error) -> Self {
+        CatalogError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for CatalogError {
+    fn from(err: serde_json::Error) -> Self {
+        CatalogError::Format(err)
+    }
+}
+
+/// Informations read from a plugin library when it was scanned
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginEntry {
+    pub path: PathBuf,
+    /// Modification time of the library when it was scanned, in seconds since the epoch
+    pub modified: u64,
+    pub name: String,
+    pub vendor: String,
+    pub unique_id: i32,
+    pub version: i32,
+    /// Name of the plugin `Category`
+    pub category: String,
+    pub inputs: i32,
+    pub outputs: i32,
+    pub parameters: i32,
+    pub presets: i32,
+    pub initial_delay: i32,
+    /// Bits of the plugin `PluginFlags`
+    pub flags: i32,
+}
+
+impl PluginEntry {
+    fn new(path: PathBuf, modified: u64, info: Info, flags: PluginFlags) -> Self {
+        Self {
+            path,
+            modified,
+            name: info.name,
+            vendor: info.vendor,
+            unique_id: info.unique_id,
+            version: info.version,
+            category: format!("{:?}", info.category),
+            inputs: info.inputs,
+            outputs: info.outputs,
+            parameters: info.parameters,
+            presets: info.presets,
+            initial_delay: info.initial_delay,
+            flags: flags.bits(),
+        }
+    }
+
+    pub fn flags(&self) -> PluginFlags {
+        PluginFlags::from_bits_truncate(self.flags)
+    }
+}
+
+/// A library that failed to be scanned, it is skipped until it is modified
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlacklistEntry {
+    pub path: PathBuf,
+    /// Modification time of the library when it failed, in seconds since the epoch
+    pub modified: u64,
+    pub reason: String,
+}
+
+/// Plugins found by the scanner, keyed by the path of their library
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PluginCatalog {
+    plugins: BTreeMap<PathBuf, PluginEntry>,
+    blacklist: BTreeMap<PathBuf, BlacklistEntry>,
+    /// Library being scanned, still set on the next launch if the scan crashed the process
+    scanning: Option<PathBuf>,
+}
+
+impl PluginCatalog {
+    /// Read a catalog, a missing file gives an empty catalog
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Self, CatalogError> {
+        match fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the catalog, the previous file is only replaced once the new one is complete
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<(), CatalogError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn plugins(&self) -> impl Iterator<Item = &PluginEntry> {
+        self.plugins.values()
+    }
+
+    pub fn blacklist(&self) -> impl Iterator<Item = &BlacklistEntry> {
+        self.blacklist.values()
+    }
+
+    pub fn get<T: AsRef<Path>>(&self, path: T) -> Option<&PluginEntry> {
+        self.plugins.get(path.as_ref())
+    }
+
+    /// Find a plugin by name, case insensitive
+    pub fn find(&self, name: &str) -> Option<&PluginEntry> {
+        let name = name.to_lowercase();
+        self.plugins
+            .values()
+            .find(|entry| entry.name.to_lowercase() == name)
+    }
+
+    pub fn find_by_id(&self, unique_id: i32) -> Option<&PluginEntry> {
+        self.plugins
+            .values()
+            .find(|entry| entry.unique_id == unique_id)
+    }
+
+    pub fn is_blacklisted<T: AsRef<Path>>(&self, path: T) -> bool {
+        self.blacklist.contains_key(path.as_ref())
+    }
+
+    /// Remove a library from the blacklist so it is scanned again, return `false` if it wasn't
+    /// blacklisted
+    pub fn unblacklist<T: AsRef<Path>>(&mut self, path: T) -> bool {
+        self.blacklist.remove(path.as_ref()).is_some()
+    }
+
+    fn add_blacklist(&mut self, path: PathBuf, modified: u64, reason: String) {
+        warn!("Plugin {} blacklisted: {}", path.display(), reason);
+        self.plugins.remove(&path);
+        self.blacklist.insert(
+            path.clone(),
+            BlacklistEntry {
+                path,
+                modified,
+                reason,
+            },
+        );
+    }
+
+    /// Is the library already scanned (or blacklisted) in its current version
+    fn is_up_to_date(&self, path: &Path, modified: u64) -> bool {
+        self.plugins
+            .get(path)
+            .map(|entry| entry.modified == modified)
+            .or_else(|| {
+                self.blacklist
+                    .get(path)
+                    .map(|entry| entry.modified == modified)
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Walk plugin directories and keep the informations of every library in a catalog
+///
+/// A library is loaded once and only scanned again when its modification time changes.
+/// Each library is probed in its own bridge process (see `bridge::probe`), libraries that
+/// fail to load, crash the bridge or time out are blacklisted. If the scan crashes the whole
+/// process the library being scanned is blacklisted on the next one.
+pub struct PluginScanner {
+    catalog_path: PathBuf,
+    directories: Vec<PathBuf>,
+    bridge: PathBuf,
+    timeout: Duration,
+}
+
+impl PluginScanner {
+    /// # Parameters
+    ///
+    /// * `catalog_path` File the catalog is kept in, it is created by the first scan
+    pub fn new<T: AsRef<Path>>(catalog_path: T) -> Self {
+        Self {
+            catalog_path: catalog_path.as_ref().to_path_buf(),
+            directories: Vec::new(),
+            bridge: bridge::default_executable().unwrap_or_else(|_| PathBuf::from("naama-bridge")),
+            timeout: DEFAULT_SCAN_TIMEOUT,
+        }
+    }
+
+    /// Set the bridge executable the libraries are probed in, `bridge::default_executable`
+    /// by default
+    pub fn set_bridge<T: AsRef<Path>>(&mut self, bridge: T) {
+        self.bridge = bridge.as_ref().to_path_buf();
+    }
+
+    /// Add a directory to scan, its subdirectories are scanned too
+    pub fn add_directory<T: AsRef<Path>>(&mut self, path: T) {
+        self.directories.push(path.as_ref().to_path_buf());
+    }
+
+    /// Set the time given to a plugin to be loaded and queried, its bridge is then killed
+    /// and it is blacklisted
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Scan the new and modified libraries of every directory and update the catalog
+    ///
+    /// Libraries removed from the scanned directories are forgotten, the ones found by scans
+    /// of other directories are kept while they exist.
+    ///
+    /// The catalog is saved before each library is loaded so a crash can be detected on
+    /// the next scan. A bridge executable that can't be started is reported as an error,
+    /// nothing is blacklisted.
+    pub fn scan(&self) -> Result<PluginCatalog, CatalogError> {
+        let mut catalog = PluginCatalog::load(&self.catalog_path)?;
+        if let Some(path) = catalog.scanning.take() {
+            let modified = modified_time(&path).unwrap_or(0);
+            catalog.add_blacklist(path, modified, "Crashed while scanning".to_string());
+        }
+        let mut libraries = BTreeSet::new();
+        for directory in self.directories.iter() {
+            find_libraries(directory, &mut libraries)?;
+        }
+        let keep = |path: &PathBuf| {
+            libraries.contains(path)
+                || (path.exists() && !self.directories.iter().any(|dir| path.starts_with(dir)))
+        };
+        catalog.plugins.retain(|path, _| keep(path));
+        catalog.blacklist.retain(|path, _| keep(path));
+        for path in libraries {
+            let modified = match modified_time(&path) {
+                Ok(modified) => modified,
+                Err(err) => {
+                    warn!("Unable to read {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            if catalog.is_up_to_date(&path, modified) {
+                continue;
+            }
+            catalog.scanning = Some(path.clone());
+            catalog.save(&self.catalog_path)?;
+            let result = bridge::probe(&self.bridge, &path, self.timeout);
+            catalog.scanning = None;
+            match result {
+                Ok((info, flags)) => {
+                    let entry = PluginEntry::new(path.clone(), modified, info, flags);
+                    info!("Plugin found: {} ({})", entry.name, path.display());
+                    catalog.blacklist.remove(&path);
+                    catalog.plugins.insert(path, entry);
+                }
+                Err(BridgeError::Io(err)) => {
+                    catalog.save(&self.catalog_path)?;
+                    return Err(err.into());
+                }
+                Err(err) => catalog.add_blacklist(path, modified, format!("{}", err)),
+            }
+        }
+        catalog.save(&self.catalog_path)?;
+        Ok(catalog)
+    }
+}
+
+fn modified_time(path: &Path) -> io::Result<u64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0))
+}
+
+/// Collect the plugin libraries of a directory and its subdirectories
+fn find_libraries(directory: &Path, libraries: &mut BTreeSet<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+        match extension.as_ref().map(String::as_str) {
+            // OS X bundle, the library is the mach-o file of the bundle
+            Some("vst") if path.is_dir() => {
+                if let Some(binary) = fs::read_dir(path.join("Contents").join("MacOS"))
+                    .ok()
+                    .and_then(|mut entries| entries.next())
+                    .and_then(Result::ok)
+                {
+                    libraries.insert(binary.path());
+                }
+            }
+            _ if path.is_dir() => find_libraries(&path, libraries)?,
+            Some("dll") | Some("so") | Some("dylib") => {
+                libraries.insert(path);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("plugins").join("nested")).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn blacklist_invalid_libraries() {
+        let dir = scratch_dir("engine-catalog-blacklist");
+        let library = dir.join("plugins").join("nested").join("broken.so");
+        fs::write(&library, b"not a library").unwrap();
+        fs::write(dir.join("plugins").join("readme.txt"), b"").unwrap();
+        let catalog_path = dir.join("catalog.json");
+        let mut scanner = PluginScanner::new(&catalog_path);
+        scanner.add_directory(dir.join("plugins"));
+        // Without a bridge nothing can be scanned, nor blacklisted
+        scanner.set_bridge(dir.join("missing-bridge"));
+        assert!(scanner.scan().is_err());
+        let saved = PluginCatalog::load(&catalog_path).unwrap();
+        assert_eq!(saved.blacklist().count(), 0);
+        assert!(saved.scanning.is_none());
+        // A bridge exiting without answering, like one crashed by the plugin
+        scanner.set_bridge("false");
+
+        let catalog = scanner.scan().unwrap();
+        assert_eq!(catalog.plugins().count(), 0);
+        assert_eq!(catalog.blacklist().count(), 1);
+        assert!(catalog.is_blacklisted(&library));
+
+        // Saved on disk and not scanned again while the library is unchanged
+        let mut saved = PluginCatalog::load(&catalog_path).unwrap();
+        assert_eq!(saved.blacklist().next(), catalog.blacklist().next());
+        saved.blacklist.get_mut(&library).unwrap().reason = "Kept".to_string();
+        saved.save(&catalog_path).unwrap();
+        let catalog = scanner.scan().unwrap();
+        assert_eq!(catalog.blacklist().next().unwrap().reason, "Kept");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn blacklist_after_crash() {
+        let dir = scratch_dir("engine-catalog-crash");
+        let library = dir.join("plugins").join("crash.dll");
+        fs::write(&library, b"").unwrap();
+        let catalog_path = dir.join("catalog.json");
+        let catalog = PluginCatalog {
+            scanning: Some(library.clone()),
+            ..PluginCatalog::default()
+        };
+        catalog.save(&catalog_path).unwrap();
+        let mut scanner = PluginScanner::new(&catalog_path);
+        scanner.add_directory(dir.join("plugins"));
+
+        scanner.set_bridge("false");
+
+        let catalog = scanner.scan().unwrap();
+        let entry = catalog.blacklist().next().unwrap();
+        assert_eq!(entry.path, library);
+        assert_eq!(entry.reason, "Crashed while scanning");
+        assert!(PluginCatalog::load(&catalog_path)
+            .unwrap()
+            .scanning
+            .is_none());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_other_directories() {
+        let dir = scratch_dir("engine-catalog-directories");
+        let library = dir.join("plugins").join("nested").join("broken.so");
+        fs::write(&library, b"").unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        let catalog_path = dir.join("catalog.json");
+        let mut scanner = PluginScanner::new(&catalog_path);
+        scanner.add_directory(dir.join("plugins"));
+        scanner.set_bridge("false");
+        assert!(scanner.scan().unwrap().is_blacklisted(&library));
+
+        let mut other = PluginScanner::new(&catalog_path);
+        other.add_directory(dir.join("other"));
+        other.set_bridge("false");
+        assert!(other.scan().unwrap().is_blacklisted(&library));
+        // Removed libraries are forgotten whatever the scanned directories
+        fs::remove_file(&library).unwrap();
+        assert_eq!(other.scan().unwrap().blacklist().count(), 0);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn missing_catalog_is_empty() {
+        let catalog = PluginCatalog::load("/nonexistent/catalog.json").unwrap();
+        assert_eq!(catalog.plugins().count(), 0);
+        assert!(catalog.find("gain").is_none());
+    }
+}
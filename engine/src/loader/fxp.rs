@@ -0,0 +1,634 @@
+//! FXP preset and FXB bank files, the standard containers for VST 2 plugin states
+//!
+//! Both exist in two forms: a list of normalized parameter values, or an opaque chunk for the
+//! plugins with `Info::preset_chunks`. Every field is big endian.
+use crate::loader::parameters::{ParameterError, VstParameters};
+use std::{fs, io, path::Path};
+use vst::plugin::Info;
+
+/// Length of a program name, including the terminating NUL
+const NAME_LENGTH: usize = 28;
+/// Reserved bytes after the bank header, the current program is stored in the first 4 bytes
+/// since version 2
+const BANK_RESERVED: usize = 128;
+
+#[derive(Debug, Fail)]
+pub enum FxError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Not an FXP or FXB file")]
+    NotFx,
+    #[fail(display = "Truncated FXP or FXB file")]
+    Truncated,
+    #[fail(
+        display = "The file is for the plugin with id {}, not {}",
+        found, expected
+    )]
+    WrongPlugin { expected: i32, found: i32 },
+    #[fail(
+        display = "The file was saved by version {} of the plugin, version {} is loaded",
+        found, loaded
+    )]
+    NewerVersion { loaded: i32, found: i32 },
+    #[fail(display = "Parameter error: {}", _0)]
+    Parameter(ParameterError),
+}
+
+impl From<io::Error> for FxError {
+    fn from(err: io::Error) -> Self {
+        FxError::Io(err)
+    }
+}
+
+impl From<ParameterError> for FxError {
+    fn from(err: ParameterError) -> Self {
+        FxError::Parameter(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PresetData {
+    /// Normalized value of every parameter
+    Parameters(Vec<f32>),
+    /// State saved by the plugin itself
+    Chunk(Vec<u8>),
+}
+
+/// A program of a plugin, stored in `.fxp` files
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    /// `Info::unique_id` of the plugin
+    pub unique_id: i32,
+    /// `Info::version` of the plugin
+    pub version: i32,
+    /// At most 27 bytes are saved
+    pub name: String,
+    pub data: PresetData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BankData {
+    /// Every program of the plugin
+    Programs(Vec<Preset>),
+    /// State of every program saved by the plugin itself
+    Chunk(Vec<u8>),
+}
+
+/// Every program of a plugin, stored in `.fxb` files
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bank {
+    pub unique_id: i32,
+    pub version: i32,
+    /// Number of programs, the length of `BankData::Programs` when they are listed
+    pub program_count: i32,
+    /// Program selected when the bank was saved
+    pub current_program: i32,
+    pub data: BankData,
+}
+
+/// A decoded `.fxp` or `.fxb` file
+#[derive(Debug, Clone, PartialEq)]
+pub enum FxFile {
+    Preset(Preset),
+    Bank(Bank),
+}
+
+impl FxFile {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, FxError> {
+        Self::decode(&fs::read(path)?)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, FxError> {
+        let mut reader = Reader { data, position: 0 };
+        let magic = reader.header()?.magic;
+        reader.position = 0;
+        if magic == *b"FxBk" || magic == *b"FBCh" {
+            Ok(FxFile::Bank(reader.bank()?))
+        } else {
+            Ok(FxFile::Preset(reader.preset()?))
+        }
+    }
+
+    /// Apply the preset or the bank to a plugin, see `Preset::apply` and `Bank::apply`
+    pub fn apply(&self, parameters: &VstParameters, info: &Info) -> Result<(), FxError> {
+        match self {
+            FxFile::Preset(preset) => preset.apply(parameters, info),
+            FxFile::Bank(bank) => bank.apply(parameters, info),
+        }
+    }
+}
+
+/// Check that a file saved for `unique_id` at `version` can be loaded into the plugin, files
+/// of older versions are accepted
+fn check_plugin(unique_id: i32, version: i32, info: &Info) -> Result<(), FxError> {
+    if unique_id != info.unique_id {
+        return Err(FxError::WrongPlugin {
+            expected: info.unique_id,
+            found: unique_id,
+        });
+    }
+    if version > info.version {
+        return Err(FxError::NewerVersion {
+            loaded: info.version,
+            found: version,
+        });
+    }
+    Ok(())
+}
+
+impl Preset {
+    /// Save the current program of a plugin, as a chunk if the plugin supports it
+    pub fn capture(parameters: &VstParameters, info: &Info) -> Result<Self, FxError> {
+        let data = if info.preset_chunks {
+            PresetData::Chunk(parameters.preset_data()?)
+        } else {
+            PresetData::Parameters(
+                (0..parameters.count())
+                    .map(|index| parameters.get(index))
+                    .collect::<Result<_, _>>()?,
+            )
+        };
+        Ok(Self {
+            unique_id: info.unique_id,
+            version: info.version,
+            name: parameters.program_name(parameters.program()?)?,
+            data,
+        })
+    }
+
+    /// Load the preset into the current program of a plugin. Parameters missing in the
+    /// plugin are ignored
+    pub fn apply(&self, parameters: &VstParameters, info: &Info) -> Result<(), FxError> {
+        check_plugin(self.unique_id, self.version, info)?;
+        match &self.data {
+            PresetData::Chunk(chunk) => parameters.load_preset_data(chunk)?,
+            PresetData::Parameters(values) => {
+                for (index, value) in values.iter().take(parameters.count() as usize).enumerate() {
+                    parameters.set(index as i32, *value)?;
+                }
+            }
+        }
+        parameters.set_program_name(&self.name)?;
+        Ok(())
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, FxError> {
+        match FxFile::from_path(path)? {
+            FxFile::Preset(preset) => Ok(preset),
+            FxFile::Bank(_) => Err(FxError::NotFx),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FxError> {
+        Ok(fs::write(path, self.encode())?)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        self.write(&mut writer);
+        writer.finish()
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        let (magic, count) = match &self.data {
+            PresetData::Parameters(values) => (b"FxCk", values.len() as i32),
+            PresetData::Chunk(_) => (b"FPCh", 0),
+        };
+        let start = writer.begin(magic, 1, self.unique_id, self.version, count);
+        let mut name = [0u8; NAME_LENGTH];
+        let mut length = self.name.len().min(NAME_LENGTH - 1);
+        while !self.name.is_char_boundary(length) {
+            length -= 1;
+        }
+        name[..length].copy_from_slice(&self.name.as_bytes()[..length]);
+        writer.bytes(&name);
+        match &self.data {
+            PresetData::Parameters(values) => values.iter().for_each(|value| writer.f32(*value)),
+            PresetData::Chunk(chunk) => {
+                writer.i32(chunk.len() as i32);
+                writer.bytes(chunk);
+            }
+        }
+        writer.end(start);
+    }
+}
+
+impl Bank {
+    /// Save every program of a plugin, as a chunk if the plugin supports it. The current
+    /// program is selected again once the programs are read
+    pub fn capture(parameters: &VstParameters, info: &Info) -> Result<Self, FxError> {
+        let current_program = parameters.program()?;
+        let data = if info.preset_chunks {
+            BankData::Chunk(parameters.bank_data()?)
+        } else {
+            let mut programs = Vec::with_capacity(info.presets.max(0) as usize);
+            for program in 0..info.presets {
+                parameters.set_program(program)?;
+                programs.push(Preset::capture(parameters, info)?);
+            }
+            parameters.set_program(current_program)?;
+            BankData::Programs(programs)
+        };
+        Ok(Self {
+            unique_id: info.unique_id,
+            version: info.version,
+            program_count: info.presets,
+            current_program,
+            data,
+        })
+    }
+
+    /// Load every program into a plugin and select the current program of the bank. Programs
+    /// missing in the plugin are ignored
+    pub fn apply(&self, parameters: &VstParameters, info: &Info) -> Result<(), FxError> {
+        check_plugin(self.unique_id, self.version, info)?;
+        match &self.data {
+            BankData::Chunk(chunk) => parameters.load_bank_data(chunk)?,
+            BankData::Programs(programs) => {
+                for (program, preset) in programs.iter().take(info.presets as usize).enumerate() {
+                    parameters.set_program(program as i32)?;
+                    preset.apply(parameters, info)?;
+                }
+            }
+        }
+        parameters.set_program(self.current_program)?;
+        Ok(())
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, FxError> {
+        match FxFile::from_path(path)? {
+            FxFile::Bank(bank) => Ok(bank),
+            FxFile::Preset(_) => Err(FxError::NotFx),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FxError> {
+        Ok(fs::write(path, self.encode())?)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        let (magic, count) = match &self.data {
+            BankData::Programs(programs) => (b"FxBk", programs.len() as i32),
+            BankData::Chunk(_) => (b"FBCh", self.program_count),
+        };
+        let start = writer.begin(magic, 2, self.unique_id, self.version, count);
+        writer.i32(self.current_program);
+        writer.bytes(&[0; BANK_RESERVED - 4]);
+        match &self.data {
+            BankData::Programs(programs) => {
+                programs.iter().for_each(|preset| preset.write(&mut writer))
+            }
+            BankData::Chunk(chunk) => {
+                writer.i32(chunk.len() as i32);
+                writer.bytes(chunk);
+            }
+        }
+        writer.end(start);
+        writer.finish()
+    }
+}
+
+/// Big endian writer, the size fields are filled by `end`
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    /// Write the common header, returns the position of the size field
+    fn begin(
+        &mut self,
+        magic: &[u8; 4],
+        version: i32,
+        id: i32,
+        fx_version: i32,
+        count: i32,
+    ) -> usize {
+        self.bytes(b"CcnK");
+        let start = self.data.len();
+        self.i32(0);
+        self.bytes(magic);
+        self.i32(version);
+        self.i32(id);
+        self.i32(fx_version);
+        self.i32(count);
+        start
+    }
+
+    /// Set the size of the structure started at `start`, the bytes following the size field
+    fn end(&mut self, start: usize) {
+        let size = (self.data.len() - start - 4) as i32;
+        self.data[start..start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Common header of the presets and the banks, the count that follows is read by the caller
+struct Header {
+    /// Kind of structure, e.g. `FxCk`
+    magic: [u8; 4],
+    /// Version of the file format
+    format: i32,
+    unique_id: i32,
+    version: i32,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], FxError> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + length)
+            .ok_or(FxError::Truncated)?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, FxError> {
+        let bytes = self.bytes(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32, FxError> {
+        Ok(f32::from_bits(self.i32()? as u32))
+    }
+
+    fn header(&mut self) -> Result<Header, FxError> {
+        if self.bytes(4).map_err(|_| FxError::NotFx)? != b"CcnK" {
+            return Err(FxError::NotFx);
+        }
+        // The size is not reliable, some hosts write the size of the whole file
+        self.i32()?;
+        let mut magic = [0; 4];
+        magic.copy_from_slice(self.bytes(4)?);
+        Ok(Header {
+            magic,
+            format: self.i32()?,
+            unique_id: self.i32()?,
+            version: self.i32()?,
+        })
+    }
+
+    /// Read a chunk preceded by its size
+    fn chunk(&mut self) -> Result<Vec<u8>, FxError> {
+        let size = self.i32()?;
+        if size < 0 {
+            return Err(FxError::Truncated);
+        }
+        Ok(self.bytes(size as usize)?.to_vec())
+    }
+
+    fn preset(&mut self) -> Result<Preset, FxError> {
+        let Header {
+            magic,
+            unique_id,
+            version,
+            ..
+        } = self.header()?;
+        let count = self.i32()?.max(0) as usize;
+        let name = self.bytes(NAME_LENGTH)?;
+        let name = &name[..name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(NAME_LENGTH)];
+        let data = match &magic {
+            b"FxCk" => {
+                PresetData::Parameters((0..count).map(|_| self.f32()).collect::<Result<_, _>>()?)
+            }
+            b"FPCh" => PresetData::Chunk(self.chunk()?),
+            _ => return Err(FxError::NotFx),
+        };
+        Ok(Preset {
+            unique_id,
+            version,
+            name: String::from_utf8_lossy(name).into_owned(),
+            data,
+        })
+    }
+
+    fn bank(&mut self) -> Result<Bank, FxError> {
+        let Header {
+            magic,
+            format,
+            unique_id,
+            version,
+        } = self.header()?;
+        let program_count = self.i32()?;
+        let reserved = self.bytes(BANK_RESERVED)?;
+        let current_program = match format {
+            1 => 0,
+            _ => i32::from_be_bytes([reserved[0], reserved[1], reserved[2], reserved[3]]),
+        };
+        let data = match &magic {
+            b"FxBk" => BankData::Programs(
+                (0..program_count.max(0))
+                    .map(|_| self.preset())
+                    .collect::<Result<_, _>>()?,
+            ),
+            b"FBCh" => BankData::Chunk(self.chunk()?),
+            _ => return Err(FxError::NotFx),
+        };
+        Ok(Bank {
+            unique_id,
+            version,
+            program_count,
+            current_program,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use vst::plugin::PluginParameters;
+
+    /// Two programs of two parameters, the chunk is the raw values of the current program
+    #[derive(Default)]
+    struct TestParameters {
+        program: Mutex<(usize, [[f32; 2]; 2], [String; 2])>,
+    }
+
+    impl PluginParameters for TestParameters {
+        fn change_preset(&self, preset: i32) {
+            self.program.lock().unwrap().0 = preset as usize;
+        }
+
+        fn get_preset_num(&self) -> i32 {
+            self.program.lock().unwrap().0 as i32
+        }
+
+        fn set_preset_name(&self, name: String) {
+            let mut program = self.program.lock().unwrap();
+            let current = program.0;
+            program.2[current] = name;
+        }
+
+        fn get_preset_name(&self, preset: i32) -> String {
+            self.program.lock().unwrap().2[preset as usize].clone()
+        }
+
+        fn get_parameter(&self, index: i32) -> f32 {
+            let program = self.program.lock().unwrap();
+            program.1[program.0][index as usize]
+        }
+
+        fn set_parameter(&self, index: i32, value: f32) {
+            let mut program = self.program.lock().unwrap();
+            let current = program.0;
+            program.1[current][index as usize] = value;
+        }
+
+        fn get_preset_data(&self) -> Vec<u8> {
+            (0..2)
+                .map(|index| (self.get_parameter(index) * 255.0) as u8)
+                .collect()
+        }
+
+        fn load_preset_data(&self, data: &[u8]) {
+            for (index, byte) in data.iter().enumerate() {
+                self.set_parameter(index as i32, f32::from(*byte) / 255.0);
+            }
+        }
+    }
+
+    fn plugin(preset_chunks: bool) -> (VstParameters, Info) {
+        let info = Info {
+            unique_id: 0x4e61_4d61,
+            version: 3,
+            presets: 2,
+            parameters: 2,
+            preset_chunks,
+            ..Info::default()
+        };
+        (
+            VstParameters::local(Arc::new(TestParameters::default()), 2),
+            info,
+        )
+    }
+
+    #[test]
+    fn preset_roundtrip() {
+        let (parameters, info) = plugin(false);
+        parameters.set(1, 0.5).unwrap();
+        parameters
+            .set_program_name("A rather long program name")
+            .unwrap();
+        let preset = Preset::capture(&parameters, &info).unwrap();
+        assert_eq!(preset.data, PresetData::Parameters(vec![0.0, 0.5]));
+        let data = preset.encode();
+        assert_eq!(&data[..4], b"CcnK");
+        assert_eq!(&data[8..12], b"FxCk");
+        assert_eq!(data.len(), 8 + 20 + NAME_LENGTH + 8);
+        assert_eq!(i32::from_be_bytes([data[4], data[5], data[6], data[7]]), 56);
+        let decoded = match FxFile::decode(&data).unwrap() {
+            FxFile::Preset(preset) => preset,
+            other => panic!("Unexpected file {:?}", other),
+        };
+        assert_eq!(decoded.name, "A rather long program name");
+        assert_eq!(decoded.data, preset.data);
+
+        parameters.set_program(1).unwrap();
+        decoded.apply(&parameters, &info).unwrap();
+        assert_eq!(parameters.get(1).unwrap(), 0.5);
+        assert_eq!(parameters.program_name(1).unwrap(), decoded.name);
+
+        let chunk = Preset {
+            data: PresetData::Chunk(vec![255, 0]),
+            ..decoded
+        };
+        assert_eq!(
+            FxFile::decode(&chunk.encode()).unwrap(),
+            FxFile::Preset(chunk.clone())
+        );
+        chunk.apply(&parameters, &info).unwrap();
+        assert_eq!(parameters.get(0).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn bank_roundtrip() {
+        let (parameters, info) = plugin(false);
+        for program in 0..2 {
+            parameters.set_program(program).unwrap();
+            parameters.set(0, 0.25 * (program + 1) as f32).unwrap();
+            parameters
+                .set_program_name(&format!("Program {}", program))
+                .unwrap();
+        }
+        let bank = Bank::capture(&parameters, &info).unwrap();
+        assert_eq!(bank.current_program, 1);
+        assert_eq!(parameters.program().unwrap(), 1);
+        let decoded = Bank::from_path({
+            let path = std::env::temp_dir().join("engine-fxp-bank.fxb");
+            bank.save(&path).unwrap();
+            path
+        })
+        .unwrap();
+        assert_eq!(decoded, bank);
+
+        let (other, _) = plugin(false);
+        decoded.apply(&other, &info).unwrap();
+        assert_eq!(other.program().unwrap(), 1);
+        assert_eq!(other.get(0).unwrap(), 0.5);
+        other.set_program(0).unwrap();
+        assert_eq!(other.get(0).unwrap(), 0.25);
+        assert_eq!(other.program_name(0).unwrap(), "Program 0");
+
+        let (parameters, info) = plugin(true);
+        let chunk = Bank::capture(&parameters, &info).unwrap();
+        assert_eq!(
+            FxFile::decode(&chunk.encode()).unwrap(),
+            FxFile::Bank(chunk)
+        );
+    }
+
+    #[test]
+    fn reject_files() {
+        let (parameters, mut info) = plugin(false);
+        let preset = Preset::capture(&parameters, &info).unwrap();
+        info.version = 2;
+        match preset.apply(&parameters, &info) {
+            Err(FxError::NewerVersion {
+                loaded: 2,
+                found: 3,
+            }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        info.version = 4;
+        assert!(preset.apply(&parameters, &info).is_ok());
+        info.unique_id = 1;
+        match preset.apply(&parameters, &info) {
+            Err(FxError::WrongPlugin { expected: 1, .. }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match FxFile::decode(b"RIFF") {
+            Err(FxError::NotFx) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match FxFile::decode(&preset.encode()[..40]) {
+            Err(FxError::Truncated) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
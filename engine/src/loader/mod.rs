@@ -1,2 +1,8 @@
 pub mod vst;
-pub mod asset;
\ No newline at end of file
+pub mod asset;
+pub mod aiff;
+pub mod bridge;
+pub mod catalog;
+pub mod fxp;
+pub mod parameters;
+pub mod smf;
\ No newline at end of file
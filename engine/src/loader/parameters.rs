@@ -0,0 +1,349 @@
+//! Access to the parameters of a loaded plugin from any thread
+use crate::loader::bridge::{BridgeChannel, BridgeError};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, RwLock};
+use vst::plugin::PluginParameters;
+
+#[derive(Debug, Fail)]
+pub enum ParameterError {
+    #[fail(display = "No parameter at index {}", _0)]
+    InvalidIndex(i32),
+    #[fail(display = "The plugin is no longer loaded")]
+    Unloaded,
+    #[fail(display = "Plugin bridge error: {}", _0)]
+    Bridge(BridgeError),
+}
+
+impl From<BridgeError> for ParameterError {
+    fn from(err: BridgeError) -> Self {
+        ParameterError::Bridge(err)
+    }
+}
+
+/// Description and current value of a parameter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterInfo {
+    pub index: i32,
+    pub name: String,
+    /// Unit of the displayed value, e.g. "dB"
+    pub label: String,
+    /// Value as displayed by the plugin, e.g. "-6.0"
+    pub text: String,
+    /// Normalized value in the range [0, 1]
+    pub value: f32,
+    pub can_be_automated: bool,
+}
+
+impl ParameterInfo {
+    /// Read the parameter at `index` from the plugin
+    pub fn read(parameters: &dyn PluginParameters, index: i32) -> Self {
+        Self {
+            index,
+            name: parameters.get_parameter_name(index),
+            label: parameters.get_parameter_label(index),
+            text: parameters.get_parameter_text(index),
+            value: parameters.get_parameter(index),
+            can_be_automated: parameters.can_be_automated(index),
+        }
+    }
+}
+
+/// Where the parameters calls are sent
+#[derive(Clone)]
+enum Access {
+    /// The `PluginParameters` object of a plugin loaded in the engine
+    Local(Arc<dyn PluginParameters>),
+    /// The control channel of a bridged plugin
+    Bridged(Arc<Mutex<BridgeChannel>>),
+}
+
+/// Parameters of a plugin, get a handle with `VstPlugin::parameters`
+///
+/// The handle can be cloned and used from any thread without locking the `VstPlugin`, so the
+/// audio thread keeps processing while the parameters are read or changed. Calls made once the
+/// plugin is dropped fail with `ParameterError::Unloaded`.
+#[derive(Clone)]
+pub struct VstParameters {
+    access: Access,
+    count: i32,
+    /// Cleared when the plugin is dropped, calls hold the read lock
+    loaded: Arc<RwLock<bool>>,
+}
+
+// VST 2.4 plugins must accept parameter calls from any thread, `loaded` guarantees the
+// instance outlives the calls
+unsafe impl Send for VstParameters {}
+unsafe impl Sync for VstParameters {}
+
+impl VstParameters {
+    pub(crate) fn local(parameters: Arc<dyn PluginParameters>, count: i32) -> Self {
+        Self::new(Access::Local(parameters), count)
+    }
+
+    pub(crate) fn bridged(channel: Arc<Mutex<BridgeChannel>>, count: i32) -> Self {
+        Self::new(Access::Bridged(channel), count)
+    }
+
+    fn new(access: Access, count: i32) -> Self {
+        Self {
+            access,
+            count: count.max(0),
+            loaded: Arc::new(RwLock::new(true)),
+        }
+    }
+
+    /// Make every handle fail, called before the plugin instance is dropped
+    pub(crate) fn release(&self) {
+        if let Ok(mut loaded) = self.loaded.write() {
+            *loaded = false;
+        }
+    }
+
+    /// Check the index and call `local` or `bridged` while the plugin is loaded
+    fn call<T, L, B>(&self, index: i32, local: L, bridged: B) -> Result<T, ParameterError>
+    where
+        L: FnOnce(&dyn PluginParameters) -> T,
+        B: FnOnce(&mut BridgeChannel) -> Result<T, BridgeError>,
+    {
+        if index < 0 || index >= self.count {
+            return Err(ParameterError::InvalidIndex(index));
+        }
+        self.with(local, bridged)
+    }
+
+    /// Call `local` or `bridged` while the plugin is loaded
+    fn with<T, L, B>(&self, local: L, bridged: B) -> Result<T, ParameterError>
+    where
+        L: FnOnce(&dyn PluginParameters) -> T,
+        B: FnOnce(&mut BridgeChannel) -> Result<T, BridgeError>,
+    {
+        let loaded = self.loaded.read().map_err(|_| ParameterError::Unloaded)?;
+        if !*loaded {
+            return Err(ParameterError::Unloaded);
+        }
+        match &self.access {
+            Access::Local(parameters) => Ok(local(&**parameters)),
+            Access::Bridged(channel) => {
+                let mut channel = channel.lock().map_err(|_| ParameterError::Unloaded)?;
+                Ok(bridged(&mut channel)?)
+            }
+        }
+    }
+
+    /// Number of parameters of the plugin
+    pub fn count(&self) -> i32 {
+        self.count
+    }
+
+    /// Get the normalized value of a parameter
+    pub fn get(&self, index: i32) -> Result<f32, ParameterError> {
+        self.call(
+            index,
+            |parameters| parameters.get_parameter(index),
+            |channel| channel.get_parameter(index),
+        )
+    }
+
+    /// Set the normalized value of a parameter, it is clamped to [0, 1]
+    pub fn set(&self, index: i32, value: f32) -> Result<(), ParameterError> {
+        let value = value.clamp(0.0, 1.0);
+        self.call(
+            index,
+            |parameters| parameters.set_parameter(index, value),
+            |channel| channel.set_parameter(index, value),
+        )
+    }
+
+    /// Set a parameter from a text as displayed by the plugin, e.g. "-6 dB". Returns false if
+    /// the plugin doesn't understand the text, many plugins don't support it at all
+    pub fn set_text(&self, index: i32, text: &str) -> Result<bool, ParameterError> {
+        self.call(
+            index,
+            |parameters| parameters.string_to_parameter(index, text.to_string()),
+            |channel| channel.set_parameter_text(index, text),
+        )
+    }
+
+    /// Describe a parameter
+    pub fn info(&self, index: i32) -> Result<ParameterInfo, ParameterError> {
+        self.call(
+            index,
+            |parameters| ParameterInfo::read(parameters, index),
+            |channel| channel.parameter_info(index),
+        )
+    }
+
+    /// Describe every parameter, in index order
+    pub fn list(&self) -> Result<Vec<ParameterInfo>, ParameterError> {
+        (0..self.count).map(|index| self.info(index)).collect()
+    }
+
+    /// Get the index of the current program (preset)
+    pub fn program(&self) -> Result<i32, ParameterError> {
+        self.with(
+            |parameters| parameters.get_preset_num(),
+            |channel| channel.program(),
+        )
+    }
+
+    /// Switch to another program of the plugin bank
+    pub fn set_program(&self, program: i32) -> Result<(), ParameterError> {
+        self.with(
+            |parameters| parameters.change_preset(program),
+            |channel| channel.set_program(program),
+        )
+    }
+
+    pub fn program_name(&self, program: i32) -> Result<String, ParameterError> {
+        self.with(
+            |parameters| parameters.get_preset_name(program),
+            |channel| channel.program_name(program),
+        )
+    }
+
+    /// Rename the current program
+    pub fn set_program_name(&self, name: &str) -> Result<(), ParameterError> {
+        self.with(
+            |parameters| parameters.set_preset_name(name.to_string()),
+            |channel| channel.set_program_name(name),
+        )
+    }
+
+    /// Get the opaque state chunk of the current program, only for plugins with
+    /// `Info::preset_chunks`
+    pub fn preset_data(&self) -> Result<Vec<u8>, ParameterError> {
+        self.with(
+            |parameters| parameters.get_preset_data(),
+            |channel| channel.data(false),
+        )
+    }
+
+    /// Get the opaque state chunk of every program
+    pub fn bank_data(&self) -> Result<Vec<u8>, ParameterError> {
+        self.with(
+            |parameters| parameters.get_bank_data(),
+            |channel| channel.data(true),
+        )
+    }
+
+    /// Restore the current program from a chunk returned by `preset_data`
+    pub fn load_preset_data(&self, data: &[u8]) -> Result<(), ParameterError> {
+        self.with(
+            |parameters| parameters.load_preset_data(data),
+            |channel| channel.set_data(false, data),
+        )
+    }
+
+    /// Restore every program from a chunk returned by `bank_data`
+    pub fn load_bank_data(&self, data: &[u8]) -> Result<(), ParameterError> {
+        self.with(
+            |parameters| parameters.load_bank_data(data),
+            |channel| channel.set_data(true, data),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vst::util::AtomicFloat;
+
+    /// A gain and a mute switch, the gain accepts values in dB
+    struct TestParameters {
+        gain: AtomicFloat,
+        mute: AtomicFloat,
+    }
+
+    impl PluginParameters for TestParameters {
+        fn get_parameter(&self, index: i32) -> f32 {
+            match index {
+                0 => self.gain.get(),
+                _ => self.mute.get(),
+            }
+        }
+
+        fn set_parameter(&self, index: i32, value: f32) {
+            match index {
+                0 => self.gain.set(value),
+                _ => self.mute.set(value),
+            }
+        }
+
+        fn get_parameter_name(&self, index: i32) -> String {
+            ["Gain", "Mute"][index as usize].to_string()
+        }
+
+        fn get_parameter_label(&self, index: i32) -> String {
+            ["dB", ""][index as usize].to_string()
+        }
+
+        fn get_parameter_text(&self, index: i32) -> String {
+            match index {
+                0 => format!("{:.1}", self.gain.get() * 24.0 - 24.0),
+                _ => (if self.mute.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            }
+        }
+
+        fn can_be_automated(&self, index: i32) -> bool {
+            index == 0
+        }
+
+        fn string_to_parameter(&self, index: i32, text: String) -> bool {
+            match (index, text.trim_end_matches("dB").trim().parse::<f32>()) {
+                (0, Ok(db)) => {
+                    self.gain.set((db + 24.0) / 24.0);
+                    true
+                }
+                _ => false,
+            }
+        }
+    }
+
+    #[test]
+    fn local_parameters() {
+        let parameters = VstParameters::local(
+            Arc::new(TestParameters {
+                gain: AtomicFloat::new(1.0),
+                mute: AtomicFloat::new(0.0),
+            }),
+            2,
+        );
+        let handle = parameters.clone();
+        std::thread::spawn(move || handle.set(1, 2.0).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(parameters.get(1).unwrap(), 1.0);
+        assert!(parameters.set_text(0, "-6 dB").unwrap());
+        assert!(!parameters.set_text(0, "loud").unwrap());
+        assert_eq!(
+            parameters.list().unwrap(),
+            vec![
+                ParameterInfo {
+                    index: 0,
+                    name: "Gain".to_string(),
+                    label: "dB".to_string(),
+                    text: "-6.0".to_string(),
+                    value: 0.75,
+                    can_be_automated: true,
+                },
+                ParameterInfo {
+                    index: 1,
+                    name: "Mute".to_string(),
+                    label: "".to_string(),
+                    text: "On".to_string(),
+                    value: 1.0,
+                    can_be_automated: false,
+                },
+            ]
+        );
+        match parameters.get(2) {
+            Err(ParameterError::InvalidIndex(2)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        parameters.release();
+        match parameters.get(0) {
+            Err(ParameterError::Unloaded) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
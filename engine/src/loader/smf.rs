@@ -0,0 +1,319 @@
+//! Standard MIDI File decoder, formats 0 and 1 with a metrical (ticks per quarter note) timing
+use crate::prelude::*;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+#[derive(Debug, Fail)]
+pub enum SmfError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Not a Standard MIDI File")]
+    NotMidi,
+    #[fail(display = "Unsupported MIDI file format {}", _0)]
+    UnsupportedFormat(u16),
+    #[fail(display = "SMPTE timing is not supported")]
+    SmpteTiming,
+    #[fail(display = "Invalid event in track {}", _0)]
+    InvalidEvent(usize),
+}
+
+impl From<io::Error> for SmfError {
+    fn from(err: io::Error) -> Self {
+        SmfError::Io(err)
+    }
+}
+
+/// Channel message of a track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmfEvent {
+    /// Position in ticks from the start of the file
+    pub tick: u64,
+    /// Status and data bytes, unused bytes are 0
+    pub data: [u8; 3],
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SmfTrack {
+    /// Name from the track name meta event
+    pub name: Option<String>,
+    /// Channel messages sorted by position, meta and system exclusive events are left out
+    pub events: Vec<SmfEvent>,
+}
+
+/// A decoded MIDI file
+///
+/// Tempo and time signature changes of every track are gathered in `tempos` and
+/// `time_signatures`, see `tempo_map` to play the file at its own tempo.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiFile {
+    /// 0 for a single track, 1 for simultaneous tracks
+    pub format: u16,
+    pub ticks_per_quarter: u16,
+    pub tracks: Vec<SmfTrack>,
+    /// Position in ticks and tempo in beats per minute
+    pub tempos: Vec<(u64, f64)>,
+    /// Position in ticks, numerator and denominator
+    pub time_signatures: Vec<(u64, i32, i32)>,
+}
+
+impl MidiFile {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, SmfError> {
+        Self::decode(BufReader::new(File::open(path)?))
+    }
+
+    /// Decode a whole MIDI stream
+    pub fn decode<R: Read>(mut rd: R) -> Result<Self, SmfError> {
+        let (id, header) = read_chunk(&mut rd)?.ok_or(SmfError::NotMidi)?;
+        if &id != b"MThd" || header.len() < 6 {
+            return Err(SmfError::NotMidi);
+        }
+        let format = be_u16(&header[0..2]);
+        let track_count = be_u16(&header[2..4]) as usize;
+        let division = be_u16(&header[4..6]);
+        if format > 1 {
+            return Err(SmfError::UnsupportedFormat(format));
+        }
+        if division & 0x8000 != 0 || division == 0 {
+            return Err(SmfError::SmpteTiming);
+        }
+        let mut file = Self {
+            format,
+            ticks_per_quarter: division,
+            tracks: Vec::with_capacity(track_count),
+            tempos: Vec::new(),
+            time_signatures: Vec::new(),
+        };
+        while file.tracks.len() < track_count {
+            let (id, data) = match read_chunk(&mut rd)? {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            // Unknown chunks must be skipped
+            if &id == b"MTrk" {
+                let index = file.tracks.len();
+                let track = file
+                    .read_track(&data)
+                    .ok_or(SmfError::InvalidEvent(index))?;
+                file.tracks.push(track);
+            }
+        }
+        file.tempos.sort_by_key(|(tick, _)| *tick);
+        file.time_signatures.sort_by_key(|(tick, _, _)| *tick);
+        Ok(file)
+    }
+
+    /// Parse the events of a track, `None` if the track is truncated or malformed
+    fn read_track(&mut self, data: &[u8]) -> Option<SmfTrack> {
+        let mut track = SmfTrack::default();
+        let mut position = 0;
+        let mut tick = 0u64;
+        let mut running_status = None;
+        while position < data.len() {
+            tick += u64::from(read_vlq(data, &mut position)?);
+            let mut status = *data.get(position)?;
+            if status < 0x80 {
+                // Running status: the status of the previous channel message is reused
+                status = running_status?;
+            } else {
+                position += 1;
+            }
+            match status {
+                0xFF => {
+                    let kind = *data.get(position)?;
+                    position += 1;
+                    let length = read_vlq(data, &mut position)? as usize;
+                    let payload = data.get(position..position + length)?;
+                    position += length;
+                    match kind {
+                        0x03 if track.name.is_none() => {
+                            track.name = Some(String::from_utf8_lossy(payload).into_owned());
+                        }
+                        0x2F => break,
+                        0x51 if length == 3 => {
+                            let micros = u32::from(payload[0]) << 16
+                                | u32::from(payload[1]) << 8
+                                | u32::from(payload[2]);
+                            if micros > 0 {
+                                self.tempos.push((tick, 60_000_000.0 / f64::from(micros)));
+                            }
+                        }
+                        0x58 if length >= 2 && payload[1] < 31 => {
+                            self.time_signatures.push((
+                                tick,
+                                i32::from(payload[0]),
+                                1 << payload[1],
+                            ));
+                        }
+                        _ => {}
+                    }
+                    // Meta events cancel the running status
+                    running_status = None;
+                }
+                0xF0 | 0xF7 => {
+                    let length = read_vlq(data, &mut position)? as usize;
+                    position += length;
+                    running_status = None;
+                }
+                0x80..=0xEF => {
+                    let length = match status & 0xF0 {
+                        0xC0 | 0xD0 => 1,
+                        _ => 2,
+                    };
+                    let bytes = data.get(position..position + length)?;
+                    position += length;
+                    let mut event = [status, 0, 0];
+                    event[1..=length].copy_from_slice(bytes);
+                    track.events.push(SmfEvent { tick, data: event });
+                    running_status = Some(status);
+                }
+                _ => return None,
+            }
+        }
+        Some(track)
+    }
+
+    /// Convert a position in ticks into quarter notes
+    pub fn ticks_to_beats(&self, tick: u64) -> f64 {
+        tick as f64 / f64::from(self.ticks_per_quarter)
+    }
+
+    /// Build the tempo map of the file, 120 BPM in 4/4 until the first changes
+    pub fn tempo_map(&self) -> TempoMap {
+        let mut map = TempoMap::default();
+        for (tick, bpm) in self.tempos.iter() {
+            map.set_tempo(self.ticks_to_beats(*tick), *bpm);
+        }
+        // Signatures are placed in order since a bar depends on the previous signatures
+        for (tick, numerator, denominator) in self.time_signatures.iter() {
+            let bar = map.beats_to_bars(self.ticks_to_beats(*tick)).round() as u32;
+            map.set_time_signature(bar, *numerator, *denominator);
+        }
+        map
+    }
+}
+
+/// Read the next chunk, `None` at the end of the stream
+fn read_chunk<R: Read>(rd: &mut R) -> Result<Option<([u8; 4], Vec<u8>)>, SmfError> {
+    let mut header = [0u8; 8];
+    if let Err(err) = rd.read_exact(&mut header) {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(err.into());
+    }
+    let mut id = [0u8; 4];
+    id.copy_from_slice(&header[0..4]);
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let mut data = Vec::new();
+    rd.take(size as u64).read_to_end(&mut data)?;
+    Ok(Some((id, data)))
+}
+
+/// Read a variable length quantity, at most 4 bytes
+fn read_vlq(data: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    for _ in 0..4 {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        value = value << 7 | u32::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    /// Format 1 file at 96 ticks per quarter note: a tempo track (90 BPM then 3/4 at beat 4)
+    /// and a melody track using running status
+    pub(crate) fn two_tracks() -> Vec<u8> {
+        let mut file = chunk(b"MThd", &[0, 1, 0, 2, 0, 96]);
+        file.extend(chunk(
+            b"MTrk",
+            &[
+                0x00, 0xFF, 0x51, 0x03, 0x0A, 0x2C, 0x2A, // 666666 µs per quarter note
+                0x83, 0x00, 0xFF, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08, // 3/4 at tick 384
+                0x00, 0xFF, 0x2F, 0x00,
+            ],
+        ));
+        file.extend(chunk(b"XFIH", &[1, 2, 3]));
+        file.extend(chunk(
+            b"MTrk",
+            &[
+                0x00, 0xFF, 0x03, 0x04, b'L', b'e', b'a', b'd', // track name
+                0x00, 0xC0, 0x05, // program change
+                0x00, 0x90, 0x3C, 0x64, // note on
+                0x60, 0x3C, 0x00, // note off as a running note on
+                0x00, 0x40, 0x64, // running status again
+                0x81, 0x40, 0x80, 0x40, 0x00, // note off 192 ticks later
+                0x00, 0xFF, 0x2F, 0x00,
+            ],
+        ));
+        file
+    }
+
+    #[test]
+    fn decode_format_1() {
+        let file = MidiFile::decode(&two_tracks()[..]).unwrap();
+        assert_eq!(file.format, 1);
+        assert_eq!(file.ticks_per_quarter, 96);
+        assert_eq!(file.tracks.len(), 2);
+        assert!(file.tracks[0].events.is_empty());
+        let lead = &file.tracks[1];
+        assert_eq!(lead.name.as_ref().map(String::as_str), Some("Lead"));
+        let events: Vec<_> = lead.events.iter().map(|e| (e.tick, e.data)).collect();
+        assert_eq!(
+            events,
+            vec![
+                (0, [0xC0, 0x05, 0]),
+                (0, [0x90, 0x3C, 0x64]),
+                (96, [0x90, 0x3C, 0x00]),
+                (96, [0x90, 0x40, 0x64]),
+                (288, [0x80, 0x40, 0x00]),
+            ]
+        );
+        let map = file.tempo_map();
+        assert!((map.tempo_at(0.0) - 90.0).abs() < 1e-3);
+        assert_eq!(map.time_signature_at(3.9), (4, 4));
+        assert_eq!(map.time_signature_at(4.0), (3, 4));
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        match MidiFile::decode(&b"RIFF\0\0\0\x04WAVE"[..]) {
+            Err(SmfError::NotMidi) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match MidiFile::decode(&chunk(b"MThd", &[0, 2, 0, 1, 0, 96])[..]) {
+            Err(SmfError::UnsupportedFormat(2)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match MidiFile::decode(&chunk(b"MThd", &[0, 0, 0, 1, 0xE7, 0x28])[..]) {
+            Err(SmfError::SmpteTiming) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        let mut truncated = chunk(b"MThd", &[0, 0, 0, 1, 0, 96]);
+        truncated.extend(chunk(b"MTrk", &[0x00, 0x90, 0x3C]));
+        match MidiFile::decode(&truncated[..]) {
+            Err(SmfError::InvalidEvent(0)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,45 +1,211 @@
-use crate::{prelude::*, supervisor::linker::Linker, devices::VstBufferedDevice};
+use crate::{
+    devices::VstBufferedDevice,
+    loader::{
+        bridge::{BridgeError, BridgedInstance},
+        fxp::{Bank, FxError, FxFile, Preset},
+        parameters::VstParameters,
+    },
+    prelude::*,
+    supervisor::{
+        automation::{AutomationLane, PARAMETER_CHANGES_CAPACITY},
+        delay::DelayLine,
+        linker::{Linker, LinkerError},
+        midi::MIDI_BUFFER_CAPACITY,
+        queue::EventQueue,
+        tempo::TempoMap,
+        transport::Transport,
+    },
+};
 use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
     ffi::c_void,
-    sync::{Arc, RwLock},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc, RwLock,
+    },
 };
 use vst::{
-    api::{self, TimeInfo},
-    buffer::AudioBuffer,
+    api::{self, AutomationState, Supported, TimeInfo},
+    buffer::SendEventBuffer,
     editor::Editor,
-    host::{Host, PluginInstance},
+    event::Event,
+    host::{Host, HostBuffer, HostCanDo, InstanceId, PluginInstance},
     plugin::{Info, Plugin},
 };
 
+/// Parameter changes a plugin can send between two reads of its queue
+const AUTOMATION_QUEUE_CAPACITY: usize = 1024;
+/// Channels of the devices a plugin is piped through
+const CHANNELS: usize = 2;
+
 /// Unique id assigned to a vst instance
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
 pub struct VstId(u64);
 
+/// Parameter change made by a plugin itself, e.g. from its editor, or sent to it by an
+/// automation lane
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParameterChange {
+    pub index: i32,
+    /// New value in the range [0, 1]
+    pub value: f32,
+    /// Position in samples: of the transport when a plugin made the change, in the block
+    /// for the automation
+    pub sample_pos: f64,
+}
+
+/// A parameter grabbed or released by the user in the plugin editor, the changes made in
+/// between are usually recorded as automation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterEdit {
+    Begin(i32),
+    End(i32),
+}
+
+/// Events sent by a plugin through the host callbacks, pushed by the `VstHost` and read
+/// through the `VstPlugin`
+pub struct PluginEvents {
+    pub(crate) midi: EventQueue<MidiEvent>,
+    pub(crate) automation: EventQueue<ParameterChange>,
+    pub(crate) edits: EventQueue<ParameterEdit>,
+    /// Set when the inputs, outputs or initial delay of the plugin changed
+    pub(crate) io_changed: AtomicBool,
+    /// Set when the plugin asks the host to read its programs and parameters again
+    pub(crate) display_changed: AtomicBool,
+}
+
+impl PluginEvents {
+    pub fn new() -> Self {
+        Self {
+            midi: EventQueue::new(MIDI_BUFFER_CAPACITY),
+            automation: EventQueue::new(AUTOMATION_QUEUE_CAPACITY),
+            edits: EventQueue::new(AUTOMATION_QUEUE_CAPACITY),
+            io_changed: AtomicBool::new(false),
+            display_changed: AtomicBool::new(false),
+        }
+    }
+}
+
 /// VST plugin host
 pub struct VstHost {
-    pub time_info: Option<TimeInfo>,
+    /// Musical time reported to the plugins
+    pub transport: Transport,
+    /// Tempo and time signature changes followed by the transport
+    tempo_map: TempoMap,
     pub block_size: isize,
+    /// Latency of the audio input reported to the plugins, in samples
+    pub input_latency: isize,
+    /// Latency of the audio output reported to the plugins, in samples
+    pub output_latency: isize,
+    /// Is the graph rendered to a file, the plugins running in a bridge are then waited for
+    /// instead of being given the duration of a block
+    pub offline: bool,
+    /// Queues of the loaded instances, events of unknown instances are dropped
+    instances: BTreeMap<InstanceId, Arc<PluginEvents>>,
 }
 
 impl VstHost {
     /// Create an empty host
     ///
     /// # Parametters
+    /// * `sample_rate` The sample rate of the transport
     /// * `block_size` The default samples block size
-    pub fn new(block_size: isize) -> Self {
+    pub fn new(sample_rate: f64, block_size: isize) -> Self {
         Self {
-            time_info: None,
+            transport: Transport::new(sample_rate),
+            tempo_map: TempoMap::default(),
             block_size,
+            input_latency: 0,
+            output_latency: 0,
+            offline: false,
+            instances: BTreeMap::new(),
         }
     }
+
+    /// Route the MIDI events and parameter changes sent by `instance` into `events`
+    pub fn register_instance(&mut self, instance: InstanceId, events: Arc<PluginEvents>) {
+        self.instances.insert(instance, events);
+    }
+
+    pub fn unregister_instance(&mut self, instance: InstanceId) {
+        self.instances.remove(&instance);
+    }
+
+    pub fn tempo_map(&self) -> &TempoMap {
+        &self.tempo_map
+    }
+
+    /// Replace the tempo map, the transport keeps its position in samples
+    pub fn set_tempo_map(&mut self, tempo_map: TempoMap) {
+        self.tempo_map = tempo_map;
+        self.transport.update(&self.tempo_map);
+    }
+
+    /// Move the transport to the next block
+    pub fn advance(&mut self, frames: usize) {
+        self.transport.advance(frames, &self.tempo_map);
+    }
+
+    /// Move the transport to a position in samples
+    pub fn seek(&mut self, sample_pos: f64) {
+        self.transport.seek(sample_pos, &self.tempo_map);
+    }
 }
 
 impl Host for VstHost {
-    fn automate(&self, index: i32, value: f32) {
-        info!("Parameter {} had its value changed to {}", index, value);
+    /// MIDI and time info go both ways, IO changes are forwarded to the `VstPlugin`. No
+    /// editor window is owned by the host so window sizes are not supported, nor are offline
+    /// processing and file selectors
+    fn can_do(&self, can_do: HostCanDo) -> Supported {
+        match can_do {
+            HostCanDo::SendEvents
+            | HostCanDo::SendMidiEvent
+            | HostCanDo::SendTimeInfo
+            | HostCanDo::ReceiveEvents
+            | HostCanDo::ReceiveMidiEvent
+            | HostCanDo::AcceptIOChanges => Supported::Yes,
+            HostCanDo::Other(feature) => {
+                trace!("Unknown host feature {} asked by a plugin", feature);
+                Supported::No
+            }
+            _ => Supported::No,
+        }
     }
-    fn process_events(&self, events: &api::Events) {
-        info!("Events: {:?}", events.num_events);
+
+    fn automate_instance(&self, instance: InstanceId, index: i32, value: f32) {
+        let change = ParameterChange {
+            index,
+            value,
+            sample_pos: self.transport.sample_pos(),
+        };
+        match self.instances.get(&instance) {
+            Some(events) => {
+                if !events.automation.push(change) {
+                    trace!("Automation queue full, parameter {} change dropped", index);
+                }
+            }
+            None => trace!(
+                "Parameter {} of an unknown plugin changed to {}",
+                index,
+                value
+            ),
+        }
+    }
+
+    fn process_instance_events(&self, instance: InstanceId, events: &api::Events) {
+        let queue = match self.instances.get(&instance) {
+            Some(queue) => &queue.midi,
+            None => return trace!("{} events of an unknown plugin", events.num_events),
+        };
+        // SysEx payloads don't outlive the callback, only MIDI events are kept
+        for event in events.events() {
+            if let Event::Midi(event) = event {
+                queue.push(event);
+            }
+        }
     }
 
     fn idle(&self) {
@@ -47,13 +213,82 @@ impl Host for VstHost {
     }
 
     fn get_time_info(&self, mask: i32) -> Option<TimeInfo> {
-        Some(TimeInfo::default())
+        Some(self.transport.time_info(mask))
     }
 
     fn get_block_size(&self) -> isize {
         info!("Return bszie ...");
         self.block_size
     }
+
+    fn get_sample_rate(&self) -> f32 {
+        self.transport.sample_rate() as f32
+    }
+
+    fn get_input_latency(&self) -> isize {
+        self.input_latency
+    }
+
+    fn get_output_latency(&self) -> isize {
+        self.output_latency
+    }
+
+    /// The lanes are played, the changes made by the plugins can be recorded while the
+    /// transport records
+    fn get_automation_state(&self) -> AutomationState {
+        if self.transport.is_recording() {
+            AutomationState::ReadWrite
+        } else {
+            AutomationState::Read
+        }
+    }
+
+    fn io_changed_instance(&self, instance: InstanceId) -> bool {
+        match self.instances.get(&instance) {
+            Some(events) => {
+                events.io_changed.store(true, AtomicOrdering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn begin_edit_instance(&self, instance: InstanceId, index: i32) {
+        if let Some(events) = self.instances.get(&instance) {
+            events.edits.push(ParameterEdit::Begin(index));
+        }
+    }
+
+    fn end_edit_instance(&self, instance: InstanceId, index: i32) {
+        if let Some(events) = self.instances.get(&instance) {
+            events.edits.push(ParameterEdit::End(index));
+        }
+    }
+
+    fn update_display_instance(&self, instance: InstanceId) {
+        if let Some(events) = self.instances.get(&instance) {
+            events.display_changed.store(true, AtomicOrdering::Release);
+        }
+    }
+}
+
+/// Where the plugin code runs
+enum Instance {
+    /// Loaded in the engine process
+    Local(PluginInstance),
+    /// Running in a bridge process
+    Bridged(BridgedInstance),
+}
+
+/// Lifecycle of a `VstPlugin`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginState {
+    /// Part of the graph and processing samples
+    Active,
+    /// Removed from the graph
+    Unloaded,
+    /// The bridge process of the plugin died or stopped responding, the plugin outputs silence
+    Crashed,
 }
 
 /// VST Instance wrapper that contains extra informations like I/O devices index
@@ -63,11 +298,36 @@ pub struct VstPlugin {
     /// VST Basic informations
     info: Info,
     /// VST Instance
-    instance: PluginInstance,
+    instance: Instance,
     /// Input device (allocated in the linker arena)
     input: InputIndex,
     /// Input output (allocated in the linker arena)
     output: OutputIndex,
+    /// MIDI input device (allocated in the linker arena)
+    midi_input: MidiInputIndex,
+    /// MIDI output device (allocated in the linker arena)
+    midi_output: MidiOutputIndex,
+    /// Events of the block, allocated once for the audio thread
+    send_buffer: SendEventBuffer,
+    /// Events sent by `process_events` for the next block
+    block_events: Vec<MidiEvent>,
+    /// Events of the part of the block being processed
+    slice_events: Vec<MidiEvent>,
+    /// Automated parameters, at most one lane per parameter
+    lanes: Vec<AutomationLane>,
+    /// Changes of the automation lanes for the block, sorted by position
+    changes: Vec<ParameterChange>,
+    /// MIDI events and parameter changes sent by the plugin
+    events: Arc<PluginEvents>,
+    /// Parameters shared with the other threads
+    parameters: VstParameters,
+    /// Is the plugin editor opened
+    editor_opened: bool,
+    /// The inputs are copied to the outputs without calling the plugin
+    bypass: bool,
+    /// Delays the inputs copied while bypassed by the latency of the plugin
+    bypass_delay: DelayLine,
+    state: PluginState,
 }
 
 impl VstPlugin {
@@ -82,10 +342,32 @@ impl VstPlugin {
         instance.set_sample_rate(sample_rate);
         instance.set_block_size(block_size);
         instance.resume();
+        Self::register(Instance::Local(instance), info, block_size, linker)
+    }
+
+    /// Wrap a plugin running in a bridge process, it is already initialized by
+    /// `BridgedInstance::spawn`
+    pub fn bridged(instance: BridgedInstance, block_size: i64, linker: &mut Linker) -> Self {
+        let info = instance.get_info();
+        Self::register(Instance::Bridged(instance), info, block_size, linker)
+    }
+
+    fn register(mut instance: Instance, info: Info, block_size: i64, linker: &mut Linker) -> Self {
         let id = VstId(crate::supervisor::linker::new_id());
-        let virt_device = Box::new(VstBufferedDevice::new(block_size as usize, 2, id));
+        let virt_device = Box::new(VstBufferedDevice::new(block_size as usize, CHANNELS, id));
         let input = linker.register_input(virt_device.clone());
-        let output = linker.register_output(virt_device);
+        let output = linker.register_output(virt_device.clone());
+        let midi_input = linker.register_midi_input(virt_device.clone());
+        let midi_output = linker.register_midi_output(virt_device);
+        let parameters = match &mut instance {
+            Instance::Local(instance) => {
+                VstParameters::local(instance.get_parameter_object(), info.parameters)
+            }
+            Instance::Bridged(instance) => {
+                VstParameters::bridged(instance.channel(), info.parameters)
+            }
+        };
+        let bypass_delay = DelayLine::new(CHANNELS, info.initial_delay.max(0) as usize);
         info!("Plugin initialized: {:?}", info);
         Self {
             id,
@@ -93,6 +375,136 @@ impl VstPlugin {
             instance,
             input,
             output,
+            midi_input,
+            midi_output,
+            send_buffer: SendEventBuffer::new(MIDI_BUFFER_CAPACITY),
+            block_events: Vec::with_capacity(MIDI_BUFFER_CAPACITY),
+            slice_events: Vec::with_capacity(MIDI_BUFFER_CAPACITY),
+            lanes: Vec::new(),
+            changes: Vec::with_capacity(PARAMETER_CHANGES_CAPACITY),
+            events: Arc::new(PluginEvents::new()),
+            parameters,
+            editor_opened: false,
+            bypass: false,
+            bypass_delay,
+            state: PluginState::Active,
+        }
+    }
+
+    /// Tear down the plugin: its devices are removed from the linker, the editor is closed
+    /// and the instance is suspended. The instance is dropped with the last graph using it.
+    ///
+    /// The teardown always goes to the end, the devices that could not be removed are returned
+    pub fn unload(&mut self, linker: &mut Linker) -> Vec<LinkerError> {
+        let errors = vec![
+            linker.unregister_input(self.input).err(),
+            linker.unregister_output(self.output).err(),
+            linker.unregister_midi_input(self.midi_input).err(),
+            linker.unregister_midi_output(self.midi_output).err(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let state = self.state;
+        self.state = PluginState::Unloaded;
+        match &mut self.instance {
+            Instance::Local(instance) => {
+                if self.editor_opened {
+                    instance.close_editor();
+                }
+                instance.suspend();
+            }
+            Instance::Bridged(instance) => {
+                if state != PluginState::Crashed {
+                    if let Err(err) = instance.suspend() {
+                        warn!("Unable to suspend the plugin {:?}: {}", self.id, err);
+                    }
+                }
+            }
+        }
+        info!("Plugin unloaded: {:?}", self.id);
+        errors
+    }
+
+    /// Is the plugin still processing samples
+    pub fn is_active(&self) -> bool {
+        self.state == PluginState::Active
+    }
+
+    pub fn state(&self) -> PluginState {
+        self.state
+    }
+
+    /// Does the plugin run in a bridge process
+    pub fn is_bridged(&self) -> bool {
+        match self.instance {
+            Instance::Bridged(_) => true,
+            Instance::Local(_) => false,
+        }
+    }
+
+    pub fn is_bypassed(&self) -> bool {
+        self.bypass
+    }
+
+    /// Bypass the plugin: its inputs are copied to its outputs, delayed by its latency, and
+    /// the MIDI events it receives are dropped. The automation is not played while it is
+    /// bypassed
+    pub fn set_bypass(&mut self, bypass: bool) {
+        if bypass && !self.bypass {
+            self.bypass_delay.reset();
+        }
+        self.bypass = bypass;
+    }
+
+    /// Get the number of samples the plugin delays its inputs by, its initial delay. The
+    /// graph delays the other branches by the same amount
+    pub fn get_latency(&self) -> usize {
+        self.info.initial_delay.max(0) as usize
+    }
+
+    /// Read the initial delay of the plugin again, after it signaled an IO change (see
+    /// `take_io_changed`). Returns true if the latency changed, the graph must then be
+    /// compiled again
+    pub fn update_latency(&mut self) -> bool {
+        if self.state != PluginState::Active {
+            return false;
+        }
+        let initial_delay = match &mut self.instance {
+            Instance::Local(instance) => instance.get_initial_delay(),
+            Instance::Bridged(instance) => match instance.get_initial_delay() {
+                Ok(initial_delay) => initial_delay,
+                Err(err) => {
+                    warn!(
+                        "Unable to read the latency of the plugin {:?}: {}",
+                        self.id, err
+                    );
+                    return false;
+                }
+            },
+        };
+        if initial_delay == self.info.initial_delay {
+            return false;
+        }
+        info!(
+            "Latency of the plugin {:?} changed to {} samples",
+            self.id, initial_delay
+        );
+        self.info.initial_delay = initial_delay;
+        self.bypass_delay = DelayLine::new(CHANNELS, self.get_latency());
+        true
+    }
+
+    /// Get the number of samples the plugin keeps producing once its input is silent
+    pub fn get_tail_size(&self) -> usize {
+        let size = match &self.instance {
+            Instance::Local(instance) => instance.get_tail_size(),
+            Instance::Bridged(instance) => instance.get_tail_size(),
+        };
+        // 0 means unknown and 1 no tail
+        match size {
+            size if size > 1 => size as usize,
+            _ => 0,
         }
     }
 
@@ -104,13 +516,381 @@ impl VstPlugin {
         self.output
     }
 
+    /// Get the MIDI input device, its events are sent to the plugin before each block
+    pub fn get_midi_input(&self) -> MidiInputIndex {
+        self.midi_input
+    }
+
+    /// Get the MIDI output device, it plays the events sent by the plugin during each block
+    pub fn get_midi_output(&self) -> MidiOutputIndex {
+        self.midi_output
+    }
+
+    /// Get the id the plugin calls the host with, bridged plugins call the host of their
+    /// bridge process
+    pub fn instance_id(&self) -> Option<InstanceId> {
+        match &self.instance {
+            Instance::Local(instance) => Some(instance.instance_id()),
+            Instance::Bridged(_) => None,
+        }
+    }
+
+    /// Get the queues filled by the host callbacks of the plugin
+    pub(crate) fn events(&self) -> Arc<PluginEvents> {
+        self.events.clone()
+    }
+
+    /// Move the MIDI events sent by the plugin into `buffer`, until it is full
+    pub fn drain_midi_output(&mut self, buffer: &mut MidiBuffer) {
+        while buffer.len() < buffer.capacity() {
+            match self.events.midi.pop() {
+                Some(event) => buffer.push(event),
+                None => break,
+            };
+        }
+    }
+
+    /// Take the oldest parameter change made by the plugin itself, to be recorded as
+    /// automation. Plugins usually don't report the changes made through `set_parameter`
+    pub fn poll_automation(&mut self) -> Option<ParameterChange> {
+        self.events.automation.pop()
+    }
+
+    /// Take the oldest parameter grabbed or released in the editor of the plugin
+    pub fn poll_edit(&mut self) -> Option<ParameterEdit> {
+        self.events.edits.pop()
+    }
+
+    /// Did the inputs, outputs or initial delay of the plugin change since the last call
+    pub fn take_io_changed(&self) -> bool {
+        self.events.io_changed.swap(false, AtomicOrdering::AcqRel)
+    }
+
+    /// Did the plugin ask to read its programs and parameters again since the last call
+    pub fn take_display_changed(&self) -> bool {
+        self.events
+            .display_changed
+            .swap(false, AtomicOrdering::AcqRel)
+    }
+
+    /// Get the plugin informations (name, vendor, parameters count [...])
+    pub fn get_info(&self) -> &Info {
+        &self.info
+    }
+
+    /// Get a handle on the parameters, it can be used from any thread while the plugin plays
+    pub fn parameters(&self) -> VstParameters {
+        self.parameters.clone()
+    }
+
+    /// Save the current program into an `.fxp` preset file
+    pub fn save_preset<P: AsRef<Path>>(&self, path: P) -> Result<(), FxError> {
+        Preset::capture(&self.parameters, &self.info)?.save(path)
+    }
+
+    /// Save every program into an `.fxb` bank file
+    pub fn save_bank<P: AsRef<Path>>(&self, path: P) -> Result<(), FxError> {
+        Bank::capture(&self.parameters, &self.info)?.save(path)
+    }
+
+    /// Load an `.fxp` preset into the current program or an `.fxb` bank. The file must have
+    /// been saved by the same plugin, at the same or an older version
+    pub fn load_preset<P: AsRef<Path>>(&self, path: P) -> Result<(), FxError> {
+        FxFile::from_path(path)?.apply(&self.parameters, &self.info)
+    }
+
+    /// Set the value of the parameter at `index`, in the range [0, 1]
+    pub fn set_parameter(&mut self, index: i32, value: f32) {
+        match &mut self.instance {
+            Instance::Local(instance) => {
+                instance.get_parameter_object().set_parameter(index, value);
+            }
+            Instance::Bridged(instance) if self.state == PluginState::Active => {
+                if let Err(err) = instance.set_parameter(index, value) {
+                    self.crashed(err);
+                }
+            }
+            Instance::Bridged(_) => {}
+        }
+    }
+
+    /// Get the automation lanes, they are played while the transport is playing
+    pub fn automation(&self) -> &[AutomationLane] {
+        &self.lanes
+    }
+
+    pub fn automation_lane(&self, parameter: i32) -> Option<&AutomationLane> {
+        self.lanes.iter().find(|lane| lane.parameter() == parameter)
+    }
+
+    /// Automate a parameter, the lane already automating it is replaced
+    pub fn set_automation(&mut self, lane: AutomationLane) {
+        match self
+            .lanes
+            .iter()
+            .position(|other| other.parameter() == lane.parameter())
+        {
+            Some(idx) => self.lanes[idx] = lane,
+            None => self.lanes.push(lane),
+        }
+    }
+
+    /// Remove the automation of a parameter, it keeps its last value
+    pub fn remove_automation(&mut self, parameter: i32) -> Option<AutomationLane> {
+        let idx = self
+            .lanes
+            .iter()
+            .position(|lane| lane.parameter() == parameter)?;
+        Some(self.lanes.remove(idx))
+    }
+
+    /// Open the plugin editor, bridged plugins have no editor
     pub fn load_editor(&mut self, win_handle: *mut c_void) {
-        let edit = self.instance.get_editor().expect("Editor");
+        let instance = match &mut self.instance {
+            Instance::Local(instance) => instance,
+            Instance::Bridged(_) => {
+                warn!("The editor of a bridged plugin can't be opened");
+                return;
+            }
+        };
+        let edit = instance.get_editor().expect("Editor");
         info!("Editor size: W {}, H {}", edit.size().0, edit.size().1);
-        self.instance.open_editor(win_handle);
+        instance.open_editor(win_handle);
+        self.editor_opened = true;
+    }
+
+    /// Send the events of the next block to the plugin, must be called right before `next`
+    ///
+    /// # Parameters
+    ///
+    /// * `events` Sorted by `delta_frames`, the events past `MIDI_BUFFER_CAPACITY` are dropped
+    pub fn process_events(&mut self, events: &[MidiEvent]) {
+        if self.state != PluginState::Active || self.bypass {
+            return;
+        }
+        match &mut self.instance {
+            Instance::Local(_) => {
+                let free = self.block_events.capacity() - self.block_events.len();
+                self.block_events.extend(events.iter().take(free).cloned());
+            }
+            Instance::Bridged(instance) => instance.process_events(events),
+        }
+    }
+
+    /// Process the next block
+    ///
+    /// The block is processed in several parts when the automation changes a parameter in
+    /// the middle of it, the changes are applied at the start of each part.
+    ///
+    /// # Parameters
+    ///
+    /// * `inputs` and `outputs` Channels of the block, all of the same length
+    /// * `buffer` Holds the channels given to the plugin, it must fit them
+    /// * `transport` Position of the block, the automation is played while it is playing
+    /// * `tempo_map` Map followed by the transport
+    pub fn next(
+        &mut self,
+        inputs: &[Vec<f32>],
+        outputs: &mut [Vec<f32>],
+        buffer: &mut HostBuffer<f32>,
+        transport: Option<&Transport>,
+        tempo_map: &TempoMap,
+    ) {
+        if self.state != PluginState::Active {
+            return;
+        }
+        if self.bypass {
+            for output in outputs.iter_mut() {
+                output.iter_mut().for_each(|sample| *sample = 0.0);
+            }
+            self.bypass_delay.mix(inputs, outputs);
+            // The values are sent again once the plugin is enabled
+            self.lanes.iter_mut().for_each(AutomationLane::reset);
+            return;
+        }
+        let frames = inputs
+            .iter()
+            .chain(outputs.iter())
+            .map(|channel| channel.len())
+            .next()
+            .unwrap_or(0);
+        self.changes.clear();
+        match transport.filter(|transport| transport.is_playing()) {
+            Some(transport) => {
+                for lane in self.lanes.iter_mut() {
+                    lane.write_changes(transport, tempo_map, frames, &mut self.changes);
+                }
+                // Doesn't allocate unlike the stable sort
+                self.changes.sort_unstable_by(|a, b| {
+                    a.sample_pos
+                        .partial_cmp(&b.sample_pos)
+                        .unwrap_or(Ordering::Equal)
+                });
+            }
+            // The values are sent again once the transport plays
+            None => self.lanes.iter_mut().for_each(AutomationLane::reset),
+        }
+        match &mut self.instance {
+            Instance::Local(instance) => {
+                process_sliced(
+                    instance,
+                    buffer,
+                    inputs,
+                    outputs,
+                    &self.block_events,
+                    &self.changes,
+                    &mut self.send_buffer,
+                    &mut self.slice_events,
+                );
+                self.block_events.clear();
+            }
+            Instance::Bridged(instance) => {
+                instance.process_parameter_changes(&self.changes);
+                if let Err(err) = instance.process(&mut buffer.bind(inputs, outputs), &self.events)
+                {
+                    outputs
+                        .iter_mut()
+                        .for_each(|channel| channel.iter_mut().for_each(|sample| *sample = 0.0));
+                    self.crashed(err);
+                }
+            }
+        }
+    }
+
+    fn crashed(&mut self, err: BridgeError) {
+        error!("Plugin {} ({:?}) crashed: {}", self.info.name, self.id, err);
+        self.state = PluginState::Crashed;
+    }
+}
+
+impl Drop for VstPlugin {
+    fn drop(&mut self) {
+        self.parameters.release();
+    }
+}
+
+/// Process a block in parts starting at each parameter change, the changes are set through
+/// the `PluginParameters` of the plugin and each part gets the MIDI events in its range
+///
+/// # Parameters
+///
+/// * `events` Sorted by `delta_frames`, relative to the start of the block
+/// * `changes` Sorted by position in the block
+/// * `slice_events` Holds the events of each part, its capacity is never exceeded
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn process_sliced(
+    instance: &mut PluginInstance,
+    buffer: &mut HostBuffer<f32>,
+    inputs: &[Vec<f32>],
+    outputs: &mut [Vec<f32>],
+    events: &[MidiEvent],
+    changes: &[ParameterChange],
+    send_buffer: &mut SendEventBuffer,
+    slice_events: &mut Vec<MidiEvent>,
+) {
+    let frames = inputs
+        .iter()
+        .chain(outputs.iter())
+        .map(|channel| channel.len())
+        .next()
+        .unwrap_or(0);
+    let parameters = if changes.is_empty() {
+        None
+    } else {
+        Some(instance.get_parameter_object())
+    };
+    let (mut start, mut change, mut event) = (0, 0, 0);
+    loop {
+        while let Some(next) = changes
+            .get(change)
+            .filter(|next| next.sample_pos <= start as f64)
+        {
+            if let Some(parameters) = parameters.as_ref() {
+                parameters.set_parameter(next.index, next.value);
+            }
+            change += 1;
+        }
+        let end = changes
+            .get(change)
+            .map(|next| (next.sample_pos.ceil() as usize).max(start + 1).min(frames))
+            .unwrap_or(frames);
+        // The last part gets the events past the end of the block
+        slice_events.clear();
+        while let Some(next) = events.get(event) {
+            if end < frames && next.delta_frames.max(0) as usize >= end {
+                break;
+            }
+            if slice_events.len() < slice_events.capacity() {
+                let mut next = *next;
+                next.delta_frames = (next.delta_frames - start as i32).max(0);
+                slice_events.push(next);
+            }
+            event += 1;
+        }
+        if !slice_events.is_empty() {
+            send_buffer.send_events_to_plugin(&slice_events[..], instance);
+        }
+        instance.process(&mut buffer.bind_range(inputs, outputs, start..end));
+        if end >= frames {
+            break;
+        }
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_plugin_events() {
+        let mut host = VstHost::new(48000.0, 64);
+        let events = Arc::new(PluginEvents::new());
+        let instance = InstanceId::from_effect(8 as *mut _);
+        host.register_instance(instance, events.clone());
+        host.seek(480.0);
+        host.automate_instance(instance, 2, 0.5);
+        host.automate_instance(InstanceId::from_effect(16 as *mut _), 3, 1.0);
+        assert_eq!(
+            events.automation.pop(),
+            Some(ParameterChange {
+                index: 2,
+                value: 0.5,
+                sample_pos: 480.0
+            })
+        );
+        assert_eq!(events.automation.pop(), None);
+        host.unregister_instance(instance);
+        host.automate_instance(instance, 2, 0.5);
+        assert!(events.automation.is_empty());
     }
 
-    pub fn next<'a>(&mut self, buffer: &mut AudioBuffer<'a, f32>) {
-        self.instance.process(buffer);
+    #[test]
+    fn host_callbacks() {
+        let mut host = VstHost::new(48000.0, 64);
+        let events = Arc::new(PluginEvents::new());
+        let instance = InstanceId::from_effect(8 as *mut _);
+        let unknown = InstanceId::from_effect(16 as *mut _);
+        host.register_instance(instance, events.clone());
+        assert_eq!(host.get_sample_rate(), 48000.0);
+        assert_eq!(host.can_do(HostCanDo::ReceiveMidiEvent), Supported::Yes);
+        assert_eq!(host.can_do(HostCanDo::AcceptIOChanges), Supported::Yes);
+        assert_eq!(host.can_do(HostCanDo::Offline), Supported::No);
+        assert_eq!(host.can_do(HostCanDo::SizeWindow), Supported::No);
+        assert_eq!(host.get_automation_state(), AutomationState::Read);
+        host.transport.set_recording(true);
+        assert_eq!(host.get_automation_state(), AutomationState::ReadWrite);
+        // No editor window is owned by the host, the plugin is told it was not resized
+        assert!(!host.size_window_instance(instance, 640, 480));
+        assert!(host.io_changed_instance(instance));
+        assert!(events.io_changed.load(AtomicOrdering::Acquire));
+        host.begin_edit_instance(instance, 1);
+        host.end_edit_instance(instance, 1);
+        host.end_edit_instance(unknown, 2);
+        assert_eq!(events.edits.pop(), Some(ParameterEdit::Begin(1)));
+        assert_eq!(events.edits.pop(), Some(ParameterEdit::End(1)));
+        assert_eq!(events.edits.pop(), None);
+        host.update_display_instance(instance);
+        assert!(events.display_changed.load(AtomicOrdering::Acquire));
     }
 }
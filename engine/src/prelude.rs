@@ -1,8 +1,19 @@
-pub use crate::loader::vst::{VstHost, VstId, VstPlugin};
+pub use crate::loader::fxp::{Bank, BankData, FxError, FxFile, Preset, PresetData};
+pub use crate::loader::parameters::{ParameterInfo, VstParameters};
+pub use crate::loader::vst::{
+    ParameterChange, ParameterEdit, PluginState, VstHost, VstId, VstPlugin,
+};
+pub use crate::supervisor::automation::{AutomationLane, Breakpoint, Curve};
 pub use crate::supervisor::linker::{
-    DeviceId, InputIndex, Linker, OutputIndex, PipeIndex, SampleDevice, SampleInput, SampleOutput,
+    DeviceId, InputIndex, Linker, MidiDevice, MidiInput, MidiInputIndex, MidiOutput,
+    MidiOutputIndex, MidiPipeIndex, OutputIndex, PipeIndex, SampleDevice, SampleInput,
+    SampleOutput,
 };
+pub use crate::supervisor::midi::{midi_event, MidiBuffer};
+pub use crate::supervisor::tempo::TempoMap;
+pub use crate::supervisor::transport::Transport;
 pub use vst::buffer::AudioBuffer;
+pub use vst::event::MidiEvent;
 
 pub use cpal;
 pub use sample;
@@ -0,0 +1,355 @@
+//! Parameter automation: values of a plugin parameter over time, played with the transport
+use crate::loader::vst::ParameterChange;
+use crate::supervisor::{tempo::TempoMap, transport::Transport};
+use serde::{Deserialize, Serialize};
+
+/// Parameter changes sent to a plugin per block, the changes past it wait for the next block
+pub const PARAMETER_CHANGES_CAPACITY: usize = 1024;
+/// Samples between two values sent along a curve, a step is sent at its exact position
+pub const AUTOMATION_RESOLUTION: usize = 32;
+/// Steepness of the `Exponential` curve
+const EXPONENTIAL_STEEPNESS: f32 = 4.0;
+/// Iterations used to find the position on a Bézier curve, enough for a 32 bits value
+const BEZIER_ITERATIONS: usize = 24;
+
+/// How the value moves from a breakpoint to the next one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Curve {
+    /// Keep the value until the next breakpoint
+    Step,
+    Linear,
+    /// Slow start and fast end, e.g. for frequencies
+    Exponential,
+    /// Cubic Bézier easing between (0, 0) and (1, 1) through two control points, like the CSS
+    /// `cubic-bezier` function. `x1` and `x2` are clamped to [0, 1]
+    Bezier {
+        x1: f32,
+        y1: f32,
+        x2: f32,
+        y2: f32,
+    },
+}
+
+impl Curve {
+    /// Progress of the value at `t`, the progress of the time between the two breakpoints.
+    /// Both are 0 at the first breakpoint and 1 at the second one
+    pub fn progress(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match *self {
+            Curve::Step => 0.0,
+            Curve::Linear => t,
+            Curve::Exponential => {
+                ((EXPONENTIAL_STEEPNESS * t).exp() - 1.0) / (EXPONENTIAL_STEEPNESS.exp() - 1.0)
+            }
+            Curve::Bezier { x1, y1, x2, y2 } => {
+                let (x1, x2) = (x1.clamp(0.0, 1.0), x2.clamp(0.0, 1.0));
+                // x grows with the curve parameter, it is found by bisection
+                let (mut low, mut high) = (0.0, 1.0);
+                for _ in 0..BEZIER_ITERATIONS {
+                    let middle = (low + high) / 2.0;
+                    if bezier(middle, x1, x2) < t {
+                        low = middle;
+                    } else {
+                        high = middle;
+                    }
+                }
+                bezier((low + high) / 2.0, y1, y2)
+            }
+        }
+    }
+}
+
+/// Cubic Bézier from 0 to 1 through the control values `p1` and `p2`
+fn bezier(s: f32, p1: f32, p2: f32) -> f32 {
+    let r = 1.0 - s;
+    3.0 * r * r * s * p1 + 3.0 * r * s * s * p2 + s * s * s
+}
+
+/// Value of a lane from a position
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Breakpoint {
+    /// Position in quarter notes
+    pub beat: f64,
+    /// Parameter value in the range [0, 1]
+    pub value: f32,
+    /// Curve followed up to the next breakpoint
+    pub curve: Curve,
+}
+
+/// Automation of one parameter of a plugin
+///
+/// The value before the first breakpoint is the value of the first breakpoint and the last
+/// value is kept after the last one. Lanes are played while the transport is playing, the
+/// changes are sent to the plugin at their position in the block.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AutomationLane {
+    parameter: i32,
+    /// Sorted by position
+    points: Vec<Breakpoint>,
+    /// Last value sent to the plugin
+    #[serde(skip)]
+    sent: Option<f32>,
+}
+
+impl AutomationLane {
+    /// Create an empty lane for the parameter at `parameter`
+    pub fn new(parameter: i32) -> Self {
+        Self {
+            parameter,
+            points: Vec::new(),
+            sent: None,
+        }
+    }
+
+    /// Index of the automated parameter
+    pub fn parameter(&self) -> i32 {
+        self.parameter
+    }
+
+    pub fn points(&self) -> &[Breakpoint] {
+        &self.points
+    }
+
+    /// Add a breakpoint, replacing the one at the same position. The value is clamped to
+    /// [0, 1] and negative positions are ignored
+    pub fn set_point(&mut self, beat: f64, value: f32, curve: Curve) {
+        if beat < 0.0 || !beat.is_finite() || value.is_nan() {
+            return;
+        }
+        let point = Breakpoint {
+            beat,
+            value: value.clamp(0.0, 1.0),
+            curve,
+        };
+        match self.points.iter().position(|other| other.beat >= beat) {
+            Some(idx) if self.points[idx].beat == beat => self.points[idx] = point,
+            Some(idx) => self.points.insert(idx, point),
+            None => self.points.push(point),
+        }
+    }
+
+    /// Remove the breakpoint at `beat`
+    pub fn remove_point(&mut self, beat: f64) {
+        self.points.retain(|point| point.beat != beat);
+    }
+
+    /// Value of the parameter at a position in quarter notes, `None` without breakpoints
+    pub fn value_at(&self, beat: f64) -> Option<f32> {
+        let idx = match self.points.iter().rposition(|point| point.beat <= beat) {
+            Some(idx) => idx,
+            None => return self.points.first().map(|point| point.value),
+        };
+        let from = &self.points[idx];
+        Some(match self.points.get(idx + 1) {
+            Some(to) => {
+                let t = (beat - from.beat) / (to.beat - from.beat);
+                from.value + (to.value - from.value) * from.curve.progress(t as f32)
+            }
+            None => from.value,
+        })
+    }
+
+    /// Forget the last value sent, the value is sent again on the next block
+    pub(crate) fn reset(&mut self) {
+        self.sent = None;
+    }
+
+    /// Push the changes of the block starting at the transport position into `changes`, their
+    /// position is in samples from the start of the block. Changes past the capacity of
+    /// `changes` are dropped, nothing is allocated.
+    ///
+    /// Positions are converted through the tempo map followed by the transport and the block
+    /// is split where it wraps around the loop range. Steps are sent at their exact position,
+    /// values along the other curves every `AUTOMATION_RESOLUTION` samples and at each
+    /// breakpoint.
+    pub(crate) fn write_changes(
+        &mut self,
+        transport: &Transport,
+        tempo_map: &TempoMap,
+        frames: usize,
+        changes: &mut Vec<ParameterChange>,
+    ) {
+        let rate = transport.sample_rate();
+        for segment in transport.segments(frames, tempo_map) {
+            let end = segment.frame + segment.frames;
+            // Position of a frame of the segment in samples
+            let position = |frame: usize| segment.sample_pos + (frame - segment.frame) as f64;
+            let mut frame = segment.frame;
+            while frame < end {
+                let beat = tempo_map.samples_to_beats(position(frame), rate);
+                let value = match self.value_at(beat) {
+                    Some(value) => value,
+                    None => return,
+                };
+                if self.sent != Some(value) {
+                    if changes.len() == changes.capacity() {
+                        return;
+                    }
+                    changes.push(ParameterChange {
+                        index: self.parameter,
+                        value,
+                        sample_pos: frame as f64,
+                    });
+                    self.sent = Some(value);
+                }
+                let next = self.points.iter().position(|point| point.beat > beat);
+                let next_frame = match next {
+                    Some(idx) => {
+                        let point = tempo_map.beats_to_samples(self.points[idx].beat, rate);
+                        let offset = (point - segment.sample_pos).ceil();
+                        let point_frame =
+                            segment.frame + offset.min(segment.frames as f64) as usize;
+                        match self.points.get(idx.wrapping_sub(1)) {
+                            Some(from) if from.curve != Curve::Step => {
+                                point_frame.min(frame + AUTOMATION_RESOLUTION)
+                            }
+                            _ => point_frame,
+                        }
+                    }
+                    None => end,
+                };
+                frame = next_frame.max(frame + 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn curves() {
+        let mut lane = AutomationLane::new(3);
+        lane.set_point(4.0, 1.0, Curve::Step);
+        lane.set_point(0.0, 0.5, Curve::Linear);
+        lane.set_point(2.0, 0.0, Curve::Exponential);
+        lane.set_point(6.0, 2.0, Curve::Linear);
+        assert_eq!(lane.points().len(), 4);
+        assert_eq!(lane.value_at(-1.0), Some(0.5));
+        assert_close(lane.value_at(1.0).unwrap(), 0.25);
+        assert_close(lane.value_at(3.0).unwrap(), 0.1192);
+        assert_eq!(lane.value_at(4.0), Some(1.0));
+        assert_eq!(lane.value_at(5.9), Some(1.0));
+        assert_eq!(lane.value_at(100.0), Some(1.0));
+        lane.remove_point(4.0);
+        assert_close(lane.value_at(5.0).unwrap(), 0.3561);
+        assert_eq!(AutomationLane::new(0).value_at(1.0), None);
+
+        let ease = Curve::Bezier {
+            x1: 0.42,
+            y1: 0.0,
+            x2: 0.58,
+            y2: 1.0,
+        };
+        assert_close(ease.progress(0.0), 0.0);
+        assert_close(ease.progress(0.5), 0.5);
+        assert_close(ease.progress(1.0), 1.0);
+        assert!(ease.progress(0.2) < 0.2);
+        assert!(ease.progress(0.8) > 0.8);
+        let linear = Curve::Bezier {
+            x1: 0.25,
+            y1: 0.25,
+            x2: 0.75,
+            y2: 0.75,
+        };
+        assert_close(linear.progress(0.3), 0.3);
+    }
+
+    #[test]
+    fn block_changes() {
+        // A quarter note lasts 32768 samples at 60 BPM, the positions are exact
+        let map = TempoMap::new(60.0);
+        let mut transport = Transport::new(32768.0);
+        transport.play();
+        transport.seek(32768.0 - 100.0, &map);
+        let mut lane = AutomationLane::new(1);
+        lane.set_point(0.0, 0.0, Curve::Step);
+        lane.set_point(1.0, 0.5, Curve::Linear);
+        lane.set_point(1.0 + 64.0 / 32768.0, 1.0, Curve::Step);
+        let mut changes = Vec::with_capacity(8);
+        lane.write_changes(&transport, &map, 256, &mut changes);
+        let values: Vec<_> = changes
+            .iter()
+            .map(|change| (change.index, change.sample_pos, change.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (1, 0.0, 0.0),
+                (1, 100.0, 0.5),
+                (1, 132.0, 0.75),
+                (1, 164.0, 1.0)
+            ]
+        );
+        // Values already sent are not sent again
+        transport.advance(256, &map);
+        changes.clear();
+        lane.write_changes(&transport, &map, 256, &mut changes);
+        assert!(changes.is_empty());
+        lane.reset();
+        lane.write_changes(&transport, &map, 256, &mut changes);
+        assert_eq!(changes.len(), 1);
+        // Changes past the capacity are dropped
+        transport.seek(0.0, &map);
+        let mut changes = Vec::with_capacity(2);
+        lane.write_changes(&transport, &map, 32768 + 256, &mut changes);
+        assert_eq!(changes.len(), 2);
+    }
+
+    #[test]
+    fn follow_tempo_and_loop() {
+        // 60 BPM then 120 BPM from beat 0.5, at 16384 samples
+        let mut map = TempoMap::new(60.0);
+        map.set_tempo(0.5, 120.0);
+        let mut transport = Transport::new(32768.0);
+        transport.play();
+        transport.seek(16384.0 - 16.0, &map);
+        let mut lane = AutomationLane::new(0);
+        lane.set_point(0.0, 0.0, Curve::Step);
+        lane.set_point(0.5, 0.5, Curve::Step);
+        lane.set_point(0.75, 1.0, Curve::Step);
+        let mut changes = Vec::with_capacity(8);
+        let mut write = |lane: &mut AutomationLane, transport: &Transport, frames| {
+            changes.clear();
+            lane.write_changes(transport, &map, frames, &mut changes);
+            changes
+                .iter()
+                .map(|change| (change.sample_pos, change.value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            write(&mut lane, &transport, 8192),
+            vec![(0.0, 0.0), (16.0, 0.5), (4112.0, 1.0)]
+        );
+        // The loop ends at beat 0.75, 20480 samples
+        transport.set_loop(Some((0.0, 0.75)));
+        transport.seek(20480.0 - 100.0, &map);
+        assert_eq!(
+            write(&mut lane, &transport, 256),
+            vec![(0.0, 0.5), (100.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn serialize() {
+        let mut lane = AutomationLane::new(2);
+        lane.set_point(1.5, 0.25, Curve::Exponential);
+        lane.set_point(
+            3.0,
+            0.75,
+            Curve::Bezier {
+                x1: 0.1,
+                y1: 0.2,
+                x2: 0.3,
+                y2: 0.4,
+            },
+        );
+        let json = serde_json::to_string(&lane).unwrap();
+        assert_eq!(serde_json::from_str::<AutomationLane>(&json).unwrap(), lane);
+    }
+}
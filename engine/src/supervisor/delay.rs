@@ -0,0 +1,100 @@
+/// Fixed delay of a multichannel signal, used to keep the branches of the graph aligned when
+/// they go through plugins of different latencies.
+///
+/// The memory is allocated once, processing never allocates.
+pub struct DelayLine {
+    /// Ring buffer of every channel, `delay` samples long
+    lines: Vec<Vec<f32>>,
+    /// Position of the oldest sample in the lines
+    cursor: usize,
+}
+
+impl DelayLine {
+    /// Create a silent delay line
+    ///
+    /// # Parameters
+    ///
+    /// * `channels` Number of delayed channels, extra channels of a block are ignored
+    /// * `delay` The delay in samples
+    pub fn new(channels: usize, delay: usize) -> Self {
+        Self {
+            lines: vec![vec![0f32; delay]; channels],
+            cursor: 0,
+        }
+    }
+
+    /// Get the delay in samples
+    pub fn delay(&self) -> usize {
+        self.lines.first().map(Vec::len).unwrap_or(0)
+    }
+
+    /// Write the delayed `inputs` into `outputs`
+    pub fn process(&mut self, inputs: &[Vec<f32>], outputs: &mut [Vec<f32>]) {
+        self.run(inputs, outputs, |output, sample| *output = sample);
+    }
+
+    /// Add the delayed `inputs` to `outputs`
+    pub fn mix(&mut self, inputs: &[Vec<f32>], outputs: &mut [Vec<f32>]) {
+        self.run(inputs, outputs, |output, sample| *output += sample);
+    }
+
+    /// Clear the samples waiting in the line
+    pub fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.iter_mut().for_each(|sample| *sample = 0.0);
+        }
+        self.cursor = 0;
+    }
+
+    fn run<F: Fn(&mut f32, f32)>(&mut self, inputs: &[Vec<f32>], outputs: &mut [Vec<f32>], f: F) {
+        let delay = self.delay();
+        let mut frames = 0;
+        for ((line, input), output) in self.lines.iter_mut().zip(inputs).zip(outputs.iter_mut()) {
+            frames = input.len().min(output.len());
+            if delay == 0 {
+                for (output, sample) in output.iter_mut().zip(input.iter()) {
+                    f(output, *sample);
+                }
+                continue;
+            }
+            let mut cursor = self.cursor;
+            for (output, sample) in output.iter_mut().zip(input.iter()) {
+                f(output, line[cursor]);
+                line[cursor] = *sample;
+                cursor += 1;
+                if cursor == delay {
+                    cursor = 0;
+                }
+            }
+        }
+        if delay > 0 {
+            self.cursor = (self.cursor + frames) % delay;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_line() {
+        let mut delay = DelayLine::new(2, 3);
+        let mut outputs = vec![vec![1.0; 2]; 2];
+        delay.process(&[vec![1.0, 2.0], vec![-1.0, -2.0]], &mut outputs);
+        assert_eq!(outputs, vec![vec![0.0, 0.0], vec![0.0, 0.0]]);
+        delay.mix(&[vec![3.0, 4.0], vec![-3.0, -4.0]], &mut outputs);
+        assert_eq!(outputs, vec![vec![0.0, 1.0], vec![0.0, -1.0]]);
+        delay.process(&[vec![5.0, 6.0], vec![-5.0, -6.0]], &mut outputs);
+        assert_eq!(outputs, vec![vec![2.0, 3.0], vec![-2.0, -3.0]]);
+        delay.reset();
+        delay.process(&[vec![7.0, 8.0], vec![-7.0, -8.0]], &mut outputs);
+        assert_eq!(outputs, vec![vec![0.0, 0.0], vec![0.0, 0.0]]);
+
+        let mut bypass = DelayLine::new(1, 0);
+        let mut outputs = vec![vec![0.0; 2]];
+        bypass.mix(&[vec![1.0, 2.0]], &mut outputs);
+        assert_eq!(outputs, vec![vec![1.0, 2.0]]);
+        assert_eq!(bypass.delay(), 0);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::prelude::*;
+use crate::supervisor::delay::DelayLine;
+use crate::supervisor::linker::{
+    DeviceEntry, MidiEntry, SharedInput, SharedMidiInput, SharedMidiOutput, SharedOutput,
+};
+use std::sync::{Arc, Mutex};
+use vst::host::HostBuffer;
+
+/// A node piped into another one
+struct Source {
+    /// Position of the node in the graph
+    position: usize,
+    /// Delay compensating a branch with less latency than the others piped into the same input
+    delay: Option<DelayLine>,
+}
+
+/// A device of the graph scheduled for processing, with its preallocated buffers
+pub struct GraphNode {
+    id: DeviceId,
+    input_idx: Option<InputIndex>,
+    input: Option<SharedInput>,
+    output: Option<SharedOutput>,
+    /// Is the device owned by a vst plugin
+    vst_node: bool,
+    vst: Option<Arc<Mutex<VstPlugin>>>,
+    /// Every node piped into this one
+    sources: Vec<Source>,
+    /// Samples the device delays its inputs by, the latency of a plugin
+    initial_delay: usize,
+    /// Samples the output of the node is late by, from the sources of the graph
+    latency: usize,
+    /// Samples the device keeps producing once its inputs are silent, the tail of a plugin
+    tail_size: usize,
+    /// Samples the output of the node keeps playing once the sources of the graph ended
+    tail: usize,
+    /// Sum of all incoming samples
+    mix: Vec<Vec<f32>>,
+    /// Samples read by the nodes depending on this one
+    samples: Vec<Vec<f32>>,
+    buffer: HostBuffer<f32>,
+    /// Did the output device reach the end of its stream
+    ended: bool,
+    midi_input_idx: Option<MidiInputIndex>,
+    midi_input: Option<SharedMidiInput>,
+    midi_output: Option<SharedMidiOutput>,
+    /// Position of every node sending MIDI events to this one
+    midi_sources: Vec<usize>,
+    /// Incoming events of all the MIDI sources, sorted by position
+    midi_mix: MidiBuffer,
+    /// Events read by the nodes depending on this one
+    midi_events: MidiBuffer,
+    /// Did the MIDI output device reach the end of its stream
+    midi_ended: bool,
+}
+
+impl GraphNode {
+    pub fn new(id: DeviceId) -> Self {
+        Self {
+            id,
+            input_idx: None,
+            input: None,
+            output: None,
+            vst_node: false,
+            vst: None,
+            sources: Vec::new(),
+            initial_delay: 0,
+            latency: 0,
+            tail_size: 0,
+            tail: 0,
+            mix: Vec::new(),
+            samples: Vec::new(),
+            buffer: HostBuffer::new(0, 0),
+            ended: false,
+            midi_input_idx: None,
+            midi_input: None,
+            midi_output: None,
+            midi_sources: Vec::new(),
+            midi_mix: MidiBuffer::new(0),
+            midi_events: MidiBuffer::new(0),
+            midi_ended: false,
+        }
+    }
+
+    pub fn set_input(&mut self, idx: InputIndex, entry: &DeviceEntry<dyn SampleInput>) {
+        self.input_idx = Some(idx);
+        self.input = Some(entry.device.clone());
+        self.mix = vec![vec![0f32; entry.block_size]; entry.nbr_channel];
+    }
+
+    pub fn set_output(&mut self, entry: &DeviceEntry<dyn SampleOutput>) {
+        self.output = Some(entry.device.clone());
+        self.samples = vec![vec![0f32; entry.block_size]; entry.nbr_channel];
+    }
+
+    pub fn set_midi_input(&mut self, idx: MidiInputIndex, entry: &MidiEntry<dyn MidiInput>) {
+        self.midi_input_idx = Some(idx);
+        self.midi_input = Some(entry.device.clone());
+        self.midi_mix = MidiBuffer::default();
+    }
+
+    pub fn set_midi_output(&mut self, entry: &MidiEntry<dyn MidiOutput>) {
+        self.midi_output = Some(entry.device.clone());
+        self.midi_events = MidiBuffer::default();
+    }
+
+    /// Mark the node as a vst plugin node, a missing plugin output silence
+    pub fn set_vst(&mut self, plugin: Option<Arc<Mutex<VstPlugin>>>) {
+        self.vst_node = true;
+        self.vst = plugin;
+    }
+
+    /// Set the samples the device delays its inputs by, see `VstPlugin::get_latency`
+    pub fn set_initial_delay(&mut self, initial_delay: usize) {
+        self.initial_delay = initial_delay;
+    }
+
+    /// Set the samples the device keeps producing once its inputs are silent, see
+    /// `VstPlugin::get_tail_size`
+    pub fn set_tail_size(&mut self, tail_size: usize) {
+        self.tail_size = tail_size;
+    }
+
+    /// Add the node at `position` as a source, it must be processed before this one
+    pub fn add_source(&mut self, position: usize) {
+        self.sources.push(Source {
+            position,
+            delay: None,
+        });
+    }
+
+    /// Add the node at `position` as a MIDI source, it must be processed before this one
+    pub fn add_midi_source(&mut self, position: usize) {
+        self.midi_sources.push(position);
+    }
+
+    /// Is the node an output device that is not a plugin
+    fn is_source(&self) -> bool {
+        (self.output.is_some() || self.midi_output.is_some()) && !self.vst_node
+    }
+
+    /// Did every output of the device reach the end of its stream
+    fn is_ended(&self) -> bool {
+        (self.output.is_none() || self.ended) && (self.midi_output.is_none() || self.midi_ended)
+    }
+
+    fn process(
+        &mut self,
+        previous: &[GraphNode],
+        transport: Option<&Transport>,
+        tempo_map: &TempoMap,
+    ) {
+        if self.input.is_some() {
+            for channel in self.mix.iter_mut() {
+                silence(channel);
+            }
+            for source in self.sources.iter_mut() {
+                let samples = &previous[source.position].samples;
+                if let Some(delay) = source.delay.as_mut() {
+                    delay.mix(samples, &mut self.mix);
+                    continue;
+                }
+                for (mix, samples) in self.mix.iter_mut().zip(samples.iter()) {
+                    for (mix, sample) in mix.iter_mut().zip(samples.iter()) {
+                        *mix += *sample;
+                    }
+                }
+            }
+        }
+        if self.midi_input.is_some() {
+            self.midi_mix.clear();
+            for source in self.midi_sources.iter() {
+                self.midi_mix.extend_from(&previous[*source].midi_events);
+            }
+            self.midi_mix.sort();
+        }
+        if self.vst_node {
+            // The plugin may be locked by the UI thread, in this case the block is skipped
+            let plugin = self.vst.as_ref().and_then(|plugin| plugin.try_lock().ok());
+            match plugin {
+                Some(mut plugin) if plugin.is_active() => {
+                    if !self.midi_mix.is_empty() {
+                        plugin.process_events(self.midi_mix.events());
+                    }
+                    plugin.next(
+                        &self.mix,
+                        &mut self.samples,
+                        &mut self.buffer,
+                        transport,
+                        tempo_map,
+                    );
+                    self.midi_events.clear();
+                    plugin.drain_midi_output(&mut self.midi_events);
+                }
+                _ => {
+                    self.samples.iter_mut().for_each(|channel| silence(channel));
+                    self.midi_events.clear();
+                }
+            }
+            return;
+        }
+        if let Some(input) = self.input.as_ref() {
+            if let Ok(mut input) = input.try_lock() {
+                for (channel, samples) in self.mix.iter().enumerate() {
+                    input.next(samples, channel);
+                }
+            }
+        }
+        if let Some(output) = self.output.as_ref() {
+            match output.try_lock() {
+                Ok(mut output) => {
+                    let mut ended = true;
+                    for (channel, samples) in self.samples.iter_mut().enumerate() {
+                        if output.next(samples, channel) {
+                            ended = false;
+                        } else {
+                            silence(samples);
+                        }
+                    }
+                    self.ended = ended;
+                }
+                Err(_) => self.samples.iter_mut().for_each(|channel| silence(channel)),
+            }
+        }
+        if let Some(input) = self.midi_input.as_ref() {
+            if let Ok(mut input) = input.try_lock() {
+                input.next(self.midi_mix.events());
+            }
+        }
+        if let Some(output) = self.midi_output.as_ref() {
+            self.midi_events.clear();
+            if let Ok(mut output) = output.try_lock() {
+                self.midi_ended = !output.next(&mut self.midi_events, transport, tempo_map);
+            }
+        }
+    }
+}
+
+fn silence(samples: &mut [f32]) {
+    samples.iter_mut().for_each(|sample| *sample = 0.0);
+}
+
+/// A compiled linker graph, every device is processed once per block after all the devices
+/// it depends on, samples piped into the same input are summed and MIDI events are merged.
+///
+/// The latency of the plugins is compensated: the samples of the branches with less latency
+/// are delayed before being summed, so every input receives aligned samples. MIDI events are
+/// not delayed.
+///
+/// Buffers are allocated when the graph is compiled and devices are only `try_lock`ed
+/// so processing never blocks nor allocates.
+pub struct Graph {
+    nodes: Vec<GraphNode>,
+    /// Position of the next block, the plugins automation and the MIDI sequences are only
+    /// played with a transport
+    transport: Option<Transport>,
+    /// Map followed by the transport
+    tempo_map: TempoMap,
+}
+
+impl Graph {
+    /// Create a graph from nodes sorted in dependency order
+    pub fn new(mut nodes: Vec<GraphNode>) -> Self {
+        for position in 0..nodes.len() {
+            let (previous, next) = nodes.split_at_mut(position);
+            let node = &mut next[0];
+            node.buffer = HostBuffer::new(node.mix.len(), node.samples.len());
+            let latency = node
+                .sources
+                .iter()
+                .map(|source| previous[source.position].latency)
+                .max()
+                .unwrap_or(0);
+            for source in node.sources.iter_mut() {
+                let delay = latency - previous[source.position].latency;
+                if delay > 0 {
+                    source.delay = Some(DelayLine::new(node.mix.len(), delay));
+                }
+            }
+            node.latency = latency + node.initial_delay;
+            let tail = node
+                .sources
+                .iter()
+                .map(|source| previous[source.position].tail)
+                .max()
+                .unwrap_or(0);
+            node.tail = tail + node.tail_size;
+        }
+        Self {
+            nodes,
+            transport: None,
+            tempo_map: TempoMap::default(),
+        }
+    }
+
+    /// Set the transport of the next block with the map it follows, the map is copied
+    /// without allocating unless it grew
+    pub fn set_transport(&mut self, transport: Transport, tempo_map: &TempoMap) {
+        self.transport = Some(transport);
+        self.tempo_map.clone_from(tempo_map);
+    }
+
+    /// Process one block of the whole graph
+    pub fn process(&mut self) {
+        for position in 0..self.nodes.len() {
+            let (previous, next) = self.nodes.split_at_mut(position);
+            next[0].process(previous, self.transport.as_ref(), &self.tempo_map);
+        }
+    }
+
+    /// Get the samples received by an input device during the last block
+    pub fn input_samples(&self, idx: InputIndex) -> Option<&[Vec<f32>]> {
+        self.nodes
+            .iter()
+            .find(|node| node.input_idx == Some(idx))
+            .map(|node| &node.mix[..])
+    }
+
+    /// Get the samples the input of a device is late by, the latency of the longest branch
+    /// piped into it
+    pub fn input_latency(&self, idx: InputIndex) -> Option<usize> {
+        self.nodes
+            .iter()
+            .find(|node| node.input_idx == Some(idx))
+            .map(|node| node.latency - node.initial_delay)
+    }
+
+    /// Get the samples an input device keeps receiving once the sources ended, the longest
+    /// tail of the plugin chains piped into it
+    pub fn input_tail(&self, idx: InputIndex) -> Option<usize> {
+        self.nodes
+            .iter()
+            .find(|node| node.input_idx == Some(idx))
+            .map(|node| node.tail - node.tail_size)
+    }
+
+    /// Get the events received by a MIDI input device during the last block
+    pub fn midi_input_events(&self, idx: MidiInputIndex) -> Option<&[MidiEvent]> {
+        self.nodes
+            .iter()
+            .find(|node| node.midi_input_idx == Some(idx))
+            .map(|node| node.midi_mix.events())
+    }
+
+    /// Get the output devices that reached the end of their stream during the last block
+    pub fn ended_outputs(&self) -> impl Iterator<Item = DeviceId> + '_ {
+        self.nodes
+            .iter()
+            .filter(|node| node.is_source() && node.is_ended())
+            .map(|node| node.id)
+    }
+
+    /// Is the end of stream reached by every output device, plugins are not taken into account
+    pub fn is_finished(&self) -> bool {
+        self.nodes
+            .iter()
+            .filter(|node| node.is_source())
+            .all(|node| node.is_ended())
+    }
+
+    /// Get the devices identifiers in processing order
+    pub fn get_schedule(&self) -> Vec<DeviceId> {
+        self.nodes.iter().map(|node| node.id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{AssetSampleOutput, MasterSample};
+    use crate::loader::asset::AudioAsset;
+    use generational_arena::Index;
+
+    /// Output device playing a constant
+    fn constant_entry(value: f32) -> DeviceEntry<dyn SampleOutput> {
+        let device: Box<dyn SampleOutput> = Box::new(AssetSampleOutput::new(
+            AudioAsset::new(vec![vec![value; 8]; 2], 48000),
+            4,
+            48000,
+        ));
+        DeviceEntry {
+            id: device.id(),
+            nbr_channel: 2,
+            block_size: 4,
+            parent_vst: None,
+            device: Arc::new(Mutex::new(device)),
+        }
+    }
+
+    /// Node playing a constant, late by `initial_delay` like a synth with look-ahead
+    fn constant(value: f32, initial_delay: usize) -> GraphNode {
+        let entry = constant_entry(value);
+        let mut node = GraphNode::new(entry.id);
+        node.set_output(&entry);
+        node.set_initial_delay(initial_delay);
+        node
+    }
+
+    /// Node mixing the nodes at `sources` into a master input
+    fn master(idx: InputIndex, sources: &[usize]) -> GraphNode {
+        let device: Box<dyn SampleInput> = Box::new(MasterSample::new(4, 2));
+        let entry = DeviceEntry {
+            id: device.id(),
+            nbr_channel: 2,
+            block_size: 4,
+            parent_vst: None,
+            device: Arc::new(Mutex::new(device)),
+        };
+        let mut master = GraphNode::new(entry.id);
+        master.set_input(idx, &entry);
+        for source in sources {
+            master.add_source(*source);
+        }
+        master
+    }
+
+    #[test]
+    fn latency_compensation() {
+        let master_idx = InputIndex::from(Index::from_raw_parts(0, 0));
+        let master = master(master_idx, &[0, 1]);
+        let mut graph = Graph::new(vec![constant(0.25, 3), constant(0.5, 0), master]);
+        assert_eq!(graph.input_latency(master_idx), Some(3));
+        graph.process();
+        let first = vec![0.25, 0.25, 0.25, 0.75];
+        assert_eq!(
+            graph.input_samples(master_idx).unwrap(),
+            &[first.clone(), first][..]
+        );
+        graph.process();
+        assert_eq!(
+            graph.input_samples(master_idx).unwrap(),
+            &[vec![0.75; 4], vec![0.75; 4]][..]
+        );
+    }
+
+    #[test]
+    fn tail_along_paths() {
+        let master_idx = InputIndex::from(Index::from_raw_parts(0, 0));
+        let mut source = constant(0.0, 0);
+        source.set_tail_size(2);
+        // A device both reading the source and playing, like an effect plugin
+        let effect_idx = InputIndex::from(Index::from_raw_parts(1, 0));
+        let mut effect = master(effect_idx, &[0]);
+        effect.set_output(&constant_entry(0.0));
+        effect.set_tail_size(3);
+        let mut parallel = constant(0.0, 0);
+        parallel.set_tail_size(4);
+        let graph = Graph::new(vec![source, effect, parallel, master(master_idx, &[1, 2])]);
+        assert_eq!(graph.input_tail(effect_idx), Some(2));
+        assert_eq!(graph.input_tail(master_idx), Some(5));
+    }
+}
@@ -1,9 +1,9 @@
 use crate::prelude::*;
+use crate::supervisor::graph::{Graph, GraphNode};
+use crate::supervisor::midi::MidiBuffer;
 use generational_arena::{Arena, Index};
-use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock};
-use vst::buffer::AudioBuffer;
-use vst::host::HostBuffer;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, RwLock};
 
 #[derive(Debug, Fail)]
 pub enum LinkerError {
@@ -17,6 +17,14 @@ pub enum LinkerError {
     PipeBufferMalformated,
     #[fail(display = "Pipe must get the same number of inputs and outputs")]
     PipeWrongIO,
+    #[fail(display = "Invalide MIDI input index: {:?}", _0)]
+    InvalideMidiInput(MidiInputIndex),
+    #[fail(display = "Invalide MIDI output index: {:?}", _0)]
+    InvalideMidiOutput(MidiOutputIndex),
+    #[fail(display = "Invalide MIDI pipe index: {:?}", _0)]
+    InvalideMidiPipe(MidiPipeIndex),
+    #[fail(display = "The graph contains a cycle through device {:?}", _0)]
+    Cycle(DeviceId),
 }
 
 /// Index of an allocated input device int the linker arena
@@ -46,6 +54,33 @@ impl From<Index> for PipeIndex {
     }
 }
 
+/// Index of an allocated MIDI input device int the linker arena
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
+pub struct MidiInputIndex(Index);
+impl From<Index> for MidiInputIndex {
+    fn from(idx: Index) -> Self {
+        Self(idx)
+    }
+}
+
+/// Index of an allocated MIDI output device int the linker arena
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
+pub struct MidiOutputIndex(Index);
+impl From<Index> for MidiOutputIndex {
+    fn from(idx: Index) -> Self {
+        Self(idx)
+    }
+}
+
+/// Index of an allocated pipe of two MIDI device int the linker arena
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Ord, PartialOrd)]
+pub struct MidiPipeIndex(Index);
+impl From<Index> for MidiPipeIndex {
+    fn from(idx: Index) -> Self {
+        Self(idx)
+    }
+}
+
 /// Unique identifier of an I/O device
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
 pub struct DeviceId(pub u64);
@@ -64,7 +99,7 @@ pub fn new_id() -> u64 {
     }
 }
 
-pub trait SampleDevice {
+pub trait SampleDevice: Send {
     /// Get the sample block size
     fn block_size(&self) -> usize;
 
@@ -88,22 +123,121 @@ pub trait SampleInput: SampleDevice {
 }
 
 pub trait SampleOutput: SampleDevice {
-    fn next(&self, channel: usize) -> Option<std::sync::RwLockReadGuard<Vec<f32>>> {
-        unimplemented!()
+    /// Fill `buffer` with the next samples of `channel`,
+    /// returns false when there is no samples left
+    fn next(&mut self, buffer: &mut [f32], channel: usize) -> bool;
+}
+
+/// A device exchanging MIDI events, a device can be both a sample and a MIDI device when
+/// both return the same `id`
+pub trait MidiDevice: Send {
+    /// Get the block size, in samples, the events positions are relative to
+    fn block_size(&self) -> usize;
+
+    /// Get unique identifier
+    fn id(&self) -> DeviceId;
+
+    /// If the device was loaded from a VST parent_vst must return his instance id,
+    /// the events it receives are then sent to the plugin before each `process`
+    fn parent_vst(&self) -> Option<VstId> {
+        None
     }
 }
 
+pub trait MidiInput: MidiDevice {
+    /// Receive the events of the block, sorted by `delta_frames`
+    fn next(&mut self, _events: &[MidiEvent]) {}
+}
+
+pub trait MidiOutput: MidiDevice {
+    /// Push the events of the next block into `events`, their `delta_frames` is the position
+    /// in the block. Returns false when there is no events left
+    ///
+    /// # Parameters
+    ///
+    /// * `events` Receives the events of the block
+    /// * `transport` Position of the block, `None` when the graph is played without one
+    /// * `tempo_map` Map followed by the transport
+    fn next(
+        &mut self,
+        events: &mut MidiBuffer,
+        transport: Option<&Transport>,
+        tempo_map: &TempoMap,
+    ) -> bool;
+}
+
+/// Input device shared between the linker and the compiled graphs
+pub type SharedInput = Arc<Mutex<Box<dyn SampleInput>>>;
+
+/// Output device shared between the linker and the compiled graphs
+pub type SharedOutput = Arc<Mutex<Box<dyn SampleOutput>>>;
+
+/// MIDI input device shared between the linker and the compiled graphs
+pub type SharedMidiInput = Arc<Mutex<Box<dyn MidiInput>>>;
+
+/// MIDI output device shared between the linker and the compiled graphs
+pub type SharedMidiOutput = Arc<Mutex<Box<dyn MidiOutput>>>;
+
 pub struct SamplePipe {
     inputs: InputIndex,
     outputs: OutputIndex,
-    buffer: HostBuffer<f32>,
+}
+
+pub struct MidiPipe {
+    inputs: MidiInputIndex,
+    outputs: MidiOutputIndex,
+}
+
+/// A registered device with its properties, they are read once
+/// so editing the graph never locks a device used by the audio thread
+pub struct DeviceEntry<T: ?Sized> {
+    pub id: DeviceId,
+    pub nbr_channel: usize,
+    pub block_size: usize,
+    pub parent_vst: Option<VstId>,
+    pub device: Arc<Mutex<Box<T>>>,
+}
+
+impl<T: SampleDevice + ?Sized> DeviceEntry<T> {
+    fn new(device: Box<T>) -> Self {
+        Self {
+            id: device.id(),
+            nbr_channel: device.nbr_channel(),
+            block_size: device.block_size(),
+            parent_vst: device.parent_vst(),
+            device: Arc::new(Mutex::new(device)),
+        }
+    }
+}
+
+/// A registered MIDI device, see `DeviceEntry`
+pub struct MidiEntry<T: ?Sized> {
+    pub id: DeviceId,
+    pub block_size: usize,
+    pub parent_vst: Option<VstId>,
+    pub device: Arc<Mutex<Box<T>>>,
+}
+
+impl<T: MidiDevice + ?Sized> MidiEntry<T> {
+    fn new(device: Box<T>) -> Self {
+        Self {
+            id: device.id(),
+            block_size: device.block_size(),
+            parent_vst: device.parent_vst(),
+            device: Arc::new(Mutex::new(device)),
+        }
+    }
 }
 
 pub struct Linker {
-    output_devices: Arena<Box<dyn SampleOutput>>,
-    input_devices: Arena<Box<dyn SampleInput>>,
+    output_devices: Arena<DeviceEntry<dyn SampleOutput>>,
+    input_devices: Arena<DeviceEntry<dyn SampleInput>>,
     pipes: Arena<SamplePipe>,
-    sequences: BTreeMap<PipeIndex, PipeIndex>,
+    midi_outputs: Arena<MidiEntry<dyn MidiOutput>>,
+    midi_inputs: Arena<MidiEntry<dyn MidiInput>>,
+    midi_pipes: Arena<MidiPipe>,
+    /// Devices in dependency order
+    schedule: Vec<DeviceId>,
 }
 
 impl Linker {
@@ -112,63 +246,252 @@ impl Linker {
             output_devices: Arena::new(),
             input_devices: Arena::new(),
             pipes: Arena::new(),
-            sequences: BTreeMap::new(),
+            midi_outputs: Arena::new(),
+            midi_inputs: Arena::new(),
+            midi_pipes: Arena::new(),
+            schedule: Vec::new(),
         }
     }
 
-    pub fn get_next(&self, actual: PipeIndex) -> Option<PipeIndex> {
-        self.sequences.get(&actual).map(|e| *e)
-    }
-
     pub fn register_input(&mut self, input: Box<dyn SampleInput>) -> InputIndex {
-        self.input_devices.insert(input).into()
+        let idx = self.input_devices.insert(DeviceEntry::new(input)).into();
+        self.calc_schedule()
+            .expect("A new device can't create a cycle");
+        idx
     }
 
     pub fn register_output(&mut self, output: Box<dyn SampleOutput>) -> OutputIndex {
-        self.output_devices.insert(output).into()
+        let idx = self.output_devices.insert(DeviceEntry::new(output)).into();
+        self.calc_schedule()
+            .expect("A new device can't create a cycle");
+        idx
+    }
+
+    /// Remove an input device and every pipe connected to it
+    pub fn unregister_input(&mut self, idx: InputIndex) -> Result<SharedInput, LinkerError> {
+        let device = self
+            .input_devices
+            .remove(idx.0)
+            .ok_or(LinkerError::InvalideInput(idx))?;
+        self.pipes.retain(|_, pipe| pipe.inputs != idx);
+        self.calc_schedule()
+            .expect("Removing a device can't create a cycle");
+        Ok(device.device)
+    }
+
+    /// Remove an output device and every pipe connected to it
+    pub fn unregister_output(&mut self, idx: OutputIndex) -> Result<SharedOutput, LinkerError> {
+        let device = self
+            .output_devices
+            .remove(idx.0)
+            .ok_or(LinkerError::InvalideOutput(idx))?;
+        self.pipes.retain(|_, pipe| pipe.outputs != idx);
+        self.calc_schedule()
+            .expect("Removing a device can't create a cycle");
+        Ok(device.device)
+    }
+
+    pub fn register_midi_input(&mut self, input: Box<dyn MidiInput>) -> MidiInputIndex {
+        let idx = self.midi_inputs.insert(MidiEntry::new(input)).into();
+        self.calc_schedule()
+            .expect("A new device can't create a cycle");
+        idx
+    }
+
+    pub fn register_midi_output(&mut self, output: Box<dyn MidiOutput>) -> MidiOutputIndex {
+        let idx = self.midi_outputs.insert(MidiEntry::new(output)).into();
+        self.calc_schedule()
+            .expect("A new device can't create a cycle");
+        idx
+    }
+
+    /// Remove a MIDI input device and every MIDI pipe connected to it
+    pub fn unregister_midi_input(
+        &mut self,
+        idx: MidiInputIndex,
+    ) -> Result<SharedMidiInput, LinkerError> {
+        let device = self
+            .midi_inputs
+            .remove(idx.0)
+            .ok_or(LinkerError::InvalideMidiInput(idx))?;
+        self.midi_pipes.retain(|_, pipe| pipe.inputs != idx);
+        self.calc_schedule()
+            .expect("Removing a device can't create a cycle");
+        Ok(device.device)
+    }
+
+    /// Remove a MIDI output device and every MIDI pipe connected to it
+    pub fn unregister_midi_output(
+        &mut self,
+        idx: MidiOutputIndex,
+    ) -> Result<SharedMidiOutput, LinkerError> {
+        let device = self
+            .midi_outputs
+            .remove(idx.0)
+            .ok_or(LinkerError::InvalideMidiOutput(idx))?;
+        self.midi_pipes.retain(|_, pipe| pipe.outputs != idx);
+        self.calc_schedule()
+            .expect("Removing a device can't create a cycle");
+        Ok(device.device)
     }
 
     pub fn get_pipe<'a>(&'a mut self, idx: PipeIndex) -> Option<&'a mut SamplePipe> {
         self.pipes.get_mut(idx.0)
     }
 
-    pub fn bind<'a, F: (FnMut(AudioBuffer<f32>, Option<VstId>))>(
-        &'a mut self,
-        idx: PipeIndex,
-        tmp: &mut [&mut [f32]],
-        mut process: F,
-    ) -> Result<Option<PipeIndex>, LinkerError> {
-        let inputs = &mut self.input_devices;
-        let outputs = &mut self.output_devices;
-        let pipe = self
-            .pipes
-            .get_mut(idx.0)
-            .ok_or(LinkerError::InvalidePipe(idx))?;
-        let out_ins = &outputs[pipe.outputs.0];
-        let in_ins = &mut inputs[pipe.inputs.0];
-        let vst = in_ins.parent_vst();
-        let out_left = out_ins.next(0).expect("EOF");
-        let out_right = out_ins.next(1).expect("EOF");
-        let outputs = &[&out_left[0..out_left.len()], &out_right[0..out_right.len()]];
-        let binding = pipe.buffer.bind(outputs, tmp);
-        process(binding, vst);
-        in_ins.next(tmp[0], 0);
-        in_ins.next(tmp[1], 1);
-        let next = self.sequences.get(&idx).map(|e| *e);
-        Ok(next)
-    }
-
-    fn calc_sequences(&mut self) {
-        self.sequences.clear();
-        for (this, this_pipe) in self.pipes.iter() {
-            let this_id = self.output_devices[this_pipe.inputs.0].id();
-            for (other, other_pipe) in self.pipes.iter() {
-                let other_id = self.input_devices[other_pipe.outputs.0].id();
-                if this_id == other_id {
-                    self.sequences.insert(this.into(), other.into());
+    /// Get the outputs piped into `input`
+    pub fn get_sources(&self, input: InputIndex) -> Vec<OutputIndex> {
+        self.pipes
+            .iter()
+            .filter(|(_, pipe)| pipe.inputs == input)
+            .map(|(_, pipe)| pipe.outputs)
+            .collect()
+    }
+
+    /// Get the MIDI outputs piped into `input`
+    pub fn get_midi_sources(&self, input: MidiInputIndex) -> Vec<MidiOutputIndex> {
+        self.midi_pipes
+            .iter()
+            .filter(|(_, pipe)| pipe.inputs == input)
+            .map(|(_, pipe)| pipe.outputs)
+            .collect()
+    }
+
+    /// Get every pipe with the output and the input it connects
+    pub fn get_pipes(&self) -> Vec<(PipeIndex, OutputIndex, InputIndex)> {
+        self.pipes
+            .iter()
+            .map(|(idx, pipe)| (idx.into(), pipe.outputs, pipe.inputs))
+            .collect()
+    }
+
+    /// Get every MIDI pipe with the output and the input it connects
+    pub fn get_midi_pipes(&self) -> Vec<(MidiPipeIndex, MidiOutputIndex, MidiInputIndex)> {
+        self.midi_pipes
+            .iter()
+            .map(|(idx, pipe)| (idx.into(), pipe.outputs, pipe.inputs))
+            .collect()
+    }
+
+    /// Get the devices identifiers in processing order
+    pub fn get_schedule(&self) -> Vec<DeviceId> {
+        self.schedule.clone()
+    }
+
+    /// Build a graph that can be processed independently of the linker,
+    /// it keeps a handle on every device and vst plugin it needs. The latency of the plugins
+    /// is read once, the graph must be compiled again when it changes
+    ///
+    /// # Parameters
+    ///
+    /// * `plugins` The loaded plugins, devices belonging to a missing plugin output silence
+    pub fn compile(
+        &self,
+        plugins: &BTreeMap<VstId, Arc<Mutex<VstPlugin>>>,
+    ) -> Result<Graph, LinkerError> {
+        let mut nodes: Vec<GraphNode> =
+            self.schedule.iter().map(|id| GraphNode::new(*id)).collect();
+        let position: BTreeMap<DeviceId, usize> = self
+            .schedule
+            .iter()
+            .enumerate()
+            .map(|(position, id)| (*id, position))
+            .collect();
+        for (idx, entry) in self.input_devices.iter() {
+            let node = &mut nodes[position[&entry.id]];
+            node.set_input(idx.into(), entry);
+            if let Some(vst) = entry.parent_vst {
+                let plugin = plugins.get(&vst).cloned();
+                if let Some(plugin) = plugin.as_ref() {
+                    let plugin = plugin.lock().unwrap();
+                    node.set_initial_delay(plugin.get_latency());
+                    node.set_tail_size(plugin.get_tail_size());
                 }
+                node.set_vst(plugin);
             }
         }
+        for (_, entry) in self.output_devices.iter() {
+            nodes[position[&entry.id]].set_output(entry);
+        }
+        for (idx, entry) in self.midi_inputs.iter() {
+            let node = &mut nodes[position[&entry.id]];
+            node.set_midi_input(idx.into(), entry);
+            if let Some(vst) = entry.parent_vst {
+                node.set_vst(plugins.get(&vst).cloned());
+            }
+        }
+        for (_, entry) in self.midi_outputs.iter() {
+            nodes[position[&entry.id]].set_midi_output(entry);
+        }
+        for (_, pipe) in self.pipes.iter() {
+            let from = self.output_devices[pipe.outputs.0].id;
+            let to = self.input_devices[pipe.inputs.0].id;
+            nodes[position[&to]].add_source(position[&from]);
+        }
+        for (_, pipe) in self.midi_pipes.iter() {
+            let from = self.midi_outputs[pipe.outputs.0].id;
+            let to = self.midi_inputs[pipe.inputs.0].id;
+            nodes[position[&to]].add_midi_source(position[&from]);
+        }
+        Ok(Graph::new(nodes))
+    }
+
+    /// Sort the devices topologically from the pipes
+    fn calc_schedule(&mut self) -> Result<(), LinkerError> {
+        let mut nodes: BTreeSet<DeviceId> = BTreeSet::new();
+        for (_, entry) in self.input_devices.iter() {
+            nodes.insert(entry.id);
+        }
+        for (_, entry) in self.output_devices.iter() {
+            nodes.insert(entry.id);
+        }
+        for (_, entry) in self.midi_inputs.iter() {
+            nodes.insert(entry.id);
+        }
+        for (_, entry) in self.midi_outputs.iter() {
+            nodes.insert(entry.id);
+        }
+        let mut edges: BTreeMap<DeviceId, BTreeSet<DeviceId>> = BTreeMap::new();
+        let mut dependencies: BTreeMap<DeviceId, usize> = BTreeMap::new();
+        let sample_edges = self.pipes.iter().map(|(_, pipe)| {
+            (
+                self.output_devices[pipe.outputs.0].id,
+                self.input_devices[pipe.inputs.0].id,
+            )
+        });
+        let midi_edges = self.midi_pipes.iter().map(|(_, pipe)| {
+            (
+                self.midi_outputs[pipe.outputs.0].id,
+                self.midi_inputs[pipe.inputs.0].id,
+            )
+        });
+        for (from, to) in sample_edges.chain(midi_edges) {
+            if edges.entry(from).or_default().insert(to) {
+                *dependencies.entry(to).or_default() += 1;
+            }
+        }
+        let mut ready: Vec<DeviceId> = nodes
+            .iter()
+            .filter(|id| !dependencies.contains_key(id))
+            .cloned()
+            .collect();
+        let mut schedule = Vec::with_capacity(nodes.len());
+        while let Some(id) = ready.pop() {
+            nodes.remove(&id);
+            schedule.push(id);
+            for next in edges.get(&id).into_iter().flatten() {
+                let count = dependencies.get_mut(next).expect("Dependency count");
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(*next);
+                }
+            }
+        }
+        if let Some(id) = nodes.iter().next() {
+            return Err(LinkerError::Cycle(*id));
+        }
+        self.schedule = schedule;
+        Ok(())
     }
 
     pub fn pipe(
@@ -184,13 +507,284 @@ impl Linker {
             .output_devices
             .get(output_idx.0)
             .ok_or(LinkerError::InvalideOutput(output_idx))?;
-        let buffer = HostBuffer::new(inputs.nbr_channel(), outputs.nbr_channel());
+        if inputs.block_size != outputs.block_size {
+            return Err(LinkerError::PipeBufferMalformated);
+        }
         let pipe = self.pipes.insert(SamplePipe {
-            buffer,
             inputs: input_idx,
             outputs: output_idx,
         });
-        self.calc_sequences();
+        if let Err(err) = self.calc_schedule() {
+            self.pipes.remove(pipe);
+            return Err(err);
+        }
+        Ok(pipe.into())
+    }
+
+    /// Remove a pipe, both devices stay registered
+    pub fn unpipe(&mut self, idx: PipeIndex) -> Result<(), LinkerError> {
+        self.pipes
+            .remove(idx.0)
+            .ok_or(LinkerError::InvalidePipe(idx))?;
+        self.calc_schedule()
+            .expect("Removing a pipe can't create a cycle");
+        Ok(())
+    }
+
+    /// Send the events of a MIDI output to a MIDI input, the events of every output piped
+    /// into the same input are merged
+    pub fn pipe_midi(
+        &mut self,
+        output_idx: MidiOutputIndex,
+        input_idx: MidiInputIndex,
+    ) -> Result<MidiPipeIndex, LinkerError> {
+        let inputs = self
+            .midi_inputs
+            .get(input_idx.0)
+            .ok_or(LinkerError::InvalideMidiInput(input_idx))?;
+        let outputs = self
+            .midi_outputs
+            .get(output_idx.0)
+            .ok_or(LinkerError::InvalideMidiOutput(output_idx))?;
+        if inputs.block_size != outputs.block_size {
+            return Err(LinkerError::PipeBufferMalformated);
+        }
+        let pipe = self.midi_pipes.insert(MidiPipe {
+            inputs: input_idx,
+            outputs: output_idx,
+        });
+        if let Err(err) = self.calc_schedule() {
+            self.midi_pipes.remove(pipe);
+            return Err(err);
+        }
         Ok(pipe.into())
     }
+
+    /// Remove a MIDI pipe, both devices stay registered
+    pub fn unpipe_midi(&mut self, idx: MidiPipeIndex) -> Result<(), LinkerError> {
+        self.midi_pipes
+            .remove(idx.0)
+            .ok_or(LinkerError::InvalideMidiPipe(idx))?;
+        self.calc_schedule()
+            .expect("Removing a pipe can't create a cycle");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{AssetSampleOutput, MasterSample, MidiSequenceOutput};
+    use crate::loader::asset::AudioAsset;
+
+    /// Device forwarding its input to its output
+    #[derive(Clone)]
+    struct Bus {
+        id: DeviceId,
+        buffer: Arc<Mutex<Vec<Vec<f32>>>>,
+    }
+
+    impl Bus {
+        fn new(size: usize) -> Self {
+            Self {
+                id: DeviceId(new_id()),
+                buffer: Arc::new(Mutex::new(vec![vec![0f32; size]; 2])),
+            }
+        }
+    }
+
+    impl SampleDevice for Bus {
+        fn block_size(&self) -> usize {
+            self.buffer.lock().unwrap()[0].len()
+        }
+
+        fn nbr_channel(&self) -> usize {
+            self.buffer.lock().unwrap().len()
+        }
+
+        fn id(&self) -> DeviceId {
+            self.id
+        }
+    }
+
+    impl SampleInput for Bus {
+        fn next(&mut self, buffer: &[f32], channel: usize) {
+            self.buffer.lock().unwrap()[channel].copy_from_slice(buffer);
+        }
+    }
+
+    impl SampleOutput for Bus {
+        fn next(&mut self, buffer: &mut [f32], channel: usize) -> bool {
+            buffer.copy_from_slice(&self.buffer.lock().unwrap()[channel]);
+            true
+        }
+    }
+
+    fn constant(value: f32, size: usize) -> Box<AssetSampleOutput> {
+        Box::new(AssetSampleOutput::new(
+            AudioAsset::new(vec![vec![value; size]; 2], 48000),
+            size,
+            48000,
+        ))
+    }
+
+    #[test]
+    fn fan_in_fan_out() {
+        let mut linker = Linker::new();
+        let bus = Bus::new(4);
+        let first = linker.register_output(constant(0.25, 4));
+        let second = linker.register_output(constant(0.5, 4));
+        let bus_input = linker.register_input(Box::new(bus.clone()));
+        let bus_output = linker.register_output(Box::new(bus));
+        let left = linker.register_input(Box::new(MasterSample::new(4, 2)));
+        let right = linker.register_input(Box::new(MasterSample::new(4, 2)));
+        linker.pipe(bus_output, left).unwrap();
+        linker.pipe(bus_output, right).unwrap();
+        linker.pipe(first, bus_input).unwrap();
+        linker.pipe(second, bus_input).unwrap();
+        let mut graph = linker.compile(&BTreeMap::new()).unwrap();
+        graph.process();
+        for input in [left, right].iter() {
+            assert_eq!(
+                graph.input_samples(*input).unwrap(),
+                &[vec![0.75; 4], vec![0.75; 4]][..]
+            );
+        }
+    }
+
+    #[test]
+    fn unpipe_and_unregister() {
+        let mut linker = Linker::new();
+        let first = linker.register_output(constant(0.25, 4));
+        let second = linker.register_output(constant(0.5, 4));
+        let master = linker.register_input(Box::new(MasterSample::new(4, 2)));
+        let pipe = linker.pipe(first, master).unwrap();
+        linker.pipe(second, master).unwrap();
+        linker.unpipe(pipe).unwrap();
+        assert!(linker.unpipe(pipe).is_err());
+        let mut graph = linker.compile(&BTreeMap::new()).unwrap();
+        graph.process();
+        assert_eq!(
+            graph.input_samples(master).unwrap(),
+            &[vec![0.5; 4], vec![0.5; 4]][..]
+        );
+        linker.unregister_output(second).unwrap();
+        assert!(linker.unregister_output(second).is_err());
+        let mut graph = linker.compile(&BTreeMap::new()).unwrap();
+        graph.process();
+        assert_eq!(
+            graph.input_samples(master).unwrap(),
+            &[vec![0.0; 4], vec![0.0; 4]][..]
+        );
+        linker.unregister_input(master).unwrap();
+        assert_eq!(linker.get_schedule().len(), 1);
+    }
+
+    #[test]
+    fn cycle() {
+        let mut linker = Linker::new();
+        let first = Bus::new(4);
+        let second = Bus::new(4);
+        let first_input = linker.register_input(Box::new(first.clone()));
+        let first_output = linker.register_output(Box::new(first));
+        let second_input = linker.register_input(Box::new(second.clone()));
+        let second_output = linker.register_output(Box::new(second));
+        linker.pipe(first_output, second_input).unwrap();
+        match linker.pipe(second_output, first_input) {
+            Err(LinkerError::Cycle(_)) => {}
+            _ => panic!("Cycle not detected"),
+        }
+        assert_eq!(linker.get_schedule().len(), 2);
+        linker.compile(&BTreeMap::new()).unwrap().process();
+    }
+
+    /// MIDI device keeping the events it receives
+    struct MidiRecorder {
+        id: DeviceId,
+        events: Arc<Mutex<Vec<(i32, [u8; 3])>>>,
+    }
+
+    impl MidiDevice for MidiRecorder {
+        fn block_size(&self) -> usize {
+            4
+        }
+
+        fn id(&self) -> DeviceId {
+            self.id
+        }
+    }
+
+    impl MidiInput for MidiRecorder {
+        fn next(&mut self, events: &[MidiEvent]) {
+            let mut recorded = self.events.lock().unwrap();
+            recorded.extend(events.iter().map(|event| (event.delta_frames, event.data)));
+        }
+    }
+
+    #[test]
+    fn midi_routing() {
+        let mut linker = Linker::new();
+        let notes = linker.register_midi_output(Box::new(MidiSequenceOutput::new(
+            vec![
+                (5.0, midi_event(0, [0x80, 60, 0])),
+                (1.0, midi_event(0, [0x90, 60, 100])),
+            ],
+            4,
+        )));
+        let controls = linker.register_midi_output(Box::new(MidiSequenceOutput::new(
+            vec![(0.0, midi_event(0, [0xB0, 7, 100]))],
+            4,
+        )));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorder = linker.register_midi_input(Box::new(MidiRecorder {
+            id: DeviceId(new_id()),
+            events: events.clone(),
+        }));
+        linker.pipe_midi(notes, recorder).unwrap();
+        linker.pipe_midi(controls, recorder).unwrap();
+        assert_eq!(linker.get_midi_sources(recorder).len(), 2);
+        let mut graph = linker.compile(&BTreeMap::new()).unwrap();
+        // A quarter note per sample
+        let map = TempoMap::new(60.0);
+        let mut transport = Transport::new(1.0);
+        transport.play();
+        let mut process = |graph: &mut Graph| {
+            graph.set_transport(transport, &map);
+            graph.process();
+            transport.advance(4, &map);
+        };
+        process(&mut graph);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(0, [0xB0, 7, 100]), (1, [0x90, 60, 100])]
+        );
+        process(&mut graph);
+        assert_eq!(graph.midi_input_events(recorder).unwrap().len(), 1);
+        assert_eq!(events.lock().unwrap()[2], (1, [0x80, 60, 0]));
+        assert!(!graph.is_finished());
+        process(&mut graph);
+        assert!(graph.is_finished());
+        linker.unregister_midi_output(controls).unwrap();
+        assert_eq!(linker.get_midi_sources(recorder), vec![notes]);
+        linker.unregister_midi_input(recorder).unwrap();
+        assert!(linker.pipe_midi(notes, recorder).is_err());
+    }
+
+    #[test]
+    fn end_of_stream() {
+        let mut linker = Linker::new();
+        let asset = linker.register_output(constant(0.5, 4));
+        let master = linker.register_input(Box::new(MasterSample::new(4, 2)));
+        linker.pipe(asset, master).unwrap();
+        let mut graph = linker.compile(&BTreeMap::new()).unwrap();
+        graph.process();
+        assert!(!graph.is_finished());
+        graph.process();
+        assert!(graph.is_finished());
+        assert_eq!(graph.ended_outputs().count(), 1);
+        assert_eq!(
+            graph.input_samples(master).unwrap(),
+            &[vec![0.0; 4], vec![0.0; 4]][..]
+        );
+    }
 }
@@ -0,0 +1,143 @@
+use vst::event::MidiEvent;
+
+/// Events a `MidiBuffer` holds per block when no capacity is given
+pub const MIDI_BUFFER_CAPACITY: usize = 1024;
+
+/// Build a live MIDI event from its raw bytes
+///
+/// # Parameters
+///
+/// * `delta_frames` Position of the event in the block
+/// * `data` Status byte and two data bytes, e.g. `[0x90, 60, 100]` for a note on
+pub fn midi_event(delta_frames: i32, data: [u8; 3]) -> MidiEvent {
+    MidiEvent {
+        data,
+        delta_frames,
+        live: true,
+        note_length: None,
+        note_offset: None,
+        detune: 0,
+        note_off_velocity: 0,
+    }
+}
+
+/// Fixed capacity list of the MIDI events of a block
+///
+/// The memory is allocated once so the buffer can be filled on the audio thread, events pushed
+/// past the capacity are dropped.
+pub struct MidiBuffer {
+    events: Vec<MidiEvent>,
+    /// Events dropped since the last `clear`
+    dropped: usize,
+}
+
+impl MidiBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: Vec::with_capacity(capacity),
+            dropped: 0,
+        }
+    }
+
+    /// Add an event, returns false if the buffer is full
+    pub fn push(&mut self, event: MidiEvent) -> bool {
+        if self.events.len() == self.events.capacity() {
+            self.dropped += 1;
+            return false;
+        }
+        self.events.push(event);
+        true
+    }
+
+    /// Add every event of `other`
+    pub fn extend_from(&mut self, other: &MidiBuffer) {
+        for event in other.events() {
+            self.push(*event);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.dropped = 0;
+    }
+
+    /// Sort the events by `delta_frames`, events at the same position keep their order
+    ///
+    /// This is an insertion sort: blocks hold few events, mostly sorted already, and the
+    /// standard stable sort allocates.
+    pub fn sort(&mut self) {
+        for idx in 1..self.events.len() {
+            let mut position = idx;
+            while position > 0
+                && self.events[position - 1].delta_frames > self.events[position].delta_frames
+            {
+                self.events.swap(position - 1, position);
+                position -= 1;
+            }
+        }
+    }
+
+    pub fn events(&self) -> &[MidiEvent] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.events.capacity()
+    }
+
+    /// Number of events dropped because the buffer was full since the last `clear`
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Default for MidiBuffer {
+    fn default() -> Self {
+        Self::new(MIDI_BUFFER_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorted_and_bounded() {
+        let mut first = MidiBuffer::new(4);
+        first.push(midi_event(8, [0x90, 60, 100]));
+        first.push(midi_event(2, [0x90, 64, 100]));
+        let mut second = MidiBuffer::new(4);
+        second.push(midi_event(8, [0x80, 60, 0]));
+        second.push(midi_event(0, [0xB0, 7, 127]));
+        let mut mix = MidiBuffer::new(3);
+        mix.extend_from(&first);
+        mix.extend_from(&second);
+        assert_eq!(mix.len(), 3);
+        assert_eq!(mix.dropped(), 1);
+        mix.sort();
+        let events: Vec<_> = mix
+            .events()
+            .iter()
+            .map(|event| (event.delta_frames, event.data))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (2, [0x90, 64, 100]),
+                (8, [0x90, 60, 100]),
+                (8, [0x80, 60, 0])
+            ]
+        );
+        mix.clear();
+        assert!(mix.is_empty());
+        assert_eq!(mix.capacity(), 3);
+    }
+}
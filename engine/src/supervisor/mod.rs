@@ -1,57 +1,563 @@
-use crate::{devices::*, prelude::*};
+use crate::{
+    devices::*,
+    loader::{
+        asset::{AssetError, AudioAsset},
+        bridge::{BridgeError, BridgedInstance},
+        smf::{MidiFile, SmfError},
+    },
+    prelude::*,
+};
 use cpal::traits::HostTrait;
+use graph::Graph;
+use linker::LinkerError;
 use std::{
     collections::BTreeMap,
-    path::Path,
-    sync::{Arc, Mutex, RwLock},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
-use vst::host::PluginLoader;
+use swap::GraphSwap;
+use vst::host::{PluginLoadError, PluginLoader};
+pub mod automation;
+pub mod delay;
+pub mod graph;
 pub mod linker;
+pub mod midi;
+pub mod project;
+pub mod queue;
+pub mod swap;
+pub mod tempo;
+pub mod transport;
+
+#[derive(Debug, Fail)]
+pub enum SupervisorError {
+    #[fail(display = "No output device available")]
+    NoOutputDevice,
+    #[fail(display = "Output device error: {}", _0)]
+    Device(DeviceError),
+    #[fail(display = "Linker error: {}", _0)]
+    Linker(LinkerError),
+    #[fail(display = "No vst loaded with id {:?}", _0)]
+    UnknownVst(VstId),
+    #[fail(display = "The graph can't be rendered while it is played")]
+    Playing,
+    #[fail(display = "Unable to load the plugin: {}", _0)]
+    PluginLoad(PluginLoadError),
+    #[fail(display = "The plugin {} panicked while loading", _0)]
+    PluginPanicked(String),
+    #[fail(display = "Plugin bridge error: {}", _0)]
+    Bridge(BridgeError),
+    #[fail(display = "Unable to load the audio file: {}", _0)]
+    Asset(AssetError),
+    #[fail(display = "Unable to load the MIDI file: {}", _0)]
+    Smf(SmfError),
+    #[fail(display = "No audio file loaded as {:?}", _0)]
+    UnknownAsset(OutputIndex),
+    #[fail(display = "No MIDI file loaded as {:?}", _0)]
+    UnknownMidiFile(MidiOutputIndex),
+}
+
+impl From<DeviceError> for SupervisorError {
+    fn from(err: DeviceError) -> Self {
+        SupervisorError::Device(err)
+    }
+}
+
+impl From<PluginLoadError> for SupervisorError {
+    fn from(err: PluginLoadError) -> Self {
+        SupervisorError::PluginLoad(err)
+    }
+}
+
+impl From<BridgeError> for SupervisorError {
+    fn from(err: BridgeError) -> Self {
+        SupervisorError::Bridge(err)
+    }
+}
+
+impl From<AssetError> for SupervisorError {
+    fn from(err: AssetError) -> Self {
+        SupervisorError::Asset(err)
+    }
+}
+
+impl From<SmfError> for SupervisorError {
+    fn from(err: SmfError) -> Self {
+        SupervisorError::Smf(err)
+    }
+}
+
+impl From<LinkerError> for SupervisorError {
+    fn from(err: LinkerError) -> Self {
+        SupervisorError::Linker(err)
+    }
+}
 
 pub struct Supervisor {
+    /// Graph being edited, call `commit` to send the changes to the audio thread
     pub linker: Linker,
     pub cpal_host: cpal::Host,
-    pub cpal_loop: cpal::EventLoop,
-    pub main_output: SysOutputDevice,
+    pub main_output: Box<dyn OutputDevice>,
+    /// Input of the graph that is played into `main_output`
+    pub main_input: InputIndex,
     pub vst_host: Arc<Mutex<VstHost>>,
-    pub plugins: BTreeMap<VstId, VstPlugin>,
+    pub plugins: BTreeMap<VstId, Arc<Mutex<VstPlugin>>>,
+    /// Library of every loaded plugin
+    plugin_paths: BTreeMap<VstId, PathBuf>,
+    /// Audio files loaded by `load_asset`, with their transport
+    assets: BTreeMap<OutputIndex, (PathBuf, AssetTransport)>,
+    /// MIDI files loaded by `load_midi_file`, with the played tracks
+    midi_files: BTreeMap<MidiOutputIndex, (PathBuf, Option<Vec<usize>>)>,
+    /// Bridge executable the plugins are run in, they are loaded in process without it
+    bridge: Option<PathBuf>,
+    /// Compiled graphs waiting to be played
+    graph: Arc<GraphSwap<Graph>>,
+    /// Set by the audio thread once every output device reached its end of stream
+    finished: Arc<AtomicBool>,
 }
 
 impl Supervisor {
-    pub fn new() -> Self {
+    /// Create a supervisor playing into the default system output device
+    pub fn new() -> Result<Self, SupervisorError> {
         let cpal_host = cpal::default_host();
-        let cpal_loop = cpal_host.event_loop();
-        let main_output =
-            SysOutputDevice::new(cpal_host.default_output_device().expect("Output device"));
+        let device = cpal_host
+            .default_output_device()
+            .ok_or(SupervisorError::NoOutputDevice)?;
+        let main_output = SysOutputDevice::new(device, cpal_host.event_loop())?;
+        Ok(Self::with_output(cpal_host, Box::new(main_output)))
+    }
+
+    /// Create a supervisor playing into the given output device
+    ///
+    /// # Parameters
+    ///
+    /// * `cpal_host` The CPAL host used to query system devices
+    /// * `main_output` The device the graph will be played into
+    pub fn with_output(cpal_host: cpal::Host, main_output: Box<dyn OutputDevice>) -> Self {
         info!(
             "Sample rate: {}, block size: {}",
             main_output.get_sample_rate(),
             main_output.get_block_size()
         );
+        let mut linker = Linker::new();
+        let master = MasterSample::new(
+            main_output.get_block_size() as usize,
+            main_output.nbr_channel(),
+        );
+        let main_input = linker.register_input(Box::new(master));
         Self {
-            linker: Linker::new(),
+            linker,
             vst_host: Arc::new(Mutex::new(VstHost::new(
-                main_output.get_block_size() as isize
+                f64::from(main_output.get_sample_rate()),
+                main_output.get_block_size() as isize,
             ))),
             cpal_host,
-            cpal_loop,
             main_output,
+            main_input,
             plugins: BTreeMap::new(),
+            plugin_paths: BTreeMap::new(),
+            assets: BTreeMap::new(),
+            midi_files: BTreeMap::new(),
+            bridge: None,
+            graph: Arc::new(GraphSwap::new()),
+            finished: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn load_vst<T: AsRef<Path>>(&mut self, path: T) -> VstId {
-        let mut loader = PluginLoader::load(path.as_ref(), self.vst_host.clone()).unwrap();
-        let mut instance = loader.instance().unwrap();
-        let mut plugin = VstPlugin::init(
-            instance,
-            self.main_output.get_sample_rate() as f32,
-            self.main_output.get_block_size() as i64,
-            &mut self.linker,
-        );
+    /// Compile the linker graph and hand it to the audio thread, the current graph
+    /// keeps playing until the new one is picked up at the start of the next block.
+    /// The latency of the graph is reported to the main output device
+    pub fn commit(&mut self) -> Result<(), SupervisorError> {
+        let graph = self.linker.compile(&self.plugins)?;
+        let latency = graph.input_latency(self.main_input).unwrap_or(0);
+        if latency != self.main_output.get_latency() {
+            info!("Graph latency: {} samples", latency);
+            self.main_output.set_latency(latency);
+        }
+        self.graph.publish(Box::new(graph));
+        Ok(())
+    }
+
+    /// Get the samples the graph played into the main output is late by, the latency of the
+    /// slowest plugins chain
+    pub fn get_latency(&self) -> usize {
+        self.main_output.get_latency()
+    }
+
+    /// Compile the graph again if a plugin changed its latency. Plugins signal it with an IO
+    /// change from any thread, so it must be called regularly, e.g. from the UI loop.
+    /// Returns true if the graph was committed
+    pub fn update_latency(&mut self) -> Result<bool, SupervisorError> {
+        if !self.refresh_latency() {
+            return Ok(false);
+        }
+        self.commit()?;
+        Ok(true)
+    }
+
+    /// Read the latency of the plugins that signaled an IO change, returns true if one changed
+    fn refresh_latency(&self) -> bool {
+        let mut changed = false;
+        for plugin in self.plugins.values() {
+            let mut plugin = plugin.lock().unwrap();
+            if plugin.take_io_changed() && plugin.update_latency() {
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Run the plugins loaded from now on in a child process of the given bridge executable
+    /// (see `bridge::default_executable`), or in the engine process with `None`
+    pub fn set_bridge(&mut self, bridge: Option<PathBuf>) {
+        self.bridge = bridge;
+    }
+
+    /// Load a plugin and register its devices in the linker
+    ///
+    /// A plugin failing to load or panicking while it is initialized is reported as an
+    /// error, the supervisor stays usable
+    pub fn load_vst<T: AsRef<Path>>(&mut self, path: T) -> Result<VstId, SupervisorError> {
+        let path = path.as_ref();
+        if let Some(bridge) = self.bridge.as_ref() {
+            let block_size = self.main_output.get_block_size() as i64;
+            let instance = BridgedInstance::spawn(
+                bridge,
+                path,
+                self.vst_host.clone(),
+                self.main_output.get_sample_rate() as f32,
+                block_size,
+            )?;
+            let plugin = VstPlugin::bridged(instance, block_size, &mut self.linker);
+            return self.add_plugin(plugin, path);
+        }
+        let vst_host = self.vst_host.clone();
+        let sample_rate = self.main_output.get_sample_rate() as f32;
+        let block_size = self.main_output.get_block_size() as i64;
+        let linker = &mut self.linker;
+        // The plugin only touches the linker once it is initialized, a panic leaves it unchanged
+        let plugin = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut loader = PluginLoader::load(path, vst_host)?;
+            let instance = loader.instance()?;
+            Ok(VstPlugin::init(instance, sample_rate, block_size, linker))
+        }))
+        .map_err(|_| SupervisorError::PluginPanicked(path.display().to_string()))?
+        .map_err(|err: PluginLoadError| {
+            error!("Unable to load {}: {}", path.display(), err);
+            err
+        })?;
         // plugin.load_editor(win_handle);
+        self.add_plugin(plugin, path)
+    }
+
+    fn add_plugin(&mut self, plugin: VstPlugin, path: &Path) -> Result<VstId, SupervisorError> {
         let id = plugin.id;
-        self.plugins.insert(plugin.id, plugin);
-        id
+        self.plugin_paths.insert(id, path.to_path_buf());
+        if let Some(instance) = plugin.instance_id() {
+            self.vst_host
+                .lock()
+                .unwrap()
+                .register_instance(instance, plugin.events());
+        }
+        self.plugins.insert(plugin.id, Arc::new(Mutex::new(plugin)));
+        if let Err(err) = self.commit() {
+            error!("Unable to commit the graph: {}", err);
+        }
+        Ok(id)
+    }
+
+    /// Remove a plugin from the graph, every pipe connected to it is removed
+    pub fn unload_vst(&mut self, id: VstId) -> Result<(), SupervisorError> {
+        let plugin = self
+            .plugins
+            .get(&id)
+            .cloned()
+            .ok_or(SupervisorError::UnknownVst(id))?;
+        let mut plugin = plugin.lock().unwrap();
+        for err in plugin.unload(&mut self.linker) {
+            warn!("Unable to remove a device of the plugin {:?}: {}", id, err);
+        }
+        if let Some(instance) = plugin.instance_id() {
+            self.vst_host.lock().unwrap().unregister_instance(instance);
+        }
+        self.plugins.remove(&id);
+        self.plugin_paths.remove(&id);
+        self.commit()
+    }
+
+    /// Get the path of the library a plugin was loaded from
+    pub fn plugin_path(&self, id: VstId) -> Option<&Path> {
+        self.plugin_paths.get(&id).map(PathBuf::as_path)
+    }
+
+    /// Load an audio file and register it as an output device, resampled to the output
+    /// sample rate. Unlike the devices registered directly in the linker, it is saved in the
+    /// projects
+    pub fn load_asset<T: AsRef<Path>>(&mut self, path: T) -> Result<OutputIndex, SupervisorError> {
+        let path = path.as_ref();
+        let asset = AudioAsset::from_path(path)?;
+        info!(
+            "Loaded {} ({} channels, {} Hz, {:?})",
+            path.display(),
+            asset.channels(),
+            asset.sample_rate,
+            asset.duration()
+        );
+        let device = AssetSampleOutput::new(
+            asset,
+            self.main_output.get_block_size() as usize,
+            self.main_output.get_sample_rate(),
+        );
+        let transport = device.transport();
+        let idx = self.linker.register_output(Box::new(device));
+        self.assets.insert(idx, (path.to_path_buf(), transport));
+        Ok(idx)
+    }
+
+    /// Get the transport controls of an audio file loaded by `load_asset`
+    pub fn asset_transport(&self, idx: OutputIndex) -> Option<AssetTransport> {
+        self.assets
+            .get(&idx)
+            .map(|(_, transport)| transport.clone())
+    }
+
+    /// Remove an audio file loaded by `load_asset`, every pipe connected to it is removed
+    pub fn unload_asset(&mut self, idx: OutputIndex) -> Result<(), SupervisorError> {
+        self.assets
+            .remove(&idx)
+            .ok_or(SupervisorError::UnknownAsset(idx))?;
+        self.linker.unregister_output(idx)?;
+        self.commit()
+    }
+
+    /// Load a MIDI file and register it as a MIDI output device, it is saved in the projects
+    ///
+    /// # Parameters
+    ///
+    /// * `path` Path of the Standard MIDI File
+    /// * `tracks` Indexes of the played tracks, every track with `None`
+    ///
+    /// The events follow the tempo map of the host transport
+    pub fn load_midi_file<T: AsRef<Path>>(
+        &mut self,
+        path: T,
+        tracks: Option<Vec<usize>>,
+    ) -> Result<MidiOutputIndex, SupervisorError> {
+        let file = MidiFile::from_path(path.as_ref())?;
+        Ok(self.add_midi_file(path, &file, tracks))
+    }
+
+    /// Register a MIDI file already decoded from `path`, see `load_midi_file`
+    pub fn add_midi_file<T: AsRef<Path>>(
+        &mut self,
+        path: T,
+        file: &MidiFile,
+        tracks: Option<Vec<usize>>,
+    ) -> MidiOutputIndex {
+        let block_size = self.main_output.get_block_size() as usize;
+        let device = match &tracks {
+            Some(tracks) => MidiFileOutput::with_tracks(file, tracks, block_size),
+            None => MidiFileOutput::new(file, block_size),
+        };
+        let idx = self.linker.register_midi_output(Box::new(device));
+        self.midi_files
+            .insert(idx, (path.as_ref().to_path_buf(), tracks));
+        idx
+    }
+
+    /// Remove a MIDI file loaded by `load_midi_file`, every MIDI pipe connected to it is
+    /// removed
+    pub fn unload_midi_file(&mut self, idx: MidiOutputIndex) -> Result<(), SupervisorError> {
+        self.midi_files
+            .remove(&idx)
+            .ok_or(SupervisorError::UnknownMidiFile(idx))?;
+        self.linker.unregister_midi_output(idx)?;
+        self.commit()
+    }
+
+    /// Start playing the graph into the main output device, the transport is started too
+    pub fn start(&mut self) -> Result<(), SupervisorError> {
+        self.commit()?;
+        let swap = self.graph.clone();
+        let finished = self.finished.clone();
+        finished.store(false, Ordering::Release);
+        let main_input = self.main_input;
+        let vst_host = self.vst_host.clone();
+        let (mut transport, mut tempo_map) = {
+            let mut host = vst_host.lock().unwrap();
+            host.transport.play();
+            (host.transport, host.tempo_map().clone())
+        };
+        let mut graph: Option<Box<Graph>> = None;
+        let mut pending: Vec<f32> = Vec::new();
+        let mut cursor = 0;
+        // Frames played while the host was locked, the transport catches up on the next block
+        // and the graph keeps the position of the previous one
+        let mut elapsed = 0;
+        self.main_output.start(Box::new(move |out: &mut [f32]| {
+            let mut written = 0;
+            while written < out.len() {
+                if cursor >= pending.len() {
+                    pending.clear();
+                    cursor = 0;
+                    swap.fetch(&mut graph);
+                    if let Some(graph) = graph.as_mut() {
+                        graph.set_transport(transport, &tempo_map);
+                        graph.process();
+                        finished.store(graph.is_finished(), Ordering::Release);
+                        if let Some(master) = graph.input_samples(main_input) {
+                            let frames = master.first().map(|channel| channel.len()).unwrap_or(0);
+                            for frame in 0..frames {
+                                pending.extend(master.iter().map(|channel| channel[frame]));
+                            }
+                            elapsed += frames;
+                        }
+                        if let Ok(mut host) = vst_host.try_lock() {
+                            host.advance(elapsed);
+                            elapsed = 0;
+                            transport = host.transport;
+                            tempo_map.clone_from(host.tempo_map());
+                        }
+                    }
+                    if pending.is_empty() {
+                        out[written..].iter_mut().for_each(|sample| *sample = 0.0);
+                        return;
+                    }
+                }
+                let len = (out.len() - written).min(pending.len() - cursor);
+                out[written..written + len].copy_from_slice(&pending[cursor..cursor + len]);
+                written += len;
+                cursor += len;
+            }
+        }))?;
+        Ok(())
+    }
+
+    /// Get the samples the main input keeps receiving once the sources ended: the tails of
+    /// the plugins are summed along each chain piped into it and the longest chain is kept
+    pub fn get_tail_size(&self) -> usize {
+        self.linker
+            .compile(&self.plugins)
+            .ok()
+            .and_then(|graph| graph.input_tail(self.main_input))
+            .unwrap_or(0)
+    }
+
+    /// Render the graph offline, as fast as possible, into an audio file
+    ///
+    /// Everything piped into `main_input` is written to `path` until every output device
+    /// reached its end of stream and the plugins tail and latency are rendered, the length is
+    /// rounded up to whole blocks. The assets are played from their start marker and the
+    /// transport from the start of its loop, or from the start of the song.
+    ///
+    /// # Parameters
+    ///
+    /// * `path` Path of the created file
+    /// * `format` Encoding of the file
+    /// * `max_frames` Stop after this number of frames, needed for endless sources
+    pub fn render<T: AsRef<Path>>(
+        &mut self,
+        path: T,
+        format: FileFormat,
+        max_frames: Option<usize>,
+    ) -> Result<usize, SupervisorError> {
+        if self.main_output.is_running() {
+            return Err(SupervisorError::Playing);
+        }
+        let block_size = self.main_output.get_block_size() as usize;
+        let sink = FileSampleInput::create(
+            path,
+            format,
+            self.main_output.get_sample_rate(),
+            block_size,
+            self.main_output.nbr_channel(),
+        )?;
+        let sink_idx = self.linker.register_input(Box::new(sink.clone()));
+        {
+            let mut host = self.vst_host.lock().unwrap();
+            host.offline = true;
+            let start = host.transport.loop_range().map_or(0.0, |(start, _)| {
+                host.tempo_map()
+                    .beats_to_samples(start, host.transport.sample_rate())
+            });
+            host.seek(start);
+        }
+        for (_, transport) in self.assets.values() {
+            transport.rewind();
+        }
+        let result = self.render_into(sink_idx, block_size, max_frames);
+        {
+            let mut host = self.vst_host.lock().unwrap();
+            host.offline = false;
+            host.transport.stop();
+        }
+        self.linker.unregister_input(sink_idx)?;
+        let frames = sink.finalize()?;
+        result?;
+        info!("Rendered {} frames", frames);
+        Ok(frames)
+    }
+
+    fn render_into(
+        &mut self,
+        sink: InputIndex,
+        block_size: usize,
+        max_frames: Option<usize>,
+    ) -> Result<(), SupervisorError> {
+        for output in self.linker.get_sources(self.main_input) {
+            self.linker.pipe(output, sink)?;
+        }
+        let mut graph = self.linker.compile(&self.plugins)?;
+        let mut tail_left = None;
+        let mut frames = 0;
+        self.vst_host.lock().unwrap().transport.play();
+        while max_frames.map(|max| frames < max).unwrap_or(true) {
+            if self.refresh_latency() {
+                graph = self.linker.compile(&self.plugins)?;
+                // The main output is stopped, it plays the new graph from the next `start`
+                let latency = graph.input_latency(self.main_input).unwrap_or(0);
+                self.main_output.set_latency(latency);
+            }
+            {
+                let host = self.vst_host.lock().unwrap();
+                graph.set_transport(host.transport, host.tempo_map());
+            }
+            graph.process();
+            self.vst_host.lock().unwrap().advance(block_size);
+            frames += block_size;
+            if graph.is_finished() {
+                // The first silent block is part of the tail, the delayed samples follow it
+                let latency = graph.input_latency(sink).unwrap_or(0);
+                let tail = graph.input_tail(sink).unwrap_or(0);
+                let left = tail_left.get_or_insert(tail + latency);
+                if *left <= block_size {
+                    break;
+                }
+                *left -= block_size;
+            }
+        }
+        Ok(())
+    }
+
+    /// Did every output device of the played graph reach its end of stream
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    /// Stop playing the graph and the transport
+    pub fn stop(&mut self) {
+        self.main_output.stop();
+        if let Ok(mut host) = self.vst_host.lock() {
+            host.transport.stop();
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
@@ -0,0 +1,633 @@
+//! Project files: the whole session of a `Supervisor` saved as JSON
+//!
+//! A project keeps the plugins with their state, the audio and MIDI files loaded through the
+//! supervisor, the pipes between them and the transport settings. Devices registered directly
+//! in the linker are not saved, nor are the pipes connected to them.
+use super::{Supervisor, SupervisorError};
+use crate::{
+    loader::fxp::{Bank, FxError, FxFile},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Format version of the saved projects, projects of newer versions are rejected
+pub const PROJECT_VERSION: u32 = 1;
+
+#[derive(Debug, Fail)]
+pub enum ProjectError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Invalid project: {}", _0)]
+    Format(serde_json::Error),
+    #[fail(
+        display = "The project format {} is newer than the supported format",
+        _0
+    )]
+    NewerVersion(u32),
+    #[fail(display = "Unable to save the state of {}: {}", _0, _1)]
+    PluginState(String, FxError),
+    #[fail(display = "Supervisor error: {}", _0)]
+    Supervisor(SupervisorError),
+}
+
+impl From<io::Error> for ProjectError {
+    fn from(err: io::Error) -> Self {
+        ProjectError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ProjectError {
+    fn from(err: serde_json::Error) -> Self {
+        ProjectError::Format(err)
+    }
+}
+
+impl From<SupervisorError> for ProjectError {
+    fn from(err: SupervisorError) -> Self {
+        ProjectError::Supervisor(err)
+    }
+}
+
+/// Part of a project that could not be restored, the rest of the project is loaded without it
+#[derive(Debug, Fail)]
+pub enum ProjectIssue {
+    #[fail(display = "Missing plugin {}: {}", path, reason)]
+    MissingPlugin { path: String, reason: String },
+    #[fail(
+        display = "The plugin {} has the id {} instead of {}",
+        path, found, expected
+    )]
+    WrongPlugin {
+        path: String,
+        expected: i32,
+        found: i32,
+    },
+    #[fail(display = "Unable to restore the state of {}: {}", path, reason)]
+    PluginState { path: String, reason: String },
+    #[fail(display = "Missing audio file {}: {}", path, reason)]
+    MissingAsset { path: String, reason: String },
+    #[fail(display = "Missing MIDI file {}: {}", path, reason)]
+    MissingMidiFile { path: String, reason: String },
+    #[fail(display = "Unable to restore a pipe: {}", _0)]
+    Pipe(String),
+}
+
+/// Transport of the host when the project was saved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransportSettings {
+    pub tempo_map: TempoMap,
+    /// Position in samples
+    pub sample_pos: f64,
+    /// Loop range in quarter notes
+    pub loop_range: Option<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectPlugin {
+    pub path: PathBuf,
+    /// `Info::unique_id`, checked when the plugin is loaded again
+    pub unique_id: i32,
+    /// Every program of the plugin as an FXB bank, empty when the state couldn't be read
+    pub state: Vec<u8>,
+    pub bypass: bool,
+    pub automation: Vec<AutomationLane>,
+}
+
+/// An audio file and the settings of its `AssetTransport`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectAsset {
+    pub path: PathBuf,
+    pub looping: bool,
+    /// Start and end markers in frames at the sample rate of the project
+    pub markers: (usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectMidiFile {
+    pub path: PathBuf,
+    /// Played tracks, every track with `None`
+    pub tracks: Option<Vec<usize>>,
+}
+
+/// A device of the project, the indexes are positions in the project lists. A plugin stands
+/// for its sample devices in sample pipes and for its MIDI devices in MIDI pipes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endpoint {
+    /// The main input of the supervisor
+    Main,
+    Plugin(usize),
+    Asset(usize),
+    MidiFile(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectPipe {
+    pub from: Endpoint,
+    pub to: Endpoint,
+}
+
+/// Everything needed to rebuild a session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Project {
+    pub version: u32,
+    pub transport: TransportSettings,
+    pub plugins: Vec<ProjectPlugin>,
+    pub assets: Vec<ProjectAsset>,
+    pub midi_files: Vec<ProjectMidiFile>,
+    pub pipes: Vec<ProjectPipe>,
+    pub midi_pipes: Vec<ProjectPipe>,
+}
+
+impl Project {
+    /// Read a project, it is not loaded into a supervisor
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ProjectError> {
+        let project: Project = serde_json::from_slice(&fs::read(path)?)?;
+        project.check()?;
+        Ok(project)
+    }
+
+    /// Reject the projects of newer versions and the values no session can hold
+    fn check(&self) -> Result<(), ProjectError> {
+        if self.version > PROJECT_VERSION {
+            return Err(ProjectError::NewerVersion(self.version));
+        }
+        self.transport
+            .tempo_map
+            .validate()
+            .map_err(|err| ProjectError::Format(serde::de::Error::custom(err)))
+    }
+
+    /// Write the project, the previous file is only replaced once the new one is complete
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ProjectError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Supervisor {
+    /// Describe the current session
+    pub fn to_project(&self) -> Result<Project, ProjectError> {
+        let transport = {
+            let host = self.vst_host.lock().unwrap();
+            TransportSettings {
+                tempo_map: host.tempo_map().clone(),
+                sample_pos: host.transport.sample_pos(),
+                loop_range: host.transport.loop_range(),
+            }
+        };
+        let mut plugins = Vec::with_capacity(self.plugins.len());
+        // Devices of each plugin: sample input, sample output, MIDI input and MIDI output
+        let mut devices = Vec::with_capacity(self.plugins.len());
+        for (id, plugin) in self.plugins.iter() {
+            let plugin = plugin.lock().unwrap();
+            let path = self.plugin_paths[id].clone();
+            let state = if plugin.state() == PluginState::Active {
+                Bank::capture(&plugin.parameters(), plugin.get_info())
+                    .map_err(|err| ProjectError::PluginState(path.display().to_string(), err))?
+                    .encode()
+            } else {
+                warn!(
+                    "The state of the stopped plugin {} is not saved",
+                    path.display()
+                );
+                Vec::new()
+            };
+            devices.push((
+                plugin.get_inputs(),
+                plugin.get_outputs(),
+                plugin.get_midi_input(),
+                plugin.get_midi_output(),
+            ));
+            plugins.push(ProjectPlugin {
+                path,
+                unique_id: plugin.get_info().unique_id,
+                state,
+                bypass: plugin.is_bypassed(),
+                automation: plugin.automation().to_vec(),
+            });
+        }
+        let assets: Vec<_> = self
+            .assets
+            .values()
+            .map(|(path, transport)| ProjectAsset {
+                path: path.clone(),
+                looping: transport.is_looping(),
+                markers: transport.markers(),
+            })
+            .collect();
+        let midi_files: Vec<_> = self
+            .midi_files
+            .values()
+            .map(|(path, tracks)| ProjectMidiFile {
+                path: path.clone(),
+                tracks: tracks.clone(),
+            })
+            .collect();
+
+        let asset_position: BTreeMap<_, _> = self
+            .assets
+            .keys()
+            .enumerate()
+            .map(|(pos, idx)| (*idx, pos))
+            .collect();
+        let midi_file_position: BTreeMap<_, _> = self
+            .midi_files
+            .keys()
+            .enumerate()
+            .map(|(pos, idx)| (*idx, pos))
+            .collect();
+        let mut pipes = Vec::new();
+        for (_, output, input) in self.linker.get_pipes() {
+            let from = match devices.iter().position(|device| device.1 == output) {
+                Some(pos) => Some(Endpoint::Plugin(pos)),
+                None => asset_position.get(&output).map(|pos| Endpoint::Asset(*pos)),
+            };
+            let to = match devices.iter().position(|device| device.0 == input) {
+                Some(pos) => Some(Endpoint::Plugin(pos)),
+                None if input == self.main_input => Some(Endpoint::Main),
+                None => None,
+            };
+            match (from, to) {
+                (Some(from), Some(to)) => pipes.push(ProjectPipe { from, to }),
+                _ => warn!("The pipe {:?} -> {:?} is not saved", output, input),
+            }
+        }
+        let mut midi_pipes = Vec::new();
+        for (_, output, input) in self.linker.get_midi_pipes() {
+            let from = match devices.iter().position(|device| device.3 == output) {
+                Some(pos) => Some(Endpoint::Plugin(pos)),
+                None => midi_file_position
+                    .get(&output)
+                    .map(|pos| Endpoint::MidiFile(*pos)),
+            };
+            let to = devices
+                .iter()
+                .position(|device| device.2 == input)
+                .map(Endpoint::Plugin);
+            match (from, to) {
+                (Some(from), Some(to)) => midi_pipes.push(ProjectPipe { from, to }),
+                _ => warn!("The MIDI pipe {:?} -> {:?} is not saved", output, input),
+            }
+        }
+        Ok(Project {
+            version: PROJECT_VERSION,
+            transport,
+            plugins,
+            assets,
+            midi_files,
+            pipes,
+            midi_pipes,
+        })
+    }
+
+    /// Save the current session into a project file
+    pub fn save_project<P: AsRef<Path>>(&self, path: P) -> Result<(), ProjectError> {
+        self.to_project()?.save(path)
+    }
+
+    /// Replace the session by a project file, see `restore_project`
+    pub fn load_project<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<Vec<ProjectIssue>, ProjectError> {
+        let project = Project::from_path(path)?;
+        self.restore_project(&project)
+    }
+
+    /// Replace the session by a project: the plugins and the files loaded through the
+    /// supervisor are unloaded, then the project is loaded and committed
+    ///
+    /// Plugins and files that fail to load are skipped along with their pipes, each of them
+    /// is reported by an issue. The session is usable whatever the issues.
+    pub fn restore_project(
+        &mut self,
+        project: &Project,
+    ) -> Result<Vec<ProjectIssue>, ProjectError> {
+        project.check()?;
+        self.clear()?;
+        let mut issues = Vec::new();
+        {
+            let mut host = self.vst_host.lock().unwrap();
+            host.set_tempo_map(project.transport.tempo_map.clone());
+            host.seek(project.transport.sample_pos);
+            host.transport.set_loop(project.transport.loop_range);
+        }
+
+        let mut plugins = Vec::with_capacity(project.plugins.len());
+        for saved in project.plugins.iter() {
+            plugins.push(self.restore_plugin(saved, &mut issues));
+        }
+        let mut assets = Vec::with_capacity(project.assets.len());
+        for saved in project.assets.iter() {
+            match self.load_asset(&saved.path) {
+                Ok(idx) => {
+                    let transport = self.assets[&idx].1.clone();
+                    transport.set_markers(saved.markers.0, Some(saved.markers.1));
+                    transport.set_looping(saved.looping);
+                    assets.push(Some(idx));
+                }
+                Err(err) => {
+                    issues.push(ProjectIssue::MissingAsset {
+                        path: saved.path.display().to_string(),
+                        reason: err.to_string(),
+                    });
+                    assets.push(None);
+                }
+            }
+        }
+        let mut midi_files = Vec::with_capacity(project.midi_files.len());
+        for saved in project.midi_files.iter() {
+            match self.load_midi_file(&saved.path, saved.tracks.clone()) {
+                Ok(idx) => midi_files.push(Some(idx)),
+                Err(err) => {
+                    issues.push(ProjectIssue::MissingMidiFile {
+                        path: saved.path.display().to_string(),
+                        reason: err.to_string(),
+                    });
+                    midi_files.push(None);
+                }
+            }
+        }
+
+        // Pipes of the skipped devices are already reported through the devices
+        let plugin = |pos: &usize| plugins.get(*pos).cloned().flatten();
+        for pipe in project.pipes.iter() {
+            let output = match pipe.from {
+                Endpoint::Plugin(pos) => {
+                    plugin(&pos).map(|id| self.plugins[&id].lock().unwrap().get_outputs())
+                }
+                Endpoint::Asset(pos) => assets.get(pos).cloned().flatten(),
+                _ => None,
+            };
+            let input = match pipe.to {
+                Endpoint::Main => Some(self.main_input),
+                Endpoint::Plugin(pos) => {
+                    plugin(&pos).map(|id| self.plugins[&id].lock().unwrap().get_inputs())
+                }
+                _ => None,
+            };
+            if let (Some(output), Some(input)) = (output, input) {
+                if let Err(err) = self.linker.pipe(output, input) {
+                    issues.push(ProjectIssue::Pipe(err.to_string()));
+                }
+            }
+        }
+        for pipe in project.midi_pipes.iter() {
+            let output = match pipe.from {
+                Endpoint::Plugin(pos) => {
+                    plugin(&pos).map(|id| self.plugins[&id].lock().unwrap().get_midi_output())
+                }
+                Endpoint::MidiFile(pos) => midi_files.get(pos).cloned().flatten(),
+                _ => None,
+            };
+            let input = match pipe.to {
+                Endpoint::Plugin(pos) => {
+                    plugin(&pos).map(|id| self.plugins[&id].lock().unwrap().get_midi_input())
+                }
+                _ => None,
+            };
+            if let (Some(output), Some(input)) = (output, input) {
+                if let Err(err) = self.linker.pipe_midi(output, input) {
+                    issues.push(ProjectIssue::Pipe(err.to_string()));
+                }
+            }
+        }
+        for issue in issues.iter() {
+            warn!("{}", issue);
+        }
+        self.commit()?;
+        Ok(issues)
+    }
+
+    /// Load a plugin of a project and restore its state, returns `None` if it is skipped
+    fn restore_plugin(
+        &mut self,
+        saved: &ProjectPlugin,
+        issues: &mut Vec<ProjectIssue>,
+    ) -> Option<VstId> {
+        let path = saved.path.display().to_string();
+        let id = match self.load_vst(&saved.path) {
+            Ok(id) => id,
+            Err(err) => {
+                issues.push(ProjectIssue::MissingPlugin {
+                    path,
+                    reason: err.to_string(),
+                });
+                return None;
+            }
+        };
+        let found = self.plugins[&id].lock().unwrap().get_info().unique_id;
+        if found != saved.unique_id {
+            issues.push(ProjectIssue::WrongPlugin {
+                path,
+                expected: saved.unique_id,
+                found,
+            });
+            if let Err(err) = self.unload_vst(id) {
+                error!("Unable to unload the plugin {:?}: {}", id, err);
+            }
+            return None;
+        }
+        let mut plugin = self.plugins[&id].lock().unwrap();
+        if !saved.state.is_empty() {
+            let restored = FxFile::decode(&saved.state)
+                .and_then(|file| file.apply(&plugin.parameters(), plugin.get_info()));
+            if let Err(err) = restored {
+                issues.push(ProjectIssue::PluginState {
+                    path,
+                    reason: err.to_string(),
+                });
+            }
+        }
+        plugin.set_bypass(saved.bypass);
+        for lane in saved.automation.iter() {
+            plugin.set_automation(lane.clone());
+        }
+        Some(id)
+    }
+
+    /// Unload every plugin and every file loaded through the supervisor
+    fn clear(&mut self) -> Result<(), SupervisorError> {
+        let plugins: Vec<_> = self.plugins.keys().cloned().collect();
+        for id in plugins {
+            self.unload_vst(id)?;
+        }
+        let assets: Vec<_> = self.assets.keys().cloned().collect();
+        for idx in assets {
+            self.unload_asset(idx)?;
+        }
+        let midi_files: Vec<_> = self.midi_files.keys().cloned().collect();
+        for idx in midi_files {
+            self.unload_midi_file(idx)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{FileFormat, FileSampleInput, NullOutputDevice};
+
+    /// Write a stereo FLAC file of `frames` frames at `value`
+    fn write_asset(name: &str, value: f32, frames: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        let mut file =
+            FileSampleInput::create(&path, FileFormat::Flac(16), 48000, frames, 2).unwrap();
+        SampleInput::next(&mut file, &vec![value; frames], 0);
+        SampleInput::next(&mut file, &vec![value; frames], 1);
+        file.finalize().unwrap();
+        path
+    }
+
+    fn supervisor() -> Supervisor {
+        let output = NullOutputDevice::new(48000, 64);
+        Supervisor::with_output(cpal::default_host(), Box::new(output))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let first = write_asset("engine-project-first.flac", 0.25, 64);
+        let second = write_asset("engine-project-second.flac", 0.5, 64);
+        let mut session = supervisor();
+        session
+            .vst_host
+            .lock()
+            .unwrap()
+            .set_tempo_map(TempoMap::new(90.0));
+        let looped = session.load_asset(&first).unwrap();
+        let played = session.load_asset(&second).unwrap();
+        session.asset_transport(looped).unwrap().set_looping(true);
+        session
+            .asset_transport(played)
+            .unwrap()
+            .set_markers(16, Some(48));
+        session.linker.pipe(looped, session.main_input).unwrap();
+        session.linker.pipe(played, session.main_input).unwrap();
+        let project = session.to_project().unwrap();
+        assert_eq!(project.assets.len(), 2);
+        assert_eq!(project.pipes.len(), 2);
+
+        let path = std::env::temp_dir().join("engine-project.json");
+        session.save_project(&path).unwrap();
+        let mut loaded = supervisor();
+        assert!(loaded.load_project(&path).unwrap().is_empty());
+        assert_eq!(loaded.to_project().unwrap(), project);
+        // Loading again replaces the session
+        assert!(loaded.load_project(&path).unwrap().is_empty());
+        assert_eq!(loaded.linker.get_pipes().len(), 2);
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&first).ok();
+        std::fs::remove_file(&second).ok();
+    }
+
+    #[test]
+    fn missing_files() {
+        let asset = write_asset("engine-project-missing.flac", 0.25, 64);
+        let project = Project {
+            version: PROJECT_VERSION,
+            transport: TransportSettings {
+                tempo_map: TempoMap::new(120.0),
+                sample_pos: 0.0,
+                loop_range: None,
+            },
+            plugins: vec![ProjectPlugin {
+                path: PathBuf::from("examples/vst/missing.dll"),
+                unique_id: 1,
+                state: Vec::new(),
+                bypass: false,
+                automation: Vec::new(),
+            }],
+            assets: vec![
+                ProjectAsset {
+                    path: PathBuf::from("examples/assets/missing.flac"),
+                    looping: false,
+                    markers: (0, 0),
+                },
+                ProjectAsset {
+                    path: asset.clone(),
+                    looping: false,
+                    markers: (0, 64),
+                },
+            ],
+            midi_files: Vec::new(),
+            pipes: vec![
+                ProjectPipe {
+                    from: Endpoint::Asset(0),
+                    to: Endpoint::Main,
+                },
+                ProjectPipe {
+                    from: Endpoint::Asset(1),
+                    to: Endpoint::Plugin(0),
+                },
+                ProjectPipe {
+                    from: Endpoint::Asset(1),
+                    to: Endpoint::Main,
+                },
+            ],
+            midi_pipes: vec![ProjectPipe {
+                from: Endpoint::MidiFile(0),
+                to: Endpoint::Plugin(0),
+            }],
+        };
+        let mut session = supervisor();
+        let issues = session.restore_project(&project).unwrap();
+        std::fs::remove_file(&asset).ok();
+        assert_eq!(issues.len(), 2);
+        match &issues[0] {
+            ProjectIssue::MissingPlugin { path, .. } => {
+                assert_eq!(path, "examples/vst/missing.dll")
+            }
+            other => panic!("Unexpected issue {:?}", other),
+        }
+        match &issues[1] {
+            ProjectIssue::MissingAsset { .. } => {}
+            other => panic!("Unexpected issue {:?}", other),
+        }
+        assert!(session.plugins.is_empty());
+        assert_eq!(session.linker.get_pipes().len(), 1);
+
+        let newer = Project {
+            version: PROJECT_VERSION + 1,
+            ..project
+        };
+        match session.restore_project(&newer) {
+            Err(ProjectError::NewerVersion(_)) => {}
+            other => panic!("Unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn malformed_tempo_map() {
+        let path = std::env::temp_dir().join("engine-project-tempo.json");
+        let mut project = supervisor().to_project().unwrap();
+        let mut json = serde_json::to_value(&project).unwrap();
+        json["transport"]["tempo_map"]["tempos"] = serde_json::json!([]);
+        std::fs::write(&path, json.to_string()).unwrap();
+        let loaded = Project::from_path(&path);
+        std::fs::remove_file(&path).ok();
+        match loaded {
+            Err(ProjectError::Format(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let mut json = serde_json::to_value(&project.transport.tempo_map).unwrap();
+        json["signatures"] = serde_json::json!([
+            {"bar": 4, "numerator": 3, "denominator": 4},
+            {"bar": 0, "numerator": 4, "denominator": 4}
+        ]);
+        project.transport.tempo_map = serde_json::from_value(json).unwrap();
+        match supervisor().restore_project(&project) {
+            Err(ProjectError::Format(_)) => {}
+            other => panic!("Unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+}
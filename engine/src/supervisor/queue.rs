@@ -0,0 +1,116 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Lock free bounded queue of plain values (MIDI events, parameter changes) sent by the
+/// plugins to the host.
+///
+/// There must be at most one thread pushing and one thread popping at a time, plugins push
+/// from the host callbacks which are already serialized by the host lock. Values pushed into a
+/// full queue are dropped, the queue never allocates after its creation.
+pub struct EventQueue<T: Copy> {
+    slots: Box<[UnsafeCell<Option<T>>]>,
+    /// Count of popped values
+    head: AtomicUsize,
+    /// Count of pushed values
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Copy + Send> Send for EventQueue<T> {}
+unsafe impl<T: Copy + Send> Sync for EventQueue<T> {}
+
+impl<T: Copy> EventQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity.max(1))
+                .map(|_| UnsafeCell::new(None))
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a value, returns false if the queue is full
+    pub fn push(&self, value: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= self.slots.len() {
+            return false;
+        }
+        unsafe { *self.slots[tail % self.slots.len()].get() = Some(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Take the oldest value
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { *self.slots[head % self.slots.len()].get() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        value
+    }
+
+    /// Number of values waiting to be popped
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn bounded_fifo() {
+        let queue = EventQueue::new(2);
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+        assert_eq!(queue.pop(), Some(1));
+        assert!(queue.push(4));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn across_threads() {
+        let queue = Arc::new(EventQueue::new(16));
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for value in 0..10_000u32 {
+                    while !queue.push(value) {
+                        thread::yield_now();
+                    }
+                }
+            })
+        };
+        let mut expected = 0;
+        while expected < 10_000 {
+            match queue.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+        assert!(queue.is_empty());
+    }
+}
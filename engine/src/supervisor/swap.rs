@@ -0,0 +1,97 @@
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// Lock free slot used to hand values (compiled graphs) from the editing thread to the audio
+/// thread.
+///
+/// The editing thread `publish` new values, the audio thread `fetch` them. The value replaced
+/// by the audio thread is retired instead of being dropped, the editing thread reclaims it on
+/// the next `publish` or `collect` so no deallocation ever happens on the audio thread.
+pub struct GraphSwap<T> {
+    pending: AtomicPtr<T>,
+    retired: AtomicPtr<T>,
+}
+
+unsafe impl<T: Send> Send for GraphSwap<T> {}
+unsafe impl<T: Send> Sync for GraphSwap<T> {}
+
+impl<T> GraphSwap<T> {
+    pub fn new() -> Self {
+        Self {
+            pending: AtomicPtr::new(ptr::null_mut()),
+            retired: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Publish a new value, a previous value not yet fetched is dropped
+    pub fn publish(&self, value: Box<T>) {
+        self.collect();
+        let old = self.pending.swap(Box::into_raw(value), Ordering::AcqRel);
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+
+    /// Drop the value retired by the audio thread if any
+    pub fn collect(&self) {
+        let old = self.retired.swap(ptr::null_mut(), Ordering::AcqRel);
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+
+    /// Replace `current` by the last published value, returns true if it was replaced.
+    ///
+    /// Must only be called from one thread. The replaced value is retired, while the
+    /// previously retired value has not been collected the swap is postponed.
+    pub fn fetch(&self, current: &mut Option<Box<T>>) -> bool {
+        if !self.retired.load(Ordering::Acquire).is_null() {
+            return false;
+        }
+        let new = self.pending.swap(ptr::null_mut(), Ordering::AcqRel);
+        if new.is_null() {
+            return false;
+        }
+        if let Some(old) = current.replace(unsafe { Box::from_raw(new) }) {
+            self.retired.store(Box::into_raw(old), Ordering::Release);
+        }
+        true
+    }
+}
+
+impl<T> Drop for GraphSwap<T> {
+    fn drop(&mut self) {
+        for slot in [&self.pending, &self.retired].iter() {
+            let value = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+            if !value.is_null() {
+                drop(unsafe { Box::from_raw(value) });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn retired_values_are_dropped_by_the_publisher() {
+        let swap = GraphSwap::new();
+        let first = Arc::new(());
+        let second = Arc::new(());
+        let mut current = None;
+        swap.publish(Box::new(first.clone()));
+        assert!(swap.fetch(&mut current));
+        swap.publish(Box::new(second.clone()));
+        assert!(swap.fetch(&mut current));
+        assert_eq!(Arc::strong_count(&first), 2);
+        swap.publish(Box::new(first.clone()));
+        assert_eq!(Arc::strong_count(&first), 2);
+        assert!(swap.fetch(&mut current));
+        drop(current);
+        drop(swap);
+        assert_eq!(Arc::strong_count(&first), 1);
+        assert_eq!(Arc::strong_count(&second), 1);
+    }
+}
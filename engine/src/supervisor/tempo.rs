@@ -0,0 +1,422 @@
+use serde::{Deserialize, Serialize};
+
+/// Tempo from a position of the map
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TempoPoint {
+    /// Position in quarter notes
+    pub beat: f64,
+    /// Beats per minute
+    pub bpm: f64,
+    /// Is the tempo reached by a linear ramp from the previous point instead of a jump
+    pub ramp: bool,
+}
+
+/// Time signature from the start of a bar
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeSignature {
+    /// Index of the bar, starting at 0
+    pub bar: u32,
+    pub numerator: i32,
+    pub denominator: i32,
+}
+
+impl TimeSignature {
+    /// Length of a bar in quarter notes
+    fn bar_length(&self) -> f64 {
+        f64::from(self.numerator) * 4.0 / f64::from(self.denominator)
+    }
+}
+
+/// Tempo and time signature changes of a session, converts positions between samples,
+/// seconds, beats (quarter notes) and bars
+///
+/// The map always starts with a tempo at beat 0 and a time signature at bar 0.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TempoMap {
+    /// Sorted by position
+    tempos: Vec<TempoPoint>,
+    /// Sorted by bar
+    signatures: Vec<TimeSignature>,
+}
+
+impl Clone for TempoMap {
+    fn clone(&self) -> Self {
+        Self {
+            tempos: self.tempos.clone(),
+            signatures: self.signatures.clone(),
+        }
+    }
+
+    /// Reuses the memory of the map, the audio thread follows the host map this way
+    fn clone_from(&mut self, source: &Self) {
+        self.tempos.clone_from(&source.tempos);
+        self.signatures.clone_from(&source.signatures);
+    }
+}
+
+impl TempoMap {
+    /// Create a map with a single tempo in 4/4
+    pub fn new(bpm: f64) -> Self {
+        let mut map = Self {
+            tempos: vec![TempoPoint {
+                beat: 0.0,
+                bpm: 120.0,
+                ramp: false,
+            }],
+            signatures: vec![TimeSignature {
+                bar: 0,
+                numerator: 4,
+                denominator: 4,
+            }],
+        };
+        map.set_tempo(0.0, bpm);
+        map
+    }
+
+    pub fn tempos(&self) -> &[TempoPoint] {
+        &self.tempos
+    }
+
+    pub fn time_signatures(&self) -> &[TimeSignature] {
+        &self.signatures
+    }
+
+    /// Check the invariants kept by the setters, e.g. on a deserialized map: a positive tempo
+    /// at beat 0 and a time signature at bar 0 come first, the positions are increasing
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self.tempos.first() {
+            Some(first) if first.beat == 0.0 && !first.ramp => {}
+            _ => return Err("the tempo map must start with a tempo at beat 0"),
+        }
+        if self
+            .tempos
+            .iter()
+            .any(|point| point.bpm <= 0.0 || !point.bpm.is_finite() || !point.beat.is_finite())
+        {
+            return Err("the tempos must be positive");
+        }
+        if self
+            .tempos
+            .windows(2)
+            .any(|pair| pair[0].beat >= pair[1].beat)
+        {
+            return Err("the tempos must be sorted by position");
+        }
+        match self.signatures.first() {
+            Some(first) if first.bar == 0 => {}
+            _ => return Err("the tempo map must start with a time signature at bar 0"),
+        }
+        if self
+            .signatures
+            .iter()
+            .any(|signature| signature.numerator <= 0 || signature.denominator <= 0)
+        {
+            return Err("the time signatures must be positive");
+        }
+        if self
+            .signatures
+            .windows(2)
+            .any(|pair| pair[0].bar >= pair[1].bar)
+        {
+            return Err("the time signatures must be sorted by bar");
+        }
+        Ok(())
+    }
+
+    fn insert_tempo(&mut self, point: TempoPoint) {
+        if point.bpm <= 0.0 || point.beat < 0.0 || !point.bpm.is_finite() {
+            return;
+        }
+        match self
+            .tempos
+            .iter()
+            .position(|other| other.beat >= point.beat)
+        {
+            Some(idx) if self.tempos[idx].beat == point.beat => self.tempos[idx] = point,
+            Some(idx) => self.tempos.insert(idx, point),
+            None => self.tempos.push(point),
+        }
+        // The first tempo has nothing to ramp from
+        self.tempos[0].ramp = false;
+    }
+
+    /// Jump to a tempo at a position in quarter notes, the tempo must be positive
+    pub fn set_tempo(&mut self, beat: f64, bpm: f64) {
+        self.insert_tempo(TempoPoint {
+            beat,
+            bpm,
+            ramp: false,
+        });
+    }
+
+    /// Ramp linearly from the previous tempo to `bpm`, reached at `beat`
+    pub fn ramp_tempo(&mut self, beat: f64, bpm: f64) {
+        self.insert_tempo(TempoPoint {
+            beat,
+            bpm,
+            ramp: true,
+        });
+    }
+
+    /// Remove the tempo change at `beat`, the first tempo can't be removed
+    pub fn remove_tempo(&mut self, beat: f64) {
+        if beat > 0.0 {
+            self.tempos.retain(|point| point.beat != beat);
+        }
+    }
+
+    /// Change the time signature from the start of a bar, e.g. `(6, 8)`
+    pub fn set_time_signature(&mut self, bar: u32, numerator: i32, denominator: i32) {
+        if numerator <= 0 || denominator <= 0 {
+            return;
+        }
+        let signature = TimeSignature {
+            bar,
+            numerator,
+            denominator,
+        };
+        match self.signatures.iter().position(|other| other.bar >= bar) {
+            Some(idx) if self.signatures[idx].bar == bar => self.signatures[idx] = signature,
+            Some(idx) => self.signatures.insert(idx, signature),
+            None => self.signatures.push(signature),
+        }
+    }
+
+    /// Remove the time signature change at `bar`, the first one can't be removed
+    pub fn remove_time_signature(&mut self, bar: u32) {
+        if bar > 0 {
+            self.signatures.retain(|signature| signature.bar != bar);
+        }
+    }
+
+    /// Tempo segments: start point, tempo at the end of the segment if it ramps, end position
+    fn segments(&self) -> impl Iterator<Item = (TempoPoint, Option<f64>, f64)> + '_ {
+        self.tempos
+            .iter()
+            .enumerate()
+            .map(move |(idx, point)| match self.tempos.get(idx + 1) {
+                Some(next) if next.ramp => (*point, Some(next.bpm), next.beat),
+                Some(next) => (*point, None, next.beat),
+                None => (*point, None, std::f64::INFINITY),
+            })
+    }
+
+    /// Tempo in beats per minute at a position in quarter notes
+    pub fn tempo_at(&self, beat: f64) -> f64 {
+        for (start, ramp, end) in self.segments() {
+            if beat < end {
+                return match ramp {
+                    Some(bpm) if beat > start.beat => {
+                        start.bpm + (bpm - start.bpm) * (beat - start.beat) / (end - start.beat)
+                    }
+                    _ => start.bpm,
+                };
+            }
+        }
+        self.tempos[self.tempos.len() - 1].bpm
+    }
+
+    /// Seconds from the start of a segment to `beat`, inside the segment
+    fn segment_seconds(start: &TempoPoint, ramp: Option<f64>, end: f64, beat: f64) -> f64 {
+        let slope = ramp.map(|bpm| (bpm - start.bpm) / (end - start.beat));
+        match slope {
+            // The tempo integral of a linear ramp is logarithmic
+            Some(slope) if slope.abs() > std::f64::EPSILON => {
+                let bpm = start.bpm + slope * (beat - start.beat);
+                60.0 / slope * (bpm / start.bpm).ln()
+            }
+            _ => (beat - start.beat) * 60.0 / start.bpm,
+        }
+    }
+
+    /// Convert a position in quarter notes into seconds
+    pub fn beats_to_seconds(&self, beat: f64) -> f64 {
+        let mut seconds = 0.0;
+        for (start, ramp, end) in self.segments() {
+            let to = beat.min(end);
+            if to <= start.beat {
+                break;
+            }
+            seconds += Self::segment_seconds(&start, ramp, end, to);
+        }
+        seconds
+    }
+
+    /// Convert seconds into a position in quarter notes
+    pub fn seconds_to_beats(&self, seconds: f64) -> f64 {
+        let mut elapsed = 0.0;
+        for (start, ramp, end) in self.segments() {
+            let length = if end.is_finite() {
+                Self::segment_seconds(&start, ramp, end, end)
+            } else {
+                std::f64::INFINITY
+            };
+            let left = seconds - elapsed;
+            if left < length {
+                let slope = ramp.map(|bpm| (bpm - start.bpm) / (end - start.beat));
+                return match slope {
+                    Some(slope) if slope.abs() > std::f64::EPSILON => {
+                        start.beat + start.bpm * ((slope * left / 60.0).exp() - 1.0) / slope
+                    }
+                    _ => start.beat + left * start.bpm / 60.0,
+                };
+            }
+            elapsed += length;
+        }
+        0.0
+    }
+
+    /// Convert a position in quarter notes into samples
+    pub fn beats_to_samples(&self, beat: f64, sample_rate: f64) -> f64 {
+        self.beats_to_seconds(beat) * sample_rate
+    }
+
+    /// Convert a position in samples into quarter notes
+    pub fn samples_to_beats(&self, samples: f64, sample_rate: f64) -> f64 {
+        self.seconds_to_beats(samples / sample_rate)
+    }
+
+    /// Time signature segments: signature, position of its first bar in quarter notes
+    fn bars(&self) -> impl Iterator<Item = (TimeSignature, f64)> + '_ {
+        let mut beat = 0.0;
+        let mut previous: Option<TimeSignature> = None;
+        self.signatures.iter().map(move |signature| {
+            if let Some(previous) = previous {
+                beat += f64::from(signature.bar - previous.bar) * previous.bar_length();
+            }
+            previous = Some(*signature);
+            (*signature, beat)
+        })
+    }
+
+    /// Find the time signature of a position in quarter notes, with the position of its
+    /// first bar
+    fn signature_at(&self, beat: f64) -> (TimeSignature, f64) {
+        self.bars()
+            .take_while(|(_, start)| *start <= beat)
+            .last()
+            .unwrap_or((self.signatures[0], 0.0))
+    }
+
+    /// Time signature at a position in quarter notes
+    pub fn time_signature_at(&self, beat: f64) -> (i32, i32) {
+        let (signature, _) = self.signature_at(beat);
+        (signature.numerator, signature.denominator)
+    }
+
+    /// Convert a position in quarter notes into bars, the fractional part is the position
+    /// inside the bar
+    pub fn beats_to_bars(&self, beat: f64) -> f64 {
+        let (signature, start) = self.signature_at(beat);
+        f64::from(signature.bar) + (beat - start) / signature.bar_length()
+    }
+
+    /// Convert a position in bars into quarter notes
+    pub fn bars_to_beats(&self, bars: f64) -> f64 {
+        let (signature, start) = self
+            .bars()
+            .take_while(|(signature, _)| f64::from(signature.bar) <= bars)
+            .last()
+            .unwrap_or((self.signatures[0], 0.0));
+        start + (bars - f64::from(signature.bar)) * signature.bar_length()
+    }
+
+    /// Position in quarter notes of the start of the bar containing `beat`
+    pub fn bar_start(&self, beat: f64) -> f64 {
+        self.bars_to_beats(self.beats_to_bars(beat).floor())
+    }
+}
+
+impl Default for TempoMap {
+    fn default() -> Self {
+        Self::new(120.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn validate() {
+        let mut map = TempoMap::new(90.0);
+        map.ramp_tempo(4.0, 120.0);
+        map.set_time_signature(2, 6, 8);
+        assert_eq!(map.validate(), Ok(()));
+        let mut empty = map.clone();
+        empty.tempos.clear();
+        assert!(empty.validate().is_err());
+        let mut unsorted = map.clone();
+        unsorted.signatures.reverse();
+        assert!(unsorted.validate().is_err());
+        let mut late = map.clone();
+        late.signatures.remove(0);
+        assert!(late.validate().is_err());
+        let mut stopped = map;
+        stopped.tempos[1].bpm = 0.0;
+        assert!(stopped.validate().is_err());
+    }
+
+    #[test]
+    fn constant_tempo() {
+        let map = TempoMap::new(90.0);
+        assert_close(map.beats_to_seconds(3.0), 2.0);
+        assert_close(map.seconds_to_beats(2.0), 3.0);
+        assert_close(map.beats_to_samples(1.5, 48000.0), 48000.0);
+        assert_close(map.samples_to_beats(48000.0, 48000.0), 1.5);
+        assert_eq!(map.tempo_at(100.0), 90.0);
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let mut map = TempoMap::new(60.0);
+        map.ramp_tempo(4.0, 120.0);
+        map.set_tempo(8.0, 30.0);
+        assert_eq!(map.tempo_at(2.0), 90.0);
+        assert_eq!(map.tempo_at(6.0), 120.0);
+        assert_eq!(map.tempo_at(9.0), 30.0);
+        // Ramp from 60 to 120 BPM over 4 beats: 60 / 15 * ln(2) seconds
+        let ramp = 4.0 * 2f64.ln();
+        assert_close(map.beats_to_seconds(4.0), ramp);
+        assert_close(map.beats_to_seconds(8.0), ramp + 2.0);
+        assert_close(map.beats_to_seconds(9.0), ramp + 4.0);
+        for beat in &[0.5, 2.0, 3.9, 4.0, 6.0, 8.5, 12.0] {
+            assert_close(map.seconds_to_beats(map.beats_to_seconds(*beat)), *beat);
+        }
+        map.remove_tempo(8.0);
+        assert_eq!(map.tempo_at(9.0), 120.0);
+        map.set_tempo(0.0, -1.0);
+        assert_eq!(map.tempos()[0].bpm, 60.0);
+    }
+
+    #[test]
+    fn time_signature_changes() {
+        let mut map = TempoMap::default();
+        map.set_time_signature(2, 3, 4);
+        map.set_time_signature(4, 6, 8);
+        // Bars 0 and 1 in 4/4, 2 and 3 in 3/4, then 6/8
+        assert_eq!(map.bars_to_beats(2.0), 8.0);
+        assert_eq!(map.bars_to_beats(4.0), 14.0);
+        assert_eq!(map.bars_to_beats(5.5), 18.5);
+        assert_eq!(map.beats_to_bars(9.5), 2.5);
+        assert_eq!(map.beats_to_bars(18.5), 5.5);
+        assert_eq!(map.bar_start(13.0), 11.0);
+        assert_eq!(map.time_signature_at(13.0), (3, 4));
+        assert_eq!(map.time_signature_at(14.0), (6, 8));
+        map.remove_time_signature(2);
+        assert_eq!(map.bars_to_beats(4.0), 16.0);
+    }
+
+    #[test]
+    fn serialize() {
+        let mut map = TempoMap::new(100.0);
+        map.ramp_tempo(16.0, 140.0);
+        map.set_time_signature(3, 7, 8);
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(serde_json::from_str::<TempoMap>(&json).unwrap(), map);
+    }
+}
@@ -0,0 +1,413 @@
+use crate::supervisor::tempo::TempoMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vst::api::{TimeInfo, TimeInfoFlags};
+
+/// MIDI clock resolution, in ticks per quarter note
+const MIDI_CLOCKS_PER_QUARTER: f64 = 24.0;
+
+/// Musical time of the host, advanced by the supervisor after every block and reported to the
+/// plugins through `Host::get_time_info`
+///
+/// Positions are in samples, musical positions (loop range, PPQ) in quarter notes. The
+/// musical position is computed from a `TempoMap` whenever the transport moves, the transport
+/// itself is a plain value that can be copied to the audio thread or a bridge process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transport {
+    sample_rate: f64,
+    /// Position of the block being processed
+    sample_pos: f64,
+    ppq_pos: f64,
+    bar_start_pos: f64,
+    /// Beats per minute at `ppq_pos`
+    tempo: f64,
+    time_sig_numerator: i32,
+    time_sig_denominator: i32,
+    /// Loop range in quarter notes, playback jumps back to the start once the end is reached
+    loop_range: Option<(f64, f64)>,
+    playing: bool,
+    recording: bool,
+    /// Did the play, loop or record state change since the last block
+    changed: bool,
+}
+
+impl Transport {
+    /// Create a stopped transport at 120 BPM in 4/4, see `update` to follow another tempo map
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            sample_pos: 0.0,
+            ppq_pos: 0.0,
+            bar_start_pos: 0.0,
+            tempo: 120.0,
+            time_sig_numerator: 4,
+            time_sig_denominator: 4,
+            loop_range: None,
+            playing: false,
+            recording: false,
+            changed: false,
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Change the sample rate, the position in samples is kept
+    pub fn set_sample_rate(&mut self, sample_rate: f64, map: &TempoMap) {
+        self.sample_rate = sample_rate;
+        self.update(map);
+    }
+
+    /// Tempo at the current position in beats per minute
+    pub fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    /// Time signature at the current position
+    pub fn time_signature(&self) -> (i32, i32) {
+        (self.time_sig_numerator, self.time_sig_denominator)
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn play(&mut self) {
+        self.changed |= !self.playing;
+        self.playing = true;
+    }
+
+    /// Stop the transport, the position is kept
+    pub fn stop(&mut self) {
+        self.changed |= self.playing;
+        self.playing = false;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.changed |= self.recording != recording;
+        self.recording = recording;
+    }
+
+    pub fn loop_range(&self) -> Option<(f64, f64)> {
+        self.loop_range
+    }
+
+    /// Loop between two positions in quarter notes, an empty range disables the loop
+    pub fn set_loop(&mut self, range: Option<(f64, f64)>) {
+        let range = range.filter(|(start, end)| *start >= 0.0 && start < end);
+        self.changed |= self.loop_range != range;
+        self.loop_range = range;
+    }
+
+    /// Position of the current block in samples
+    pub fn sample_pos(&self) -> f64 {
+        self.sample_pos
+    }
+
+    /// Move to a position in samples
+    pub fn seek(&mut self, sample_pos: f64, map: &TempoMap) {
+        self.sample_pos = sample_pos.max(0.0);
+        self.changed = true;
+        self.update(map);
+    }
+
+    /// Compute the musical position again, to be called when the tempo map changes
+    pub fn update(&mut self, map: &TempoMap) {
+        self.ppq_pos = map.samples_to_beats(self.sample_pos, self.sample_rate);
+        self.bar_start_pos = map.bar_start(self.ppq_pos);
+        self.tempo = map.tempo_at(self.ppq_pos);
+        let (numerator, denominator) = map.time_signature_at(self.ppq_pos);
+        self.time_sig_numerator = numerator;
+        self.time_sig_denominator = denominator;
+    }
+
+    /// Samples per quarter note at the current tempo
+    fn samples_per_quarter(&self) -> f64 {
+        self.sample_rate * 60.0 / self.tempo
+    }
+
+    /// Position of the current block in quarter notes
+    pub fn ppq_pos(&self) -> f64 {
+        self.ppq_pos
+    }
+
+    /// Position of the last bar start in quarter notes
+    pub fn bar_start_pos(&self) -> f64 {
+        self.bar_start_pos
+    }
+
+    /// Move to the next block, the position only moves while playing and wraps around the
+    /// loop range at the block boundary
+    pub fn advance(&mut self, frames: usize, map: &TempoMap) {
+        self.changed = false;
+        if !self.playing {
+            return;
+        }
+        let position = self.sample_pos + frames as f64;
+        self.sample_pos = match self.loop_range {
+            Some((start, end)) => {
+                let start = map.beats_to_samples(start, self.sample_rate);
+                let end = map.beats_to_samples(end, self.sample_rate);
+                if self.sample_pos < end && position >= end {
+                    start + (position - end) % (end - start)
+                } else {
+                    position
+                }
+            }
+            None => position,
+        };
+        self.update(map);
+    }
+
+    /// Split the block starting at the transport position into the parts played at
+    /// consecutive positions: the block is only split where it wraps around the loop range,
+    /// the same way `advance` moves the next block
+    pub fn segments(&self, frames: usize, map: &TempoMap) -> BlockSegments {
+        let loop_range = self.loop_range.map(|(start, end)| {
+            (
+                map.beats_to_samples(start, self.sample_rate),
+                map.beats_to_samples(end, self.sample_rate),
+            )
+        });
+        BlockSegments {
+            position: self.sample_pos,
+            frame: 0,
+            frames,
+            loop_range,
+        }
+    }
+
+    /// Build the time informations requested by a plugin, `mask` is made of `TimeInfoFlags`
+    ///
+    /// The sample position, sample rate and transport state are always filled, the other
+    /// fields only when requested.
+    pub fn time_info(&self, mask: i32) -> TimeInfo {
+        let mask = TimeInfoFlags::from_bits_truncate(mask);
+        let mut flags = TimeInfoFlags::empty();
+        flags.set(TimeInfoFlags::TRANSPORT_CHANGED, self.changed);
+        flags.set(TimeInfoFlags::TRANSPORT_PLAYING, self.playing);
+        flags.set(TimeInfoFlags::TRANSPORT_RECORDING, self.recording);
+        flags.set(
+            TimeInfoFlags::TRANSPORT_CYCLE_ACTIVE,
+            self.loop_range.is_some(),
+        );
+        let mut info = TimeInfo {
+            sample_pos: self.sample_pos,
+            sample_rate: self.sample_rate,
+            ..TimeInfo::default()
+        };
+        if mask.contains(TimeInfoFlags::NANOSECONDS_VALID) {
+            if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                info.nanoseconds = now.as_nanos() as f64;
+                flags |= TimeInfoFlags::NANOSECONDS_VALID;
+            }
+        }
+        if mask.contains(TimeInfoFlags::PPQ_POS_VALID) {
+            info.ppq_pos = self.ppq_pos;
+            flags |= TimeInfoFlags::PPQ_POS_VALID;
+        }
+        if mask.contains(TimeInfoFlags::TEMPO_VALID) {
+            info.tempo = self.tempo;
+            flags |= TimeInfoFlags::TEMPO_VALID;
+        }
+        if mask.contains(TimeInfoFlags::BARS_VALID) {
+            info.bar_start_pos = self.bar_start_pos;
+            flags |= TimeInfoFlags::BARS_VALID;
+        }
+        if mask.contains(TimeInfoFlags::CYCLE_POS_VALID) {
+            if let Some((start, end)) = self.loop_range {
+                info.cycle_start_pos = start;
+                info.cycle_end_pos = end;
+                flags |= TimeInfoFlags::CYCLE_POS_VALID;
+            }
+        }
+        if mask.contains(TimeInfoFlags::TIME_SIG_VALID) {
+            info.time_sig_numerator = self.time_sig_numerator;
+            info.time_sig_denominator = self.time_sig_denominator;
+            flags |= TimeInfoFlags::TIME_SIG_VALID;
+        }
+        if mask.contains(TimeInfoFlags::VST_CLOCK_VALID) {
+            // Distance to the nearest clock, negative when it is behind
+            let clocks = self.ppq_pos * MIDI_CLOCKS_PER_QUARTER;
+            let offset = (clocks.round() - clocks) / MIDI_CLOCKS_PER_QUARTER;
+            info.samples_to_next_clock = (offset * self.samples_per_quarter()).round() as i32;
+            flags |= TimeInfoFlags::VST_CLOCK_VALID;
+        }
+        info.flags = flags.bits();
+        info
+    }
+}
+
+/// Part of a block played at consecutive positions, see `Transport::segments`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockSegment {
+    /// Position of the first frame in the block
+    pub frame: usize,
+    pub frames: usize,
+    /// Position of the first frame in samples
+    pub sample_pos: f64,
+}
+
+/// Iterator over the segments of a block
+pub struct BlockSegments {
+    position: f64,
+    frame: usize,
+    frames: usize,
+    /// Loop range in samples
+    loop_range: Option<(f64, f64)>,
+}
+
+impl Iterator for BlockSegments {
+    type Item = BlockSegment;
+
+    fn next(&mut self) -> Option<BlockSegment> {
+        if self.frame >= self.frames {
+            return None;
+        }
+        let left = self.frames - self.frame;
+        let segment = BlockSegment {
+            frame: self.frame,
+            frames: left,
+            sample_pos: self.position,
+        };
+        let end = self.position + left as f64;
+        match self.loop_range {
+            Some((start, end_pos)) if self.position < end_pos && end > end_pos => {
+                // The frames from the loop end on are played from the loop start
+                let frames = ((end_pos - self.position).ceil() as usize).min(left);
+                self.frame += frames;
+                self.position = start + self.position + frames as f64 - end_pos;
+                Some(BlockSegment { frames, ..segment })
+            }
+            _ => {
+                self.frame = self.frames;
+                self.position = end;
+                Some(segment)
+            }
+        }
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::new(44100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn musical_position() {
+        let mut map = TempoMap::new(60.0);
+        map.set_time_signature(0, 3, 4);
+        let mut transport = Transport::new(48000.0);
+        transport.play();
+        transport.advance(48000 * 4 + 12000, &map);
+        assert_eq!(transport.ppq_pos(), 4.25);
+        assert_eq!(transport.bar_start_pos(), 3.0);
+
+        let info = transport.time_info(TimeInfoFlags::PPQ_POS_VALID.bits());
+        let flags = TimeInfoFlags::from_bits_truncate(info.flags);
+        assert_eq!(info.sample_pos, 204000.0);
+        assert_eq!(info.ppq_pos, 4.25);
+        assert_eq!(info.tempo, 0.0);
+        assert!(flags.contains(TimeInfoFlags::TRANSPORT_PLAYING | TimeInfoFlags::PPQ_POS_VALID));
+        assert!(!flags.contains(TimeInfoFlags::TEMPO_VALID));
+        assert!(!flags.contains(TimeInfoFlags::TRANSPORT_CHANGED));
+
+        let info = transport.time_info(
+            (TimeInfoFlags::TEMPO_VALID
+                | TimeInfoFlags::BARS_VALID
+                | TimeInfoFlags::TIME_SIG_VALID)
+                .bits(),
+        );
+        assert_eq!(info.tempo, 60.0);
+        assert_eq!(info.bar_start_pos, 3.0);
+        assert_eq!((info.time_sig_numerator, info.time_sig_denominator), (3, 4));
+        // 4.25 quarter notes fall on a clock (24 per quarter note)
+        let info = transport.time_info(TimeInfoFlags::VST_CLOCK_VALID.bits());
+        assert_eq!(info.samples_to_next_clock, 0);
+    }
+
+    #[test]
+    fn loop_and_state_changes() {
+        let map = TempoMap::default();
+        let mut transport = Transport::new(48000.0);
+        transport.set_loop(Some((1.0, 2.0)));
+        transport.advance(48000, &map);
+        assert_eq!(transport.sample_pos(), 0.0);
+        transport.play();
+        let flags = TimeInfoFlags::from_bits_truncate(transport.time_info(0).flags);
+        assert!(flags
+            .contains(TimeInfoFlags::TRANSPORT_CHANGED | TimeInfoFlags::TRANSPORT_CYCLE_ACTIVE));
+        // 120 BPM, 24000 samples per quarter note: the loop spans [24000, 48000[
+        transport.advance(40000, &map);
+        transport.advance(10000, &map);
+        assert_eq!(transport.sample_pos(), 26000.0);
+        assert_eq!(transport.ppq_pos(), 26000.0 / 24000.0);
+        let info = transport.time_info(TimeInfoFlags::CYCLE_POS_VALID.bits());
+        assert_eq!((info.cycle_start_pos, info.cycle_end_pos), (1.0, 2.0));
+        assert!(!TimeInfoFlags::from_bits_truncate(info.flags)
+            .contains(TimeInfoFlags::TRANSPORT_CHANGED));
+        transport.set_loop(Some((2.0, 2.0)));
+        assert_eq!(transport.loop_range(), None);
+    }
+
+    #[test]
+    fn block_segments() {
+        let map = TempoMap::new(60.0);
+        let mut transport = Transport::new(10.0);
+        transport.seek(5.0, &map);
+        let segment = |frame, frames, sample_pos| BlockSegment {
+            frame,
+            frames,
+            sample_pos,
+        };
+        assert_eq!(
+            transport.segments(8, &map).collect::<Vec<_>>(),
+            vec![segment(0, 8, 5.0)]
+        );
+        // The loop spans [10, 17.5[, the block wraps around it twice and ends where `advance`
+        // moves the next block
+        transport.set_loop(Some((1.0, 1.75)));
+        assert_eq!(
+            transport.segments(25, &map).collect::<Vec<_>>(),
+            vec![
+                segment(0, 13, 5.0),
+                segment(13, 7, 10.5),
+                segment(20, 5, 10.0)
+            ]
+        );
+        transport.play();
+        transport.advance(25, &map);
+        assert_eq!(transport.sample_pos(), 15.0);
+    }
+
+    #[test]
+    fn follow_tempo_map() {
+        let mut map = TempoMap::new(60.0);
+        map.set_tempo(2.0, 120.0);
+        map.set_time_signature(1, 3, 8);
+        let mut transport = Transport::new(1000.0);
+        transport.play();
+        transport.advance(2500, &map);
+        // 2 beats in 2 seconds then 1 beat in half a second, bar 1 starts at beat 4
+        assert_eq!(transport.ppq_pos(), 3.0);
+        assert_eq!(transport.tempo(), 120.0);
+        assert_eq!(transport.bar_start_pos(), 0.0);
+        assert_eq!(transport.time_signature(), (4, 4));
+        transport.seek(3000.0, &map);
+        assert_eq!(transport.ppq_pos(), 4.0);
+        assert_eq!(transport.bar_start_pos(), 4.0);
+        assert_eq!(transport.time_signature(), (3, 8));
+        map.set_tempo(0.0, 30.0);
+        transport.update(&map);
+        assert_eq!(transport.ppq_pos(), 1.5);
+    }
+}
@@ -1,23 +1,326 @@
 extern crate engine;
+extern crate env_logger;
 #[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate log;
 extern crate clap;
 
-use clap::{App, SubCommand, Arg};
-use engine::prelude::*;
-use engine::supervisor::Supervisor;
+use clap::{App, Arg, ArgMatches};
+use engine::cpal::{self, traits::HostTrait};
+use engine::devices::{FileFormat, NullOutputDevice, OutputDevice, SysOutputDevice};
+use engine::loader::bridge;
+use engine::loader::catalog::{PluginCatalog, PluginScanner};
+use engine::loader::smf::MidiFile;
+use engine::prelude::VstId;
+use engine::supervisor::{tempo::TempoMap, Supervisor};
+use failure::Error;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// A parameter override given as `[plugin:]index=value`, the value is either normalized or a
+/// text the plugin converts, e.g. `-6 dB`
+struct ParamOverride {
+    plugin: usize,
+    index: i32,
+    value: String,
+}
+
+fn parse_param(param: &str) -> Result<ParamOverride, Error> {
+    let mut parts = param.splitn(2, '=');
+    let target = parts.next().unwrap_or_default();
+    let value = parts
+        .next()
+        .ok_or_else(|| format_err!("Invalid parameter `{}`, expected index=value", param))?;
+    let (plugin, index) = match target.find(':') {
+        Some(pos) => (target[..pos].parse()?, &target[pos + 1..]),
+        None => (0, target),
+    };
+    Ok(ParamOverride {
+        plugin,
+        index: index.parse()?,
+        value: value.to_string(),
+    })
+}
+
+/// Split a preset given as `[plugin:]path`, the prefix must be a number so drive letters are
+/// kept in the path
+fn parse_preset(preset: &str) -> Result<(usize, &Path), Error> {
+    match preset.find(':') {
+        Some(pos) if preset[..pos].chars().all(|c| c.is_ascii_digit()) && pos > 0 => {
+            Ok((preset[..pos].parse()?, Path::new(&preset[pos + 1..])))
+        }
+        _ => Ok((0, Path::new(preset))),
+    }
+}
+
+/// Print the parameters of every plugin of the chain
+fn list_params(supervisor: &Supervisor, chain: &[VstId]) -> Result<(), Error> {
+    for (position, id) in chain.iter().enumerate() {
+        let plugin = supervisor.plugins[id].lock().unwrap();
+        let parameters = plugin.parameters();
+        println!("{}: {}", position, plugin.get_info().name);
+        drop(plugin);
+        for param in parameters.list()? {
+            println!(
+                "  {:>3} {} = {} {} ({:.3}){}",
+                param.index,
+                param.name,
+                param.text,
+                param.label,
+                param.value,
+                if param.can_be_automated {
+                    ""
+                } else {
+                    ", not automatable"
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+fn parse_opt<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, Error>
+where
+    T::Err: failure::Fail,
+{
+    Ok(match matches.value_of(name) {
+        Some(value) => Some(value.parse()?),
+        None => None,
+    })
+}
+
+/// Scan the plugin directories and return the updated catalog, without directories the
+/// catalog is only read
+fn scan_plugins(matches: &ArgMatches) -> Result<PluginCatalog, Error> {
+    let catalog_path = matches.value_of("catalog").unwrap_or("plugins.json");
+    let directories = match matches.values_of("plugin-dir") {
+        Some(directories) => directories,
+        None => return Ok(PluginCatalog::load(catalog_path)?),
+    };
+    let mut scanner = PluginScanner::new(catalog_path);
+    for directory in directories {
+        scanner.add_directory(directory);
+    }
+    let catalog = scanner.scan()?;
+    for entry in catalog.blacklist() {
+        warn!("Skipped {}: {}", entry.path.display(), entry.reason);
+    }
+    Ok(catalog)
+}
+
+/// Resolve a `--vst` value, either a library path or the name of a scanned plugin
+fn plugin_path(catalog: &PluginCatalog, vst: &str) -> Result<PathBuf, Error> {
+    if Path::new(vst).exists() {
+        return Ok(PathBuf::from(vst));
+    }
+    catalog
+        .find(vst)
+        .map(|entry| entry.path.clone())
+        .ok_or_else(|| format_err!("No plugin found at `{}` nor named `{}`", vst, vst))
+}
+
+fn run(matches: ArgMatches) -> Result<(), Error> {
+    let sample_rate: Option<u32> = parse_opt(&matches, "sample-rate")?;
+    let block_size: Option<u32> = parse_opt(&matches, "block-size")?;
+    let tempo: Option<f64> = parse_opt(&matches, "tempo")?;
+    let params = matches
+        .values_of("param")
+        .into_iter()
+        .flatten()
+        .map(parse_param)
+        .collect::<Result<Vec<_>, _>>()?;
+    let presets = matches
+        .values_of("preset")
+        .into_iter()
+        .flatten()
+        .map(parse_preset)
+        .collect::<Result<Vec<_>, _>>()?;
+    let output_path = matches.value_of("output").map(Path::new);
+
+    let cpal_host = cpal::default_host();
+    // Nothing is played when the parameters are listed
+    let offline = output_path.is_some() || matches.is_present("list-params");
+    let main_output: Box<dyn OutputDevice> = if offline {
+        Box::new(NullOutputDevice::new(
+            sample_rate.unwrap_or(44100),
+            block_size.unwrap_or(512),
+        ))
+    } else {
+        let device = cpal_host
+            .default_output_device()
+            .ok_or_else(|| format_err!("No output device available"))?;
+        Box::new(SysOutputDevice::with_config(
+            device,
+            cpal_host.event_loop(),
+            sample_rate,
+            block_size,
+        )?)
+    };
+    let sample_rate = main_output.get_sample_rate();
+    let mut supervisor = Supervisor::with_output(cpal_host, main_output);
+    let catalog = scan_plugins(&matches)?;
+    let midi_files = matches
+        .values_of("midi")
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            MidiFile::from_path(path)
+                .map(|file| (path, file))
+                .map_err(|err| format_err!("Unable to load {}: {}", path, err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // The first MIDI file sets the tempo unless it is given
+    let tempo_map = match (tempo, midi_files.first()) {
+        (Some(tempo), _) => Some(TempoMap::new(tempo)),
+        (None, Some((_, file))) => Some(file.tempo_map()),
+        (None, None) => None,
+    };
+    if let Some(tempo_map) = tempo_map {
+        supervisor.vst_host.lock().unwrap().set_tempo_map(tempo_map);
+    }
+    if matches.is_present("bridge") {
+        supervisor.set_bridge(Some(bridge::default_executable()?));
+    }
+    // The other options add to the project
+    if let Some(path) = matches.value_of("project") {
+        // The project is played without the missing parts
+        for issue in supervisor.load_project(path)? {
+            error!("{}", issue);
+        }
+    }
+
+    // Plugins are piped one after the other, the last one plays into the main output
+    let mut chain = Vec::new();
+    for vst in matches.values_of("vst").into_iter().flatten() {
+        let path = plugin_path(&catalog, vst)?;
+        let id = supervisor.load_vst(&path)?;
+        let plugin = supervisor.plugins[&id].lock().unwrap();
+        info!("Loaded {} ({})", plugin.get_info().name, path.display());
+        chain.push((
+            id,
+            plugin.get_inputs(),
+            plugin.get_outputs(),
+            plugin.get_midi_input(),
+        ));
+    }
+    for (plugin, path) in presets {
+        let (id, _, _, _) = chain
+            .get(plugin)
+            .ok_or_else(|| format_err!("No plugin at position {}", plugin))?;
+        supervisor.plugins[id]
+            .lock()
+            .unwrap()
+            .load_preset(path)
+            .map_err(|err| format_err!("Cannot load `{}`: {}", path.display(), err))?;
+    }
+    for param in params.iter() {
+        let (id, _, _, _) = chain
+            .get(param.plugin)
+            .ok_or_else(|| format_err!("No plugin at position {}", param.plugin))?;
+        let parameters = supervisor.plugins[id].lock().unwrap().parameters();
+        match param.value.parse::<f32>() {
+            Ok(value) => parameters.set(param.index, value)?,
+            Err(_) => {
+                if !parameters.set_text(param.index, &param.value)? {
+                    bail!(
+                        "The plugin doesn't understand `{}` for parameter {}",
+                        param.value,
+                        param.index
+                    );
+                }
+            }
+        }
+    }
+    if matches.is_present("list-params") {
+        let ids: Vec<_> = chain.iter().map(|(id, _, _, _)| *id).collect();
+        return list_params(&supervisor, &ids);
+    }
+    for pair in chain.windows(2) {
+        supervisor.linker.pipe(pair[0].2, pair[1].1)?;
+    }
+    if let Some((_, _, output, _)) = chain.last() {
+        supervisor.linker.pipe(*output, supervisor.main_input)?;
+    }
+    let chain_input = chain
+        .first()
+        .map(|(_, input, _, _)| *input)
+        .unwrap_or(supervisor.main_input);
+
+    if !midi_files.is_empty() {
+        let (_, _, _, midi_input) = chain
+            .first()
+            .ok_or_else(|| format_err!("MIDI files need a plugin to play them"))?;
+        for (path, file) in midi_files.iter() {
+            let output = supervisor.add_midi_file(path, file, None);
+            supervisor.linker.pipe_midi(output, *midi_input)?;
+        }
+    }
+
+    for path in matches.values_of("sample").into_iter().flatten() {
+        let output = supervisor
+            .load_asset(path)
+            .map_err(|err| format_err!("Unable to load {}: {}", path, err))?;
+        supervisor.linker.pipe(output, chain_input)?;
+    }
+
+    if let Some(path) = matches.value_of("save-project") {
+        supervisor.save_project(path)?;
+        info!("Saved the project {}", path);
+    }
+
+    match output_path {
+        Some(path) => {
+            let format = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) if ext.eq_ignore_ascii_case("flac") => FileFormat::Flac(24),
+                _ => FileFormat::WavFloat,
+            };
+            let frames = supervisor.render(path, format, None)?;
+            info!(
+                "Wrote {} ({:.2}s)",
+                path.display(),
+                frames as f64 / f64::from(sample_rate)
+            );
+        }
+        None => {
+            supervisor.start()?;
+            while !supervisor.is_finished() {
+                thread::sleep(Duration::from_millis(50));
+                supervisor.update_latency()?;
+            }
+            let tail = (supervisor.get_tail_size() + supervisor.get_latency()) as u64;
+            thread::sleep(Duration::from_millis(tail * 1000 / u64::from(sample_rate)));
+            supervisor.stop();
+        }
+    }
+    Ok(())
+}
 
 fn main() {
+    env_logger::init();
     let matches = App::new("naama-cli")
         .version("1.0")
         .author("Asya c. <asya.corbeau.dev@gmail.com>")
         .about("A simple VST host")
-        .arg(Arg::with_name("vst").short("v").long("vst").required(true).takes_value(true).multiple(true).help("Load a VST from its path"))
-        .arg(Arg::with_name("sample").short("s").required(true).long("sample").takes_value(true).multiple(true).help("Load a `.flac` sample from its path"))
+        .arg(Arg::with_name("vst").short("v").long("vst").takes_value(true).multiple(true).number_of_values(1).help("Load a VST from its path or its name in the catalog, plugins are chained in the given order"))
+        .arg(Arg::with_name("bridge").long("bridge").help("Run every plugin in its own process, a crashing plugin is muted instead of stopping the host"))
+        .arg(Arg::with_name("plugin-dir").short("d").long("plugin-dir").takes_value(true).multiple(true).number_of_values(1).help("Scan a directory for plugins, they can then be loaded by name"))
+        .arg(Arg::with_name("catalog").short("c").long("catalog").takes_value(true).help("File the scanned plugins are kept in (default: plugins.json)"))
+        .arg(Arg::with_name("sample").short("s").required_unless_one(&["midi", "list-params", "project"]).long("sample").takes_value(true).multiple(true).number_of_values(1).help("Load a sample (FLAC, WAV, AIFF or Ogg Vorbis) from its path, every sample is played through the plugins chain"))
+        .arg(Arg::with_name("midi").short("m").long("midi").takes_value(true).multiple(true).number_of_values(1).help("Play a MIDI file (format 0 or 1) into the first plugin of the chain, its tempo is used unless `--tempo` is given"))
+        .arg(Arg::with_name("output").short("o").long("output").takes_value(true).help("Render into a `.wav` or `.flac` file instead of playing live"))
+        .arg(Arg::with_name("block-size").short("b").long("block-size").takes_value(true).help("Samples block size"))
+        .arg(Arg::with_name("sample-rate").short("r").long("sample-rate").takes_value(true).help("Sample rate in Hz"))
+        .arg(Arg::with_name("tempo").short("t").long("tempo").takes_value(true).help("Tempo in BPM reported to the plugins (default: 120)"))
+        .arg(Arg::with_name("preset").long("preset").takes_value(true).multiple(true).number_of_values(1).help("Load an FXP preset or FXB bank, prefix with `n:` to target the nth plugin of the chain. Presets are loaded before `--param`"))
+        .arg(Arg::with_name("param").short("p").long("param").takes_value(true).multiple(true).number_of_values(1).help("Set a plugin parameter as `index=value`, prefix with `n:` to target the nth plugin of the chain. The value is normalized in [0, 1] or a text like `-6 dB` if the plugin supports it"))
+        .arg(Arg::with_name("project").long("project").takes_value(true).help("Load a project file, the other options add to it"))
+        .arg(Arg::with_name("save-project").long("save-project").takes_value(true).help("Save the session into a project file before playing it"))
+        .arg(Arg::with_name("list-params").long("list-params").help("Print the parameters of the loaded plugins, after `--param` is applied, and exit"))
         .get_matches();
-    for vst in matches.values_of("vst").unwrap() {
-
+    if let Err(err) = run(matches) {
+        error!("{}", err);
+        std::process::exit(1);
     }
-    let supervisor = engine::supervisor::Supervisor::new();
-    println!("Hello, world!");
 }
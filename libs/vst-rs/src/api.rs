@@ -252,6 +252,7 @@ pub enum SpeakerArrangementType {
 
 /// Used to specify whether functionality is supported.
 #[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Supported {
     Yes,
     Maybe,
@@ -301,6 +302,23 @@ pub enum ProcessLevel {
     Offline,
 }
 
+/// Automation mode of the host, see `Host::get_automation_state`.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutomationState {
+    /// Unsupported by host.
+    Unsupported = 0,
+
+    /// The automation is neither read nor written.
+    Off,
+    /// The automation is played.
+    Read,
+    /// The parameter changes are recorded.
+    Write,
+    /// The automation is played and the parameter changes are recorded.
+    ReadWrite,
+}
+
 /// Language that the host is using.
 #[repr(i32)]
 #[allow(missing_docs)]
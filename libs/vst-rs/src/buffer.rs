@@ -381,6 +381,9 @@ pub struct SendEventBuffer {
     api_events: Vec<PlaceholderEvent>, // using SysExEvent to store both because it's larger than MidiEvent
 }
 
+// The raw pointers only point into the buffer's own allocations
+unsafe impl Send for SendEventBuffer {}
+
 impl Default for SendEventBuffer {
     fn default() -> Self {
         SendEventBuffer::new(1024)
@@ -394,7 +397,18 @@ impl SendEventBuffer {
         let header_size = mem::size_of::<api::Events>() - (mem::size_of::<*mut api::Event>() * 2);
         let body_size = mem::size_of::<*mut api::Event>() * capacity;
         let mut buf = vec![0u8; header_size + body_size];
-        let api_events = vec![unsafe { mem::zeroed::<PlaceholderEvent>() }; capacity];
+        // `EventType` has no zero variant, the placeholders are empty SysEx events instead
+        let placeholder = PlaceholderEvent {
+            event_type: api::EventType::SysEx,
+            byte_size: mem::size_of::<PlaceholderEvent>() as i32,
+            delta_frames: 0,
+            _flags: 0,
+            data_size: 0,
+            _reserved1: 0,
+            system_data: ::std::ptr::null_mut(),
+            _reserved2: 0,
+        };
+        let api_events = vec![placeholder; capacity];
         {
             let ptrs = {
                 let e = Self::buf_as_api_events(&mut buf);
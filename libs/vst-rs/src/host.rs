@@ -7,6 +7,7 @@ use std::cell::UnsafeCell;
 use std::error::Error;
 use std::ffi::CString;
 use std::mem::MaybeUninit;
+use std::ops::Range;
 use std::os::raw::c_void;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
@@ -14,7 +15,7 @@ use std::{fmt, ptr, slice};
 
 use super::editor::{Editor, Rect};
 use api::consts::*;
-use api::{self, AEffect, PluginFlags, PluginMain, Supported, TimeInfo};
+use api::{self, AEffect, AutomationState, PluginFlags, PluginMain, Supported, TimeInfo};
 use buffer::AudioBuffer;
 use channels::ChannelInfo;
 use interfaces;
@@ -184,12 +185,104 @@ pub enum OpCode {
 }
 impl_clike!(OpCode);
 
+/// Identifies the plugin instance calling the host, see `PluginInstance::instance_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InstanceId(usize);
+
+impl InstanceId {
+    /// Get the id of the instance owning `effect`.
+    pub fn from_effect(effect: *mut AEffect) -> InstanceId {
+        InstanceId(effect as usize)
+    }
+}
+
+/// Features a plugin can ask the host about, see `Host::can_do`.
+#[allow(missing_docs)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostCanDo {
+    SendEvents,
+    SendMidiEvent,
+    SendTimeInfo,
+    ReceiveEvents,
+    ReceiveMidiEvent,
+    ReportConnectionChanges,
+    AcceptIOChanges,
+    SizeWindow,
+    Offline,
+    OpenFileSelector,
+    CloseFileSelector,
+    StartStopProcess,
+    ShellCategory,
+    SendMidiEventFlagIsRealtime,
+
+    Other(String),
+}
+
+impl HostCanDo {
+    // TODO: implement FromStr
+    #![allow(clippy::should_implement_trait)]
+    /// Converts a string to a `HostCanDo` instance. Any given string that does not match the
+    /// predefined values will return a `HostCanDo::Other` value.
+    pub fn from_str(s: &str) -> HostCanDo {
+        use self::HostCanDo::*;
+
+        match s {
+            "sendVstEvents" => SendEvents,
+            "sendVstMidiEvent" => SendMidiEvent,
+            "sendVstTimeInfo" => SendTimeInfo,
+            "receiveVstEvents" => ReceiveEvents,
+            "receiveVstMidiEvent" => ReceiveMidiEvent,
+            "reportConnectionChanges" => ReportConnectionChanges,
+            "acceptIOChanges" => AcceptIOChanges,
+            "sizeWindow" => SizeWindow,
+            "offline" => Offline,
+            "openFileSelector" => OpenFileSelector,
+            "closeFileSelector" => CloseFileSelector,
+            "startStopProcess" => StartStopProcess,
+            "shellCategory" => ShellCategory,
+            "sendVstMidiEventFlagIsRealtime" => SendMidiEventFlagIsRealtime,
+            otherwise => Other(otherwise.to_string()),
+        }
+    }
+}
+
+impl Into<String> for HostCanDo {
+    fn into(self) -> String {
+        use self::HostCanDo::*;
+
+        match self {
+            SendEvents => "sendVstEvents".to_string(),
+            SendMidiEvent => "sendVstMidiEvent".to_string(),
+            SendTimeInfo => "sendVstTimeInfo".to_string(),
+            ReceiveEvents => "receiveVstEvents".to_string(),
+            ReceiveMidiEvent => "receiveVstMidiEvent".to_string(),
+            ReportConnectionChanges => "reportConnectionChanges".to_string(),
+            AcceptIOChanges => "acceptIOChanges".to_string(),
+            SizeWindow => "sizeWindow".to_string(),
+            Offline => "offline".to_string(),
+            OpenFileSelector => "openFileSelector".to_string(),
+            CloseFileSelector => "closeFileSelector".to_string(),
+            StartStopProcess => "startStopProcess".to_string(),
+            ShellCategory => "shellCategory".to_string(),
+            SendMidiEventFlagIsRealtime => "sendVstMidiEventFlagIsRealtime".to_string(),
+            Other(other) => other,
+        }
+    }
+}
+
 /// Implemented by all VST hosts.
 #[allow(unused_variables)]
 pub trait Host {
     /// Automate a parameter; the value has been changed.
     fn automate(&self, index: i32, value: f32) {}
 
+    /// Automate a parameter of the plugin `instance`; the value has been changed.
+    ///
+    /// Calls `automate` by default.
+    fn automate_instance(&self, instance: InstanceId, index: i32, value: f32) {
+        self.automate(index, value)
+    }
+
     /// Get the plugin ID of the currently loading plugin.
     ///
     /// This is only useful for shell plugins where this value will change the plugin returned.
@@ -211,9 +304,22 @@ pub trait Host {
         (1, "vendor string".to_owned(), "product string".to_owned())
     }
 
+    /// Return whether the host supports the feature asked for by a plugin.
+    fn can_do(&self, can_do: HostCanDo) -> Supported {
+        info!("Plugin is asking if host can: {:?}.", can_do);
+        Supported::Maybe
+    }
+
     /// Handle incoming events from the plugin.
     fn process_events(&self, events: &api::Events) {}
 
+    /// Handle incoming events from the plugin `instance`.
+    ///
+    /// Calls `process_events` by default.
+    fn process_instance_events(&self, instance: InstanceId, events: &api::Events) {
+        self.process_events(events)
+    }
+
     /// Get time information.
     fn get_time_info(&self, mask: i32) -> Option<TimeInfo> {
         None
@@ -228,9 +334,84 @@ pub trait Host {
         0
     }
 
-    fn get_smaple_rate(&self) -> isize {
+    /// Get the sample rate the plugins are processed at, 0 if unknown.
+    fn get_sample_rate(&self) -> f32 {
+        0.0
+    }
+
+    /// Get the latency of the audio input, in samples.
+    fn get_input_latency(&self) -> isize {
+        0
+    }
+
+    /// Get the latency of the audio output, in samples.
+    fn get_output_latency(&self) -> isize {
         0
     }
+
+    /// Get the automation mode of the host.
+    fn get_automation_state(&self) -> AutomationState {
+        AutomationState::Unsupported
+    }
+
+    /// The plugin editor asks to be resized to `width` x `height` pixels.
+    ///
+    /// Returns true if the window was resized.
+    fn size_window(&self, width: i32, height: i32) -> bool {
+        false
+    }
+
+    /// The editor of the plugin `instance` asks to be resized.
+    ///
+    /// Calls `size_window` by default.
+    fn size_window_instance(&self, instance: InstanceId, width: i32, height: i32) -> bool {
+        self.size_window(width, height)
+    }
+
+    /// The inputs, outputs or initial delay of the plugin changed.
+    ///
+    /// Returns true if the host handles the change.
+    fn io_changed(&self) -> bool {
+        false
+    }
+
+    /// The inputs, outputs or initial delay of the plugin `instance` changed.
+    ///
+    /// Calls `io_changed` by default.
+    fn io_changed_instance(&self, instance: InstanceId) -> bool {
+        self.io_changed()
+    }
+
+    /// The user started changing a parameter from the plugin editor, e.g. grabbed a knob.
+    fn begin_edit(&self, index: i32) {}
+
+    /// The user started changing a parameter of the plugin `instance`.
+    ///
+    /// Calls `begin_edit` by default.
+    fn begin_edit_instance(&self, instance: InstanceId, index: i32) {
+        self.begin_edit(index)
+    }
+
+    /// The user is done changing a parameter from the plugin editor.
+    fn end_edit(&self, index: i32) {}
+
+    /// The user is done changing a parameter of the plugin `instance`.
+    ///
+    /// Calls `end_edit` by default.
+    fn end_edit_instance(&self, instance: InstanceId, index: i32) {
+        self.end_edit(index)
+    }
+
+    /// The plugin changed something displayed by the host, e.g. its program names, and the
+    /// host should read it again.
+    fn update_display(&self) {}
+
+    /// The plugin `instance` changed something displayed by the host.
+    ///
+    /// Calls `update_display` by default.
+    fn update_display_instance(&self, instance: InstanceId) {
+        self.update_display()
+    }
 }
 
 /// All possible errors that can occur when loading a VST plugin.
@@ -250,6 +431,9 @@ pub enum PluginLoadError {
 
     /// The API version which the plugin used is not supported by this library.
     InvalidApiVersion,
+
+    /// The plugin instance does not start with the VST magic number.
+    InvalidMagic,
 }
 
 impl fmt::Display for PluginLoadError {
@@ -267,6 +451,7 @@ impl Error for PluginLoadError {
             NotAPlugin => "The given path does not contain a VST2.4 compatible library",
             InstanceFailed => "Failed to create a plugin instance",
             InvalidApiVersion => "The plugin API version is not compatible with this library",
+            InvalidMagic => "The plugin instance has an invalid magic number",
         }
     }
 }
@@ -293,6 +478,7 @@ struct PluginParametersInstance {
     effect: UnsafeCell<*mut AEffect>,
 }
 
+unsafe impl Send for PluginParametersInstance {}
 unsafe impl Sync for PluginParametersInstance {}
 
 impl Drop for PluginInstance {
@@ -383,6 +569,10 @@ impl<T: Host> PluginLoader<T> {
             return Err(PluginLoadError::InstanceFailed);
         }
 
+        if unsafe { (*effect).magic } != VST_MAGIC {
+            return Err(PluginLoadError::InvalidMagic);
+        }
+
         unsafe {
             // Move the host to the heap and add it to the `AEffect` struct for future reference
             (*effect).reserved1 = Box::into_raw(Box::new(Arc::clone(&self.host))) as isize;
@@ -444,6 +634,22 @@ impl PluginInstance {
 
         plug
     }
+
+    /// Get the flags advertised by the plugin in its `AEffect` struct.
+    pub fn get_flags(&self) -> PluginFlags {
+        unsafe { PluginFlags::from_bits_truncate((*self.get_effect()).flags) }
+    }
+
+    /// Get the initial delay advertised by the plugin, it may change when the plugin calls
+    /// `IOChanged`.
+    pub fn get_initial_delay(&self) -> i32 {
+        unsafe { (*self.get_effect()).initialDelay }
+    }
+
+    /// Get the id given to the `Host` callbacks made by this instance.
+    pub fn instance_id(&self) -> InstanceId {
+        InstanceId::from_effect(self.get_effect())
+    }
 }
 
 trait Dispatch {
@@ -644,6 +850,10 @@ impl Plugin for PluginInstance {
     fn open_editor(&mut self, handle: *mut c_void) {
         self.dispatch(plugin::OpCode::EditorOpen, 0, 0, handle, 0.0);
     }
+
+    fn close_editor(&mut self) {
+        self.opcode(plugin::OpCode::EditorClose);
+    }
 }
 
 #[derive(Debug)]
@@ -856,6 +1066,10 @@ pub struct HostBuffer<T: Float> {
     outputs: Vec<*mut T>,
 }
 
+// The raw pointers are only dereferenced through the `AudioBuffer` returned by `bind`, which
+// borrows both the `HostBuffer` and the sample arrays.
+unsafe impl<T: Float + Send> Send for HostBuffer<T> {}
+
 impl<T: Float> HostBuffer<T> {
     /// Create a `HostBuffer` for a given number of input and output channels.
     pub fn new(input_count: usize, output_count: usize) -> HostBuffer<T> {
@@ -931,6 +1145,44 @@ impl<T: Float> HostBuffer<T> {
         }
     }
 
+    /// Bind a range of the sample arrays, to process a block in several parts without
+    /// copying the samples.
+    ///
+    /// # Panics
+    /// This function will panic in the same cases as `bind`, or if the range goes past the
+    /// end of the sample arrays.
+    pub fn bind_range<'a, I, O>(
+        &'a mut self,
+        input_arrays: &[I],
+        output_arrays: &mut [O],
+        range: Range<usize>,
+    ) -> AudioBuffer<'a, T>
+    where
+        I: AsRef<[T]> + 'a,
+        O: AsMut<[T]> + 'a,
+    {
+        let length = self.bind(input_arrays, output_arrays).samples();
+        if range.start > range.end || range.end > length {
+            panic!("Range out of the sample arrays");
+        }
+        // The range is checked so the offset pointers stay inside the arrays
+        for input in self.inputs[..input_arrays.len()].iter_mut() {
+            *input = unsafe { input.add(range.start) };
+        }
+        for output in self.outputs[..output_arrays.len()].iter_mut() {
+            *output = unsafe { output.add(range.start) };
+        }
+        unsafe {
+            AudioBuffer::from_raw(
+                input_arrays.len(),
+                output_arrays.len(),
+                self.inputs.as_ptr(),
+                self.outputs.as_mut_ptr(),
+                range.end - range.start,
+            )
+        }
+    }
+
     /// Number of input channels supported by this `HostBuffer`.
     pub fn input_count(&self) -> usize {
         self.inputs.len()
@@ -991,7 +1243,99 @@ fn callback_wrapper<T: Host>(
 
 #[cfg(test)]
 mod tests {
-    use host::HostBuffer;
+    use api::{AEffect, AutomationState, Supported};
+    use host::{Host, HostBuffer, HostCanDo, InstanceId, OpCode};
+    use interfaces;
+    use std::cell::RefCell;
+    use std::ffi::CString;
+    use std::os::raw::c_void;
+    use std::ptr;
+
+    /// Records the calls of the plugins
+    #[derive(Default)]
+    struct TestHost {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl Host for TestHost {
+        fn can_do(&self, can_do: HostCanDo) -> Supported {
+            match can_do {
+                HostCanDo::SizeWindow => Supported::Yes,
+                HostCanDo::Offline => Supported::No,
+                _ => Supported::Maybe,
+            }
+        }
+
+        fn get_sample_rate(&self) -> f32 {
+            48000.0
+        }
+
+        fn get_output_latency(&self) -> isize {
+            256
+        }
+
+        fn get_automation_state(&self) -> AutomationState {
+            AutomationState::Read
+        }
+
+        fn size_window_instance(&self, instance: InstanceId, width: i32, height: i32) -> bool {
+            self.calls
+                .borrow_mut()
+                .push(format!("size {:?} {}x{}", instance, width, height));
+            true
+        }
+
+        fn begin_edit(&self, index: i32) {
+            self.calls.borrow_mut().push(format!("begin {}", index));
+        }
+
+        fn end_edit(&self, index: i32) {
+            self.calls.borrow_mut().push(format!("end {}", index));
+        }
+    }
+
+    #[test]
+    fn host_dispatch() {
+        let mut host = TestHost::default();
+        let effect = 8 as *mut AEffect;
+        let mut dispatch = |opcode: OpCode, index: i32, value: isize| {
+            interfaces::host_dispatch(&mut host, effect, opcode as i32, index, value, ptr::null_mut(), 0.0)
+        };
+        assert_eq!(dispatch(OpCode::GetSampleRate, 0, 0), 48000);
+        assert_eq!(dispatch(OpCode::GetInputLatency, 0, 0), 0);
+        assert_eq!(dispatch(OpCode::GetOutputLatency, 0, 0), 256);
+        assert_eq!(dispatch(OpCode::GetAutomationState, 0, 0), 2);
+        assert_eq!(dispatch(OpCode::SizeWindow, 640, 480), 1);
+        assert_eq!(dispatch(OpCode::IOChanged, 0, 0), 0);
+        assert_eq!(dispatch(OpCode::BeginEdit, 3, 0), 1);
+        assert_eq!(dispatch(OpCode::EndEdit, 3, 0), 1);
+        assert_eq!(dispatch(OpCode::UpdateDisplay, 0, 0), 1);
+        assert_eq!(
+            *host.calls.borrow(),
+            vec!["size InstanceId(8) 640x480", "begin 3", "end 3"]
+        );
+    }
+
+    #[test]
+    fn host_can_do() {
+        let mut host = TestHost::default();
+        let mut can_do = |feature: &str| {
+            let feature = CString::new(feature).unwrap();
+            let ptr = feature.as_ptr() as *mut c_void;
+            interfaces::host_dispatch(&mut host, ptr::null_mut(), OpCode::CanDo as i32, 0, 0, ptr, 0.0)
+        };
+        assert_eq!(can_do("sizeWindow"), 1);
+        assert_eq!(can_do("offline"), 0);
+        assert_eq!(can_do("sendVstTimeInfo"), 0);
+
+        assert_eq!(HostCanDo::from_str("acceptIOChanges"), HostCanDo::AcceptIOChanges);
+        assert_eq!(
+            HostCanDo::from_str("NoteExpression"),
+            HostCanDo::Other("NoteExpression".to_string())
+        );
+        let feature: String = HostCanDo::ReceiveMidiEvent.into();
+        assert_eq!(feature, "receiveVstMidiEvent");
+    }
 
     #[test]
     fn host_buffer() {
@@ -1017,4 +1361,20 @@ mod tests {
         assert_eq!(output_left, vec![2.0; LENGTH]);
         assert_eq!(output_right, vec![2.0; LENGTH]);
     }
+
+    #[test]
+    fn host_buffer_range() {
+        let mut host_buffer: HostBuffer<f32> = HostBuffer::new(1, 2);
+        let inputs = vec![(0..8).map(|i| i as f32).collect::<Vec<f32>>()];
+        let mut outputs = vec![vec![0.0; 8]; 2];
+        {
+            let mut audio_buffer = host_buffer.bind_range(&inputs, &mut outputs, 2..5);
+            assert_eq!(audio_buffer.samples(), 3);
+            let (inputs, mut outputs) = audio_buffer.split();
+            assert_eq!(inputs.get(0), &[2.0, 3.0, 4.0]);
+            outputs.get_mut(1).copy_from_slice(inputs.get(0));
+        }
+        assert_eq!(outputs[0], vec![0.0; 8]);
+        assert_eq!(outputs[1], vec![0.0, 0.0, 2.0, 3.0, 4.0, 0.0, 0.0, 0.0]);
+    }
 }
@@ -7,10 +7,10 @@ use std::os::raw::{c_char, c_void};
 use std::{mem, slice};
 
 use api::consts::*;
-use api::{self, AEffect, TimeInfo};
+use api::{self, AEffect, Supported, TimeInfo};
 use buffer::AudioBuffer;
 use editor::{Key, KeyCode, KnobMode, Rect};
-use host::Host;
+use host::{Host, InstanceId};
 
 /// Deprecated process function.
 pub fn process_deprecated(
@@ -335,17 +335,21 @@ pub fn host_dispatch(
     ptr: *mut c_void,
     opt: f32,
 ) -> isize {
-    use host::OpCode;
+    use host::{HostCanDo, OpCode};
 
     match OpCode::from(opcode) {
         OpCode::Version => return 2400,
-        OpCode::Automate => host.automate(index, opt),
+        OpCode::Automate => host.automate_instance(InstanceId::from_effect(effect), index, opt),
 
+        OpCode::CurrentId => return host.get_plugin_id() as isize,
         OpCode::Idle => host.idle(),
 
-        // ...
         OpCode::CanDo => {
-            info!("Plugin is asking if host can: {}.", read_string(ptr));
+            // The SDK's `canHostDo` treats any non-zero answer as supported
+            return match host.can_do(HostCanDo::from_str(&read_string(ptr))) {
+                Supported::Yes => 1,
+                Supported::Maybe | Supported::No => 0,
+            };
         }
 
         OpCode::GetVendorVersion => return host.get_info().0,
@@ -354,7 +358,8 @@ pub fn host_dispatch(
             return copy_string(ptr, &host.get_info().2, MAX_PRODUCT_STR_LEN)
         }
         OpCode::ProcessEvents => {
-            host.process_events(unsafe { &*(ptr as *const api::Events) });
+            let events = unsafe { &*(ptr as *const api::Events) };
+            host.process_instance_events(InstanceId::from_effect(effect), events);
         }
 
         OpCode::GetTime => {
@@ -375,6 +380,29 @@ pub fn host_dispatch(
         OpCode::GetBlockSize => return host.get_block_size(),
 
         OpCode::GetCurrentProcessLevel => return host.get_current_process_level(),
+        OpCode::GetAutomationState => return host.get_automation_state() as isize,
+
+        OpCode::GetSampleRate => return host.get_sample_rate() as isize,
+        OpCode::GetInputLatency => return host.get_input_latency(),
+        OpCode::GetOutputLatency => return host.get_output_latency(),
+        OpCode::SizeWindow => {
+            let instance = InstanceId::from_effect(effect);
+            return host.size_window_instance(instance, index, value as i32) as isize;
+        }
+        OpCode::IOChanged => return host.io_changed_instance(InstanceId::from_effect(effect)) as isize,
+
+        OpCode::BeginEdit => {
+            host.begin_edit_instance(InstanceId::from_effect(effect), index);
+            return 1;
+        }
+        OpCode::EndEdit => {
+            host.end_edit_instance(InstanceId::from_effect(effect), index);
+            return 1;
+        }
+        OpCode::UpdateDisplay => {
+            host.update_display_instance(InstanceId::from_effect(effect));
+            return 1;
+        }
 
 
         unimplemented => {
@@ -1,5 +1,6 @@
 //! Plugin specific structures.
 
+use std::ffi::CString;
 use std::os::raw::c_void;
 use std::ptr;
 use std::sync::Arc;
@@ -700,6 +701,9 @@ pub trait Plugin {
     }
 
     fn open_editor(&mut self, handle: *mut c_void) {}
+
+    /// Close the editor previously opened with `open_editor`.
+    fn close_editor(&mut self) {}
 }
 
 /// Parameter object shared between the UI and processing threads.
@@ -952,6 +956,21 @@ impl Host for HostCallback {
         self.callback(self.effect, host::OpCode::Idle, 0, 0, ptr::null_mut(), 0.0);
     }
 
+    /// Ask the host whether it supports a feature, e.g. receiving MIDI events.
+    fn can_do(&self, can_do: host::HostCanDo) -> Supported {
+        let can_do: String = can_do.into();
+        let can_do = CString::new(can_do).expect("Invalid string data");
+        let result = self.callback(
+            self.effect,
+            host::OpCode::CanDo,
+            0,
+            0,
+            can_do.as_ptr() as *mut c_void,
+            0.0,
+        );
+        Supported::from(result).unwrap_or(Supported::Maybe)
+    }
+
     fn get_info(&self) -> (isize, String, String) {
         use api::consts::*;
         let version = self.callback(